@@ -0,0 +1,115 @@
+//! 参加トークンを求めるクライアントを認証するためのAPIキーです。
+//!
+//! キーそのものは保存せず、SHA-256のハッシュ値とユーザーの組を1行ずつファイルに書きます。
+//!
+//! ```text
+//! # <ユーザー> <キーのSHA-256(16進数)>
+//! alice 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824
+//! ```
+//!
+//! ハッシュ値は`printf %s "$KEY" | sha256sum`で求められます。
+
+use std::{fs, io, path::Path};
+
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+
+type KeyDigest = [u8; SHA256_OUTPUT_LEN];
+
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: Vec<(KeyDigest, String)>,
+}
+
+fn key_digest(key: &str) -> KeyDigest {
+    digest(&SHA256, key.as_bytes())
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digest has a fixed length")
+}
+
+fn parse_hex(hex: &str) -> Option<KeyDigest> {
+    if hex.len() != SHA256_OUTPUT_LEN * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; SHA256_OUTPUT_LEN];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+impl ApiKeys {
+    /// `path`から読み込みます。ファイルがなければ、誰も認証しません。
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => Self::parse(&content).map_err(|line| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?}:{}: invalid API key entry", path, line),
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::warn!(
+                    "API keys not found: {:?}. No one can get join tokens.",
+                    path
+                );
+                Ok(Self::default())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 空行と`#`で始まる行は読み飛ばします。読めない行があれば、その行番号を返します。
+    pub fn parse(content: &str) -> Result<Self, usize> {
+        let mut keys = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = line
+                .split_once(char::is_whitespace)
+                .and_then(|(user, hex)| Some((parse_hex(hex.trim())?, user.to_string())));
+            keys.push(entry.ok_or(i + 1)?);
+        }
+        Ok(Self { keys })
+    }
+
+    /// `key`を持つユーザーを返します。
+    pub fn authenticate(&self, key: &str) -> Option<&str> {
+        let digest = key_digest(key);
+        self.keys
+            .iter()
+            // 比べるのにかかる時間から、ハッシュ値を推測されないようにする
+            .find(|(known, _)| {
+                known
+                    .iter()
+                    .zip(digest.iter())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+            })
+            .map(|(_, user)| user.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticate() {
+        let keys = ApiKeys::parse(
+            "# comment\n\nalice 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\n",
+        )
+        .unwrap();
+        assert_eq!(keys.authenticate("hello"), Some("alice"));
+        assert_eq!(keys.authenticate("hello "), None);
+        assert_eq!(ApiKeys::default().authenticate("hello"), None);
+    }
+
+    #[test]
+    fn reject_invalid_entry() {
+        assert_eq!(ApiKeys::parse("alice\n").unwrap_err(), 1);
+        assert_eq!(ApiKeys::parse("# ok\nbob xyz\n").unwrap_err(), 2);
+    }
+}
@@ -4,15 +4,19 @@ use axum::{
     http::header::USER_AGENT,
     middleware::Next,
     response::{Json, Response},
-    routing::get,
+    routing::{get, post},
     Router,
 };
+use balancing_server::{
+    api_keys::ApiKeys,
+    join_token::{issue_join_token, load_or_generate_issuer, JoinTokenState},
+};
 use tower::ServiceBuilder;
 
 use serde::Serialize;
 
 use http::StatusCode;
-use std::env;
+use std::{env, path::PathBuf, process, sync::Arc};
 
 // INFO: struct Hello and handler hello are placeholder
 #[derive(Serialize)]
@@ -84,11 +88,44 @@ async fn main() {
     };
     log::info!("Run on port :{}", port);
 
-    let app = Router::new().route("/hello", get(hello)).layer(
-        ServiceBuilder::new()
-            .layer(axum::middleware::from_fn(logger))
-            .layer(axum::middleware::from_fn(schemaversion_checker)),
+    let key_path = env::var_os("JOIN_TOKEN_KEY_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("./keys/join_token.pk8"));
+    let issuer = match load_or_generate_issuer(&key_path) {
+        Ok(issuer) => issuer,
+        Err(e) => {
+            log::error!(
+                "Failed to load the key for join tokens {:?}: {}",
+                key_path,
+                e
+            );
+            process::exit(1);
+        }
+    };
+    log::info!(
+        "Public key for join tokens (set this as auth.join_token_public_key of clocking-server): {}",
+        issuer.public_key_base64()
     );
+    let api_keys_path = env::var_os("JOIN_API_KEYS_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("./keys/join_api_keys"));
+    let api_keys = match ApiKeys::load(&api_keys_path) {
+        Ok(api_keys) => api_keys,
+        Err(e) => {
+            log::error!("Failed to load API keys {:?}: {}", api_keys_path, e);
+            process::exit(1);
+        }
+    };
+
+    let app = Router::new()
+        .route("/hello", get(hello))
+        .route("/instances/:instance/join", post(issue_join_token))
+        .with_state(Arc::new(JoinTokenState { issuer, api_keys }))
+        .layer(
+            ServiceBuilder::new()
+                .layer(axum::middleware::from_fn(logger))
+                .layer(axum::middleware::from_fn(schemaversion_checker)),
+        );
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
         .await
@@ -0,0 +1,151 @@
+//! Clocking-serverのインスタンスに参加するためのトークンを発行します。
+//!
+//! トークンの形式は[`suteravr_lib::clocking::join_token`]を参照してください。
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use suteravr_lib::{
+    clocking::join_token::{JoinClaims, JoinTokenIssuer},
+    messaging::id::InstanceId,
+};
+
+use crate::api_keys::ApiKeys;
+
+/// 発行したトークンの有効期間です。参加するまでの間だけ使えれば十分です。
+pub const JOIN_TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// `path`から秘密鍵(PKCS#8)を読み込みます。なければ新しく作って保存します。
+pub fn load_or_generate_issuer(path: &Path) -> io::Result<JoinTokenIssuer> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let pkcs8 = match fs::read(path) {
+        Ok(pkcs8) => pkcs8,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let pkcs8 = JoinTokenIssuer::generate_pkcs8().map_err(invalid)?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)?
+                .write_all(&pkcs8)?;
+            log::warn!("Generated a new key for join tokens: {:?}", path);
+            pkcs8
+        }
+        Err(e) => return Err(e),
+    };
+    JoinTokenIssuer::from_pkcs8(&pkcs8).map_err(invalid)
+}
+
+/// トークンの発行に使うものです。
+pub struct JoinTokenState {
+    pub issuer: JoinTokenIssuer,
+    pub api_keys: ApiKeys,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinResponse {
+    pub token: String,
+    /// UNIX時間(秒)
+    pub expires_at: u64,
+}
+
+/// `POST /instances/:instance/join`
+///
+/// `Authorization: Bearer <APIキー>`で認証したユーザーのために発行します。
+pub async fn issue_join_token(
+    State(state): State<Arc<JoinTokenState>>,
+    UrlPath(instance): UrlPath<InstanceId>,
+    headers: HeaderMap,
+) -> Result<Json<JoinResponse>, StatusCode> {
+    let user = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|key| state.api_keys.authenticate(key.trim()))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let claims = JoinClaims::new(user, instance, JOIN_TOKEN_TTL);
+    Ok(Json(JoinResponse {
+        token: state.issuer.issue(&claims),
+        expires_at: claims.expires_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+        routing::post,
+        Router,
+    };
+    use suteravr_lib::clocking::join_token::JoinTokenVerifier;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn issue_verifiable_token() {
+        let path = std::env::temp_dir().join(format!(
+            "balancing-server-join-token-{}.pk8",
+            std::process::id()
+        ));
+        let issuer = load_or_generate_issuer(&path).unwrap();
+        // 2回目は保存した鍵を読み込む
+        let public_key = load_or_generate_issuer(&path).unwrap().public_key_base64();
+        assert_eq!(issuer.public_key_base64(), public_key);
+        fs::remove_file(&path).unwrap();
+
+        let app = Router::new()
+            .route("/instances/:instance/join", post(issue_join_token))
+            .with_state(Arc::new(JoinTokenState {
+                issuer,
+                api_keys: ApiKeys::parse(
+                    "alice 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                )
+                .unwrap(),
+            }));
+        let request = |authorization: Option<&str>| {
+            let request = Request::post("/instances/1/join");
+            match authorization {
+                Some(authorization) => request.header(AUTHORIZATION, authorization),
+                None => request,
+            }
+            .body(Body::empty())
+            .unwrap()
+        };
+
+        // 認証できなければ発行しない
+        for authorization in [None, Some("Bearer wrong"), Some("hello")] {
+            let response = app.clone().oneshot(request(authorization)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = app.oneshot(request(Some("Bearer hello"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let response: JoinResponse = serde_json::from_slice(&body).unwrap();
+
+        let claims = JoinTokenVerifier::from_base64(&public_key)
+            .unwrap()
+            .verify_now(&response.token, 0x01)
+            .unwrap();
+        // ユーザーはAPIキーから決まる
+        assert_eq!(claims.user, "alice");
+        assert_eq!(claims.expires_at, response.expires_at);
+    }
+}
@@ -5,3 +5,6 @@
 //! use suteravr_lib::Foo;
 //! ```
 //!
+
+pub mod api_keys;
+pub mod join_token;
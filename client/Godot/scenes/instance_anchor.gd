@@ -4,11 +4,21 @@ extends Node
 var player_scene = preload("res://scenes/instance_player.tscn")
 var player_instances = {}
 
+# Balancing-serverの POST /instances/<インスタンスID>/join で発行された参加トークン
+# ローカルのclocking-serverを開発モードで動かしている場合は、起動時のログに表示されます。
+@export var join_token: String = ""
+
+# 接続が切れた後で、接続し直すのを待っているか
+var reconnecting = false
+
 # Called when the node enters the scene tree for the first time.
 func _ready():
 	await clocker.ready
 	clocker.connect(clocker.signal_update_player_being(), _on_update_player_being)
 	clocker.connect(clocker.signal_player_moved(), _on_player_moved)
+	clocker.connect(clocker.signal_connection_lost(), _on_connection_lost)
+	clocker.connect(clocker.signal_connection_connected(), _on_connection_connected)
+	clocker.connect(clocker.signal_connection_gave_up(), _on_connection_gave_up)
 	
 	# ホストに接続し、通信確立を待機
 	# 
@@ -21,8 +31,29 @@ func _ready():
 	await Signal(clocker, clocker.signal_connection_established())
 	
 	# インスタンスに参加
-	clocker.join_instance(1)	
+	clocker.join_instance(1, join_token)
+
+
+func _on_connection_lost(reason: String):
+	print('接続が切れました: %s' % [reason])
+	reconnecting = true
+
+func _on_connection_connected(resumed: bool):
+	if !reconnecting:
+		return
+	reconnecting = false
+	if resumed:
+		print('接続し直しました')
+		return
+	# 前のセッションを引き継げなかったので、別のプレイヤーとして参加し直す
+	print('接続し直しました。インスタンスに参加し直します')
+	for id in player_instances.keys():
+		delete_player_instance(id)
+	player_instances.clear()
+	clocker.join_instance(1, join_token)
 
+func _on_connection_gave_up(reason: String):
+	print('サーバーに接続できませんでした: %s' % [reason])
 
 func _on_update_player_being(id: int, value: bool, joining: bool):
 	if value == true:
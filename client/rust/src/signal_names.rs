@@ -1,4 +1,8 @@
 pub const SIGNAL_CONNECTION_ESTABLISHED: &str = "connection_established";
+pub const SIGNAL_CONNECTION_CONNECTING: &str = "connection_connecting";
+pub const SIGNAL_CONNECTION_CONNECTED: &str = "connection_connected";
+pub const SIGNAL_CONNECTION_LOST: &str = "connection_lost";
+pub const SIGNAL_CONNECTION_GAVE_UP: &str = "connection_gave_up";
 pub const SIGNAL_NEW_TEXTCHAT_MESSAGE: &str = "new_textchat_message";
 pub const SIGNAL_UPDATE_PLAYER_BEING: &str = "update_player_being";
 pub const SIGNAL_PLAYER_MOVED: &str = "player_moved";
@@ -1,78 +1,54 @@
-pub mod allow_unknown_cert;
-pub mod conenction;
-pub mod error;
-pub mod requests;
-
-use alkahest::deserialize;
 use hickory_resolver::TokioAsyncResolver;
-use std::sync::{atomic::AtomicU64, Arc, Mutex};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use suteravr_lib::{
     clocking::{
-        event_headers::{EventDirection, EventHeader, EventTypes},
-        schemas::{
-            event::player_move::PubPlayerMove,
-            oneshot::{
-                chat_entry::SendChatMessageRequest,
-                login::{LoginRequest, LoginResponse},
-            },
+        client::{
+            allow_unknown_cert::AllowUnknownCertVerifier,
+            pinned_cert::{KnownHosts, TofuCertVerifier},
+            reconnect::ReconnectPolicy,
+            ClientEvent, ClientOptions, ClientState, ClockingClient,
         },
+        ConnectionLimits,
     },
-    debug, error,
-    messaging::id::PlayerId,
-    messaging::player::{StandingTransform, StandingTransformEncoder},
-    util::serialize_to_new_vec,
+    debug, error, info,
+    messaging::player::StandingTransform,
+    warn,
 };
 
 use futures::executor::block_on;
-use godot::{engine::notify::NodeNotification, obj::WithBaseField, prelude::*};
-use suteravr_lib::{
-    clocking::{
-        oneshot_headers::{OneshotHeader, OneshotStep, OneshotTypes},
-        sutera_header::SuteraHeader,
-    },
-    info,
-    messaging::id::MessageId,
-    warn, SCHEMA_VERSION,
-};
-use tokio::{
-    sync::{mpsc, oneshot},
-    task::JoinError,
+use godot::{
+    engine::{notify::NodeNotification, ProjectSettings},
+    obj::WithBaseField,
+    prelude::*,
 };
+use tokio::task::JoinError;
 use tokio_rustls::rustls::{ClientConfig, RootCertStore};
 
 use crate::{
     async_driver::tokio,
     logger::GodotLogger,
     signal_names::{
-        SIGNAL_CONNECTION_ESTABLISHED, SIGNAL_NEW_TEXTCHAT_MESSAGE, SIGNAL_PLAYER_MOVED,
-        SIGNAL_UPDATE_PLAYER_BEING,
-    },
-    tcp::{
-        allow_unknown_cert::AllowUnknownCertVerifier,
-        error::TcpServerError,
-        requests::{OneshotRequest, OneshotResponse},
+        SIGNAL_CONNECTION_CONNECTED, SIGNAL_CONNECTION_CONNECTING, SIGNAL_CONNECTION_ESTABLISHED,
+        SIGNAL_CONNECTION_GAVE_UP, SIGNAL_CONNECTION_LOST, SIGNAL_NEW_TEXTCHAT_MESSAGE,
+        SIGNAL_PLAYER_MOVED, SIGNAL_UPDATE_PLAYER_BEING,
     },
 };
 
-use self::{
-    conenction::Connection,
-    requests::{EventMessage, Request, Response},
-};
-
-#[derive(Debug)]
-pub enum ShutdownReason {
-    GameExit,
-}
+/// 接続したことのあるサーバーの公開鍵を保存するファイルです。
+const KNOWN_HOSTS_PATH: &str = "user://clocking_known_hosts";
 
 #[derive(GodotClass)]
 #[class(base=Node)]
 struct ClockerConnection {
     base: Base<Node>,
-    pos: StandingTransformEncoder,
     logger: GodotLogger,
-    connection: Arc<Mutex<Option<Connection>>>,
-    player_id: Arc<Mutex<Option<PlayerId>>>,
-    message_id_dispatch: AtomicU64,
+    client: Arc<Mutex<Option<ClockingClient<GodotLogger>>>>,
+    /// 接続を作り直しても、同じプレイヤーとして参加し直せるように引き継ぎます。
+    state: ClientState,
+    options: ClientOptions,
 }
 
 impl ClockerConnection {
@@ -80,14 +56,29 @@ impl ClockerConnection {
         self.logger.clone()
     }
 
+    fn known_hosts() -> std::io::Result<KnownHosts> {
+        let path = ProjectSettings::singleton().globalize_path(KNOWN_HOSTS_PATH.into());
+        KnownHosts::open(path.to_string())
+    }
+
+    fn client(&self) -> Option<ClockingClient<GodotLogger>> {
+        self.client.lock().ok()?.clone()
+    }
+
+    fn connector(&self) -> Connector {
+        Connector {
+            client: self.client.clone(),
+            logger: self.logger(),
+            instance_id: self.base().instance_id(),
+            options: self.options,
+            state: self.state.clone(),
+        }
+    }
+
     async fn shutdown(&mut self) -> Result<(), JoinError> {
-        let taken_connection = { self.connection.lock().unwrap().take() };
-        if let Some(connection) = taken_connection {
-            connection
-                .shutdown_tx
-                .send(ShutdownReason::GameExit)
-                .unwrap();
-            connection.handle.await?;
+        let taken_client = { self.client.lock().unwrap().take() };
+        if let Some(client) = taken_client {
+            client.shutdown().await?;
         }
         Ok(())
     }
@@ -103,6 +94,29 @@ impl ClockerConnection {
     fn signal_connection_established(&mut self) -> String {
         SIGNAL_CONNECTION_ESTABLISHED.to_string()
     }
+    /// 接続を試みる前に発生します。引数は続けて失敗した回数です。
+    #[func]
+    fn signal_connection_connecting(&mut self) -> String {
+        SIGNAL_CONNECTION_CONNECTING.to_string()
+    }
+    /// 接続できて、リクエストを送れるようになったときに発生します。
+    ///
+    /// 引数は、接続し直した後に前のセッションを引き継げたかです。引き継げなかった場合は、
+    /// [`Self::join_instance`]でインスタンスに参加し直してください。
+    #[func]
+    fn signal_connection_connected(&mut self) -> String {
+        SIGNAL_CONNECTION_CONNECTED.to_string()
+    }
+    /// 接続が切れたときに発生します。引数は理由です。この後、自動で接続し直します。
+    #[func]
+    fn signal_connection_lost(&mut self) -> String {
+        SIGNAL_CONNECTION_LOST.to_string()
+    }
+    /// 接続し直すのを諦めたときに発生します。引数は理由です。
+    #[func]
+    fn signal_connection_gave_up(&mut self) -> String {
+        SIGNAL_CONNECTION_GAVE_UP.to_string()
+    }
     #[func]
     fn signal_update_player_being(&mut self) -> String {
         SIGNAL_UPDATE_PLAYER_BEING.to_string()
@@ -114,14 +128,51 @@ impl ClockerConnection {
 
     #[func]
     fn get_player_id_or_minus_one(&self) -> i64 {
-        self.player_id.lock().unwrap().map(i64::from).unwrap_or(-1)
+        self.state.player_id().map(i64::from).unwrap_or(-1)
+    }
+
+    /// 接続先のサーバーが使っているスキーマバージョンを返します。まだ分からない場合は空文字列を返します。
+    #[func]
+    fn get_server_schema_version(&self) -> String {
+        self.state
+            .server_version()
+            .map(|v| v.to_string())
+            .unwrap_or_default()
+    }
+
+    /// 次に作成する接続で使う、受信データ量の上限を設定します。
+    #[func]
+    fn set_connection_limits(
+        &mut self,
+        max_content_size: u64,
+        max_buffered_bytes: u64,
+        max_unfragmented_bytes: u64,
+    ) {
+        self.options = self.options.with_limits(ConnectionLimits {
+            max_content_size: max_content_size as usize,
+            max_buffered_bytes: max_buffered_bytes as usize,
+            max_unfragmented_bytes: max_unfragmented_bytes as usize,
+        });
+    }
+
+    /// 次に作成する接続で、接続し直すまでに待つ時間と諦めるまでの回数を設定します。
+    ///
+    /// `max_retries`が負の場合は、諦めずに接続し直し続けます。
+    #[func]
+    fn set_reconnect_policy(&mut self, initial_delay_ms: u64, max_delay_ms: u64, max_retries: i64) {
+        let reconnect = self.options.reconnect;
+        self.options = self.options.with_reconnect(ReconnectPolicy {
+            initial_delay: Duration::from_millis(initial_delay_ms),
+            max_delay: Duration::from_millis(max_delay_ms),
+            max_retries: u32::try_from(max_retries).ok(),
+            ..reconnect
+        });
     }
 
     #[func]
     fn connect_by_srv(&mut self, domain: String) {
-        let instance_id = self.base().instance_id();
         let logger = self.logger();
-        let connection = self.connection.clone();
+        let connector = self.connector();
         tokio().bind().spawn("connect_by_srv", async move {
             let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap();
             let srv = resolver
@@ -135,14 +186,7 @@ impl ClockerConnection {
                     let config = ClientConfig::builder()
                         .with_root_certificates(root_cert_store)
                         .with_no_client_auth();
-                    Self::connect(
-                        connection,
-                        logger,
-                        instance_id,
-                        config,
-                        domain,
-                        format!("{}:{}", e.target(), e.port()),
-                    )
+                    connector.connect(config, domain, format!("{}:{}", e.target(), e.port()))
                 }
                 Ok(None) => {
                     error!(
@@ -181,173 +225,251 @@ impl ClockerConnection {
             self.logger,
             "Ensure you are connecting to the right server!"
         );
-        Self::connect(
-            self.connection.clone(),
-            self.logger.clone(),
-            self.base().instance_id(),
-            config,
-            name,
-            addr,
-        );
+        self.connector().connect(config, name, addr);
+    }
+
+    /// 初めて接続したときの公開鍵を記録し、以降は同じ公開鍵のサーバーにだけ接続します。
+    ///
+    /// 自己署名証明書のサーバーに接続するためのもので、
+    /// [`Self::connect_without_certification_verifying`]よりも安全です。
+    #[func]
+    fn connect_with_pinning(&mut self, name: String, addr: String) {
+        let known_hosts = match Self::known_hosts() {
+            Ok(known_hosts) => known_hosts,
+            Err(e) => {
+                error!(self.logger, "Failed to load known hosts: {}", e);
+                return;
+            }
+        };
+        match known_hosts.get(&name) {
+            Some(fingerprint) => {
+                info!(
+                    self.logger,
+                    "Pinned public key of {}: {}", name, fingerprint
+                )
+            }
+            None => warn!(
+                self.logger,
+                "First connection to {}. Its public key will be trusted from now on.", name
+            ),
+        }
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(TofuCertVerifier::new(Arc::new(known_hosts)))
+            .with_no_client_auth();
+        self.connector().connect(config, name, addr);
+    }
+
+    /// 記録した公開鍵を消します。サーバーの鍵を入れ替えた場合に使います。
+    #[func]
+    fn forget_pinned_host(&mut self, name: String) -> bool {
+        match Self::known_hosts().and_then(|known_hosts| known_hosts.forget(&name)) {
+            Ok(forgotten) => forgotten,
+            Err(e) => {
+                error!(self.logger, "Failed to update known hosts: {}", e);
+                false
+            }
+        }
     }
 
     #[func]
     fn oneshot_send_chat_message(&mut self, content: String) {
-        let id = self.get_message_id();
         let logger = self.logger();
-        let Some(send) = self.send_tx() else {
+        let Some(client) = self.client() else {
             return;
         };
         tokio().bind().spawn("clocking_request", async move {
-            let login_result = Self::create_oneshot_p(
-                logger.clone(),
-                send,
-                OneshotRequest {
-                    sutera_header: SuteraHeader {
-                        version: SCHEMA_VERSION,
-                    },
-                    oneshot_header: OneshotHeader {
-                        step: OneshotStep::Request,
-                        message_type: OneshotTypes::TextChat_SendMessage_Pull,
-                        message_id: id,
-                    },
-
-                    payload: serialize_to_new_vec(SendChatMessageRequest { content }),
-                },
-            )
-            .await?;
-            let result = deserialize::<LoginResponse, LoginResponse>(&login_result.payload)?;
-            debug!(logger, "ChatMessage sent: {:?}", result);
-            Ok::<(), TcpServerError>(())
+            match client.send_chat(content).await {
+                Ok(()) => debug!(logger, "ChatMessage sent"),
+                Err(e) => warn!(logger, "Failed to send ChatMessage: {}", e),
+            }
         });
     }
 
     #[func]
     fn report_player_transform(&mut self, x: f64, y: f64, z: f64, xx: f64, xz: f64) {
-        self.pos.push(StandingTransform {
+        let Some(client) = self.client() else {
+            return;
+        };
+        // 接続が閉じていれば、次の接続で改めて送る
+        let _ = client.report_transform(StandingTransform {
             x,
             y,
             z,
             yaw: (xx + 1f64) * xz.signum(),
         });
-        if let Some(now) = self.pos.payload() {
-            let Some(send) = self.send_tx() else {
-                return;
-            };
-            tokio().bind().spawn("report_player_pos", async move {
-                send.send(Request::Event(EventMessage {
-                    sutera_header: SuteraHeader {
-                        version: SCHEMA_VERSION,
-                    },
-                    event_header: EventHeader {
-                        direction: EventDirection::Pull,
-                        message_type: EventTypes::Instance_PubPlayerMove_Pull,
-                    },
-                    payload: serialize_to_new_vec(PubPlayerMove { now }),
-                }))
-                .await
-                .map_err(TcpServerError::CannotSendRequest)?;
-                Ok::<(), TcpServerError>(())
-            });
-        }
     }
 
+    /// `join_token`はBalancing-serverが`instance`のために発行したものです。
     #[func]
-    fn join_instance(&mut self, join_token: u64) {
-        let id = self.get_message_id();
-        let player_id_mut = self.player_id.clone();
+    fn join_instance(&mut self, instance: u64, join_token: String) {
         let logger = self.logger();
-        let Some(send) = self.send_tx() else {
+        let Some(client) = self.client() else {
             return;
         };
         let instance_id = self.base().instance_id();
         tokio().bind().spawn("clocking_request", async move {
-            info!(logger, "Joining instance with token: {}", join_token);
-            let login_result = Self::create_oneshot_p(
-                logger.clone(),
-                send,
-                OneshotRequest {
-                    sutera_header: SuteraHeader {
-                        version: SCHEMA_VERSION,
-                    },
-                    oneshot_header: OneshotHeader {
-                        step: OneshotStep::Request,
-                        message_type: OneshotTypes::Authentication_Login_Pull,
-                        message_id: id,
-                    },
-
-                    payload: serialize_to_new_vec(LoginRequest { join_token }),
-                },
-            )
-            .await?;
-            let result = deserialize::<LoginResponse, LoginResponse>(&login_result.payload)?;
-            info!(logger, "Instance Joined: {:?}", result);
-            if let LoginResponse::Ok(player_id, players) = result {
-                {
-                    player_id_mut.lock().unwrap().replace(player_id);
-                }
-                for player in players {
-                    Gd::<ClockerConnection>::from_instance_id(instance_id)
-                        .cast::<ClockerConnection>()
-                        .call_deferred(
-                            "emit_signal".into(),
-                            &[
-                                Variant::from(SIGNAL_UPDATE_PLAYER_BEING.into_godot()),
-                                Variant::from(player.into_godot()),
-                                Variant::from(true.into_godot()),
-                                Variant::from(true.into_godot()),
-                            ],
-                        );
+            info!(logger, "Joining instance: {}", instance);
+            let players = match client.login(instance, join_token).await {
+                Ok(players) => players,
+                Err(e) => {
+                    warn!(logger, "Failed to join instance {}: {}", instance, e);
+                    return;
                 }
+            };
+            info!(logger, "Instance Joined: {:?}", players);
+            for player in players {
+                emit_signal(
+                    instance_id,
+                    &[
+                        Variant::from(SIGNAL_UPDATE_PLAYER_BEING.into_godot()),
+                        Variant::from(player.into_godot()),
+                        Variant::from(true.into_godot()),
+                        Variant::from(true.into_godot()),
+                    ],
+                );
             }
-            Ok::<(), TcpServerError>(())
         });
     }
-}
-impl ClockerConnection {
-    fn send_tx(&self) -> Option<mpsc::Sender<Request>> {
-        Some(self.connection.lock().ok()?.as_ref()?.send_tx.clone())
-    }
 
-    fn connect<T: Into<String> + Send + 'static>(
-        connection: Arc<Mutex<Option<Connection>>>,
-        logger: GodotLogger,
-        instance_id: InstanceId,
-        config: ClientConfig,
-        name: T,
-        addr: T,
-    ) {
-        *connection.lock().unwrap() =
-            Some(Connection::new(logger, instance_id, config, name, addr));
+    /// 接続し直した後に呼ぶと、切れる前と同じプレイヤーとしてインスタンスに戻ります。
+    ///
+    /// 切れている間のイベントは、この後に続けて届きます。猶予期間を過ぎていた場合は、
+    /// [`Self::join_instance`]で参加し直してください。
+    ///
+    /// 接続が切れて自動で接続し直した場合は、送っておいたリクエストより先に引き継ぐので、
+    /// 呼ぶ必要はありません。新しく接続を作り直した場合に使います。
+    #[func]
+    fn resume_session(&mut self) {
+        if self.state.resume_token().is_none() {
+            warn!(self.logger, "No session to resume.");
+            return;
+        }
+        let logger = self.logger();
+        let Some(client) = self.client() else {
+            return;
+        };
+        tokio().bind().spawn("clocking_request", async move {
+            match client.resume().await {
+                Ok(player_id) => info!(logger, "Session resumed as player {}", player_id),
+                Err(e) => warn!(logger, "Failed to resume the session: {}", e),
+            }
+        });
     }
+}
 
-    async fn create_oneshot_p(
-        logger: GodotLogger,
-        send: mpsc::Sender<Request>,
-        response: OneshotRequest,
-    ) -> Result<OneshotResponse, TcpServerError> {
-        let message_id = response.oneshot_header.message_id;
-        let (tx, rx) = oneshot::channel::<Response>();
-        send.send(Request::OneshotWithReply(response, tx))
-            .await
-            .map_err(TcpServerError::CannotSendRequest)?;
+/// 新しい接続を作り、受け取ったイベントをシグナルとして発生させます。
+struct Connector {
+    client: Arc<Mutex<Option<ClockingClient<GodotLogger>>>>,
+    logger: GodotLogger,
+    instance_id: InstanceId,
+    options: ClientOptions,
+    state: ClientState,
+}
 
-        let Response::Oneshot(oneshot) = rx.await? else {
-            error!(
-                logger,
-                "rx of messageId {:?} not received Oneshot!", message_id
+impl Connector {
+    fn connect(self, config: ClientConfig, name: String, addr: String) {
+        tokio().bind().spawn("clocker_connection", async move {
+            let (client, mut events) = ClockingClient::connect_with_state(
+                config,
+                name,
+                addr,
+                self.options,
+                self.state,
+                self.logger.clone(),
             );
-            panic!();
-        };
-        Ok(oneshot)
-    }
+            let previous = { self.client.lock().unwrap().replace(client) };
+            if let Some(previous) = previous {
+                if let Err(e) = previous.shutdown().await {
+                    warn!(
+                        self.logger,
+                        "Failed to close the previous connection: {}", e
+                    );
+                }
+            }
 
-    fn get_message_id(&mut self) -> MessageId {
-        self.message_id_dispatch
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed) as MessageId
+            // 一度目に接続できたときだけ、接続が確立したことを伝える
+            let mut established = false;
+            while let Some(event) = events.recv().await {
+                let args = match event {
+                    ClientEvent::Connecting { failures } => vec![
+                        Variant::from(SIGNAL_CONNECTION_CONNECTING.into_godot()),
+                        Variant::from(failures.into_godot()),
+                    ],
+                    ClientEvent::Connected { resumed } => {
+                        if !established {
+                            established = true;
+                            emit_signal(
+                                self.instance_id,
+                                &[Variant::from(SIGNAL_CONNECTION_ESTABLISHED.into_godot())],
+                            );
+                        }
+                        vec![
+                            Variant::from(SIGNAL_CONNECTION_CONNECTED.into_godot()),
+                            Variant::from(resumed.into_godot()),
+                        ]
+                    }
+                    ClientEvent::Lost { reason } => vec![
+                        Variant::from(SIGNAL_CONNECTION_LOST.into_godot()),
+                        Variant::from(reason.into_godot()),
+                    ],
+                    ClientEvent::GaveUp { reason } => vec![
+                        Variant::from(SIGNAL_CONNECTION_GAVE_UP.into_godot()),
+                        Variant::from(reason.into_godot()),
+                    ],
+                    ClientEvent::ChatMessage { sender, message } => vec![
+                        Variant::from(SIGNAL_NEW_TEXTCHAT_MESSAGE.into_godot()),
+                        Variant::from(sender.into_godot()),
+                        Variant::from(message.into_godot()),
+                    ],
+                    ClientEvent::PlayerJoined(player) => vec![
+                        Variant::from(SIGNAL_UPDATE_PLAYER_BEING.into_godot()),
+                        Variant::from(player.into_godot()),
+                        Variant::from(true.into_godot()),
+                        Variant::from(false.into_godot()),
+                    ],
+                    ClientEvent::PlayerLeft(player) => vec![
+                        Variant::from(SIGNAL_UPDATE_PLAYER_BEING.into_godot()),
+                        Variant::from(player.into_godot()),
+                        Variant::from(false.into_godot()),
+                        Variant::from(false.into_godot()),
+                    ],
+                    ClientEvent::PlayerMoved { player, now } => {
+                        let decode = now.decode();
+                        vec![
+                            Variant::from(SIGNAL_PLAYER_MOVED.into_godot()),
+                            Variant::from(player.into_godot()),
+                            // FIXME: マジ無駄コードだな……
+                            Variant::from(decode.0.into_godot()),
+                            Variant::from(decode.1.into_godot()),
+                            Variant::from(decode.2.into_godot()),
+                            Variant::from(decode.3.into_godot()),
+                            Variant::from(decode.4.into_godot()),
+                            Variant::from(decode.5.into_godot()),
+                            Variant::from(decode.6.into_godot()),
+                        ]
+                    }
+                    ClientEvent::Unknown(event) => {
+                        debug!(
+                            self.logger,
+                            "Unhandled event: {:?}", event.event_header.message_type
+                        );
+                        continue;
+                    }
+                };
+                emit_signal(self.instance_id, &args);
+            }
+            info!(self.logger, "Connection successfully finished.");
+        });
     }
 }
 
+fn emit_signal(instance_id: InstanceId, args: &[Variant]) {
+    Gd::<ClockerConnection>::from_instance_id(instance_id)
+        .cast::<ClockerConnection>()
+        .call_deferred("emit_signal".into(), args);
+}
+
 #[godot_api]
 impl INode for ClockerConnection {
     fn init(base: Base<Node>) -> Self {
@@ -356,11 +478,10 @@ impl INode for ClockerConnection {
         };
         Self {
             base,
-            pos: StandingTransformEncoder::new(),
             logger,
-            connection: Arc::new(Mutex::new(None)),
-            player_id: Arc::new(Mutex::new(None)),
-            message_id_dispatch: AtomicU64::new(0),
+            client: Arc::new(Mutex::new(None)),
+            state: ClientState::default(),
+            options: ClientOptions::default(),
         }
     }
 
@@ -369,6 +490,14 @@ impl INode for ClockerConnection {
             .add_user_signal(SIGNAL_NEW_TEXTCHAT_MESSAGE.into());
         self.base_mut()
             .add_user_signal(SIGNAL_CONNECTION_ESTABLISHED.into());
+        self.base_mut()
+            .add_user_signal(SIGNAL_CONNECTION_CONNECTING.into());
+        self.base_mut()
+            .add_user_signal(SIGNAL_CONNECTION_CONNECTED.into());
+        self.base_mut()
+            .add_user_signal(SIGNAL_CONNECTION_LOST.into());
+        self.base_mut()
+            .add_user_signal(SIGNAL_CONNECTION_GAVE_UP.into());
         self.base_mut()
             .add_user_signal(SIGNAL_UPDATE_PLAYER_BEING.into());
         self.base_mut().add_user_signal(SIGNAL_PLAYER_MOVED.into());
@@ -0,0 +1,215 @@
+//! Clocking-serverのキャプチャファイルを読むためのツールです。
+//!
+//! ```sh
+//! # キャプチャを読みやすい形で表示する
+//! clocking-capture dump ./captures/xxx.capture
+//! # キャプチャのうち、クライアントが送ったフレームをサーバーに送り直す
+//! clocking-capture replay ./captures/xxx.capture 127.0.0.1:3501 [localhost]
+//! ```
+//!
+//! サーバーでキャプチャを取るには、環境変数`CAPTURE_DIR`に出力先のディレクトリを指定してください。
+
+use std::{env, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use suteravr_lib::clocking::{
+    capture::{CaptureDirection, CaptureReader, CapturedFrame},
+    compression::ContentCompression,
+    traits::MessageAuthor,
+    ClockingConnection, ClockingFrameUnit,
+};
+use tokio::{
+    net::TcpStream,
+    time::{sleep_until, Instant},
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, ring::default_provider, CryptoProvider},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+const USAGE: &str = "Usage:
+    clocking-capture dump <capture>
+    clocking-capture replay <capture> <addr> [server name]";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["dump", capture] => dump(capture).await,
+        ["replay", capture, addr] => replay(capture, addr, "localhost").await,
+        ["replay", capture, addr, name] => replay(capture, addr, name).await,
+        _ => bail!(USAGE),
+    }
+}
+
+fn describe(frame: &ClockingFrameUnit) -> String {
+    match frame {
+        ClockingFrameUnit::Content(content) => {
+            format!("Content({} byte(s)) {:?}", content.len(), content)
+        }
+        ClockingFrameUnit::Unfragmented(content) => {
+            format!("Unfragmented({} byte(s)) {:?}", content.len(), content)
+        }
+        frame => format!("{:?}", frame),
+    }
+}
+
+fn elapsed(start: DateTime<Utc>, captured: &CapturedFrame) -> f64 {
+    (captured.timestamp - start).num_microseconds().unwrap_or(0) as f64 / 1_000_000f64
+}
+
+async fn dump(capture: &str) -> anyhow::Result<()> {
+    let mut reader = CaptureReader::open(capture)
+        .await
+        .with_context(|| format!("Failed to open {}", capture))?;
+    let author = reader.author();
+    let mut start = None;
+
+    while let Some(captured) = reader.next().await? {
+        let start = *start.get_or_insert(captured.timestamp);
+        println!(
+            "{:>12.6}s {:?} -> {:<8} {}",
+            elapsed(start, &captured),
+            captured.author(author),
+            match captured.direction {
+                CaptureDirection::Read => "(read)",
+                CaptureDirection::Written => "(written)",
+            },
+            describe(&captured.frame)
+        );
+    }
+    Ok(())
+}
+
+async fn replay(capture: &str, addr: &str, name: &str) -> anyhow::Result<()> {
+    let mut reader = CaptureReader::open(capture)
+        .await
+        .with_context(|| format!("Failed to open {}", capture))?;
+    let author = reader.author();
+
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(AllowAnyCertVerifier::new())
+        .with_no_client_auth();
+    let stream = TcpStream::connect(addr).await?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from(name.to_string())?, stream)
+        .await?;
+    let mut connection = ClockingConnection::new(stream, MessageAuthor::Server);
+    // 元の接続で圧縮が有効になっていた場合に備えて、受け付けておく
+    connection.set_compression(ContentCompression {
+        accept: true,
+        ..Default::default()
+    });
+    println!("Connected to {}({}). Replaying {} ...", name, addr, capture);
+
+    let started = Instant::now();
+    let mut start = None;
+    loop {
+        // クライアントが送ったフレームだけを、元の間隔で送り直す
+        let next = loop {
+            match reader.next().await? {
+                Some(captured) if captured.author(author) == MessageAuthor::Client => {
+                    break Some(captured)
+                }
+                Some(_) => continue,
+                None => break None,
+            }
+        };
+        let Some(next) = next else {
+            break;
+        };
+        let start = *start.get_or_insert(next.timestamp);
+        let deadline = started + Duration::from_secs_f64(elapsed(start, &next));
+
+        loop {
+            tokio::select! {
+                read = connection.read_frame() => match read? {
+                    Some(frame) => println!("<- {}", describe(&frame)),
+                    None => {
+                        println!("Connection closed by the server.");
+                        return Ok(());
+                    }
+                },
+                _ = sleep_until(deadline) => break,
+            }
+        }
+        println!("-> {}", describe(&next.frame));
+        connection.write_frame(&next.frame).await?;
+    }
+
+    println!("All frames have been sent. Press Ctrl-C to exit.");
+    while let Some(frame) = connection.read_frame().await? {
+        println!("<- {}", describe(&frame));
+    }
+    println!("Connection closed by the server.");
+    Ok(())
+}
+
+/// リプレイは手元のサーバーに対して行うことを想定しているので、証明書は検証しません。
+#[derive(Debug)]
+struct AllowAnyCertVerifier {
+    provider: CryptoProvider,
+}
+
+impl AllowAnyCertVerifier {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            provider: default_provider(),
+        })
+    }
+}
+
+impl ServerCertVerifier for AllowAnyCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,570 @@
+//! 多数のクライアントを模して、Clocking-serverに負荷をかけるためのツールです。
+//!
+//! ```sh
+//! # 50人がインスタンス1に参加し、60秒の間歩き回りながら5秒おきにチャットする
+//! clocking-loadbot 127.0.0.1:3501 1 --key ./keys/join_token.pk8 --clients 50
+//! ```
+//!
+//! 参加するためのトークンは、Balancing-serverと同じ秘密鍵を使って自分で発行します。
+//! `--key`を省略した場合は、環境変数`JOIN_TOKEN_KEY_PATH`か`./keys/join_token.pk8`を使います。
+//!
+//! 終わると、Oneshotの返答までの時間と、チャットが他のクライアントに届くまでの時間(chat fan-out)を
+//! パーセンタイルで表示します。移動の届くまでの時間は計らず、数だけを数えます。
+
+use std::{
+    env,
+    f64::consts::{PI, TAU},
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use suteravr_lib::{
+    clocking::{
+        client::{
+            allow_unknown_cert::AllowUnknownCertVerifier, ClientEvent, ClientOptions,
+            ClockingClient,
+        },
+        join_token::JoinTokenIssuer,
+    },
+    messaging::{id::InstanceId, player::StandingTransform},
+    util::logger::EnvLogger,
+};
+use tokio::time::{self, sleep, sleep_until, timeout, Instant, MissedTickBehavior};
+use tokio_rustls::rustls::ClientConfig;
+
+const USAGE: &str = "Usage:
+    clocking-loadbot <addr> <instance> [options]
+
+Options:
+    --server-name <name>      TLS server name (default: localhost)
+    --key <path>              PKCS#8 key for issuing join tokens (default: $JOIN_TOKEN_KEY_PATH or ./keys/join_token.pk8)
+    --clients <n>             number of simulated clients (default: 50)
+    --duration <secs>         how long each client stays after joining (default: 60)
+    --ramp-up <secs>          spread client connections over this period (default: 5)
+    --move-rate <hz>          how often each client updates its position (default: 20)
+    --chat-interval <secs>    interval between chat messages per client, 0 to disable (default: 5)
+    --path <circle|patrol|mixed>
+                              path the clients walk along (default: mixed)";
+
+/// 接続してからログインするまでに待つ時間です。
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// 歩く速さ(m/s)です。
+const WALKING_SPEED: f64 = 1.4;
+/// チャットの本文の先頭に付けます。送った時刻を読み取れるものだけを数えます。
+const CHAT_PREFIX: &str = "loadbot";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PathKind {
+    /// 原点の周りを回ります。
+    Circle,
+    /// 直線の上を往復します。
+    Patrol,
+    /// 偶数番目は回り、奇数番目は往復します。
+    Mixed,
+}
+
+#[derive(Debug, Clone)]
+struct Options {
+    addr: String,
+    instance: InstanceId,
+    server_name: String,
+    key: PathBuf,
+    clients: usize,
+    duration: Duration,
+    ramp_up: Duration,
+    move_rate: f64,
+    chat_interval: Option<Duration>,
+    path: PathKind,
+}
+
+impl Options {
+    fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut args = args.into_iter();
+        let (Some(addr), Some(instance)) = (args.next(), args.next()) else {
+            bail!(USAGE);
+        };
+        let mut options = Self {
+            addr,
+            instance: parse_field("<instance>", &instance)?,
+            server_name: "localhost".to_string(),
+            key: env::var_os("JOIN_TOKEN_KEY_PATH")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("./keys/join_token.pk8")),
+            clients: 50,
+            duration: Duration::from_secs(60),
+            ramp_up: Duration::from_secs(5),
+            move_rate: 20f64,
+            chat_interval: Some(Duration::from_secs(5)),
+            path: PathKind::Mixed,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = |field: &str| {
+                args.next()
+                    .ok_or_else(|| anyhow!("{} requires a value\n\n{}", field, USAGE))
+            };
+            match arg.as_str() {
+                "--server-name" => options.server_name = value("--server-name")?,
+                "--key" => options.key = value("--key")?.into(),
+                "--clients" => options.clients = parse_field("--clients", &value("--clients")?)?,
+                "--duration" => options.duration = parse_secs("--duration", &value("--duration")?)?,
+                "--ramp-up" => options.ramp_up = parse_secs("--ramp-up", &value("--ramp-up")?)?,
+                "--move-rate" => {
+                    options.move_rate = parse_field("--move-rate", &value("--move-rate")?)?;
+                    if options.move_rate.is_nan() || options.move_rate <= 0f64 {
+                        bail!("--move-rate must be positive");
+                    }
+                }
+                "--chat-interval" => {
+                    let interval = parse_secs("--chat-interval", &value("--chat-interval")?)?;
+                    options.chat_interval = (!interval.is_zero()).then_some(interval);
+                }
+                "--path" => {
+                    options.path = match value("--path")?.as_str() {
+                        "circle" => PathKind::Circle,
+                        "patrol" => PathKind::Patrol,
+                        "mixed" => PathKind::Mixed,
+                        path => bail!("Unknown path: {}\n\n{}", path, USAGE),
+                    }
+                }
+                _ => bail!("Unknown argument: {}\n\n{}", arg, USAGE),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str, value: &str) -> anyhow::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e: T::Err| anyhow!("Invalid {}: {:?} ({})", field, value, e))
+}
+
+fn parse_secs(field: &str, value: &str) -> anyhow::Result<Duration> {
+    let secs: f64 = parse_field(field, value)?;
+    Duration::try_from_secs_f64(secs).map_err(|e| anyhow!("Invalid {}: {:?} ({})", field, value, e))
+}
+
+/// `chat_message`が同じプロセスのクライアントのものであれば、送ったクライアントの番号と時刻を返します。
+fn parse_chat(chat_message: &str) -> Option<(usize, Duration)> {
+    let mut fields = chat_message.strip_prefix(CHAT_PREFIX)?.split_whitespace();
+    let index = fields.next()?.parse().ok()?;
+    let micros = fields.next()?.parse().ok()?;
+    Some((index, Duration::from_micros(micros)))
+}
+
+/// 並べ替えた`samples`の、`p`(0.0〜1.0)のパーセンタイルです。
+fn percentile(samples: &[Duration], p: f64) -> Duration {
+    let index = ((samples.len() as f64 * p).ceil() as usize).clamp(1, samples.len());
+    samples[index - 1]
+}
+
+/// 計った時間を溜めておき、最後にまとめてパーセンタイルを求めます。
+#[derive(Default)]
+struct Samples {
+    samples: Mutex<Vec<Duration>>,
+}
+
+impl Samples {
+    fn record(&self, sample: Duration) {
+        self.samples.lock().unwrap().push(sample);
+    }
+
+    fn report(&self, name: &str) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.is_empty() {
+            println!("{:<14} (no samples)", name);
+            return;
+        }
+        samples.sort_unstable();
+        let percentile =
+            |p: f64| format!("{:.2}ms", percentile(&samples, p).as_secs_f64() * 1000f64);
+        println!(
+            "{:<14} n={:<8} p50={:<10} p90={:<10} p99={:<10} max={}",
+            name,
+            samples.len(),
+            percentile(0.5),
+            percentile(0.9),
+            percentile(0.99),
+            percentile(1f64)
+        );
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    login: Samples,
+    chat: Samples,
+    fan_out: Samples,
+    joined: AtomicU64,
+    failed: AtomicU64,
+    lost: AtomicU64,
+    moves_sent: AtomicU64,
+    moves_received: AtomicU64,
+    chats_received: AtomicU64,
+    oneshot_errors: AtomicU64,
+}
+
+impl Stats {
+    fn report(&self, options: &Options, elapsed: Duration) {
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let per_sec = |counter: &AtomicU64| count(counter) as f64 / elapsed.as_secs_f64();
+        println!();
+        println!(
+            "Clients: {} joined, {} failed of {} ({} connection(s) lost)",
+            count(&self.joined),
+            count(&self.failed),
+            options.clients,
+            count(&self.lost)
+        );
+        println!(
+            "Moves:   {} sent ({:.1}/s), {} received ({:.1}/s)",
+            count(&self.moves_sent),
+            per_sec(&self.moves_sent),
+            count(&self.moves_received),
+            per_sec(&self.moves_received)
+        );
+        println!(
+            "Chats:   {} received ({:.1}/s), {} oneshot error(s)",
+            count(&self.chats_received),
+            per_sec(&self.chats_received),
+            count(&self.oneshot_errors)
+        );
+        println!();
+        self.login.report("login");
+        self.chat.report("chat");
+        self.fan_out.report("chat fan-out");
+    }
+}
+
+/// `index`番目のクライアントが、始めてから`t`秒後にいる位置です。
+fn scripted_transform(path: PathKind, index: usize, t: f64) -> StandingTransform {
+    let path = match path {
+        PathKind::Mixed => [PathKind::Circle, PathKind::Patrol][index % 2],
+        path => path,
+    };
+    // 同じ場所に固まらないよう、クライアントごとに位置をずらす
+    let lane = (index % 16) as f64;
+    let phase = index as f64 * 0.618 * TAU;
+    let (x, z, heading) = match path {
+        PathKind::Circle => {
+            let radius = 2f64 + lane;
+            let angle = phase + WALKING_SPEED / radius * t;
+            (
+                radius * angle.cos(),
+                radius * angle.sin(),
+                angle + PI / 2f64,
+            )
+        }
+        _ => {
+            let length = 10f64;
+            let walked = (phase / TAU * length * 2f64 + WALKING_SPEED * t) % (length * 2f64);
+            let (along, heading) = if walked < length {
+                (walked, 0f64)
+            } else {
+                (length * 2f64 - walked, PI)
+            };
+            (along - length / 2f64, lane - 8f64, heading)
+        }
+    };
+    StandingTransform {
+        x,
+        y: 0f64,
+        z,
+        yaw: (heading.cos() + 1f64) * heading.sin().signum(),
+    }
+}
+
+async fn run_bot(
+    index: usize,
+    options: Arc<Options>,
+    issuer: Arc<JoinTokenIssuer>,
+    config: ClientConfig,
+    stats: Arc<Stats>,
+    started: Instant,
+) -> anyhow::Result<()> {
+    let logger = EnvLogger {
+        target: format!("loadbot-{}", index),
+    };
+    let (client, mut events) = ClockingClient::connect(
+        config,
+        options.server_name.clone(),
+        options.addr.clone(),
+        ClientOptions::default(),
+        logger,
+    );
+
+    let connected = timeout(CONNECT_TIMEOUT, async {
+        while let Some(event) = events.recv().await {
+            match event {
+                ClientEvent::Connected { .. } => return Ok(()),
+                ClientEvent::GaveUp { reason } => bail!(reason),
+                _ => {}
+            }
+        }
+        bail!("The client has been closed")
+    })
+    .await;
+    if let Err(e) = connected.unwrap_or_else(|_| Err(anyhow!("Timed out while connecting"))) {
+        client.shutdown().await?;
+        return Err(e);
+    }
+
+    let token = issuer.issue_for(
+        format!("loadbot-{}", index),
+        options.instance,
+        options.duration + CONNECT_TIMEOUT,
+    );
+    let sent_at = Instant::now();
+    if let Err(e) = client.login(options.instance, token).await {
+        client.shutdown().await?;
+        return Err(e).context("Failed to log in");
+    }
+    stats.login.record(sent_at.elapsed());
+    stats.joined.fetch_add(1, Ordering::Relaxed);
+
+    let receiver_stats = stats.clone();
+    let receiver = tokio::spawn(async move {
+        // 接続し直している間や、引き継いだ後に届いた切れていた間のチャットは、
+        // 遅れて届くのが当然なので時間を計らない
+        let mut reconnecting = false;
+        let mut replayed_before = Duration::ZERO;
+        while let Some(event) = events.recv().await {
+            match event {
+                ClientEvent::PlayerMoved { .. } => {
+                    receiver_stats
+                        .moves_received
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                ClientEvent::ChatMessage { message, .. } => {
+                    receiver_stats
+                        .chats_received
+                        .fetch_add(1, Ordering::Relaxed);
+                    // 同じプロセスの他のクライアントが送ったものなら、送った時刻が分かる
+                    let Some((sender, sent_at)) = parse_chat(&message) else {
+                        continue;
+                    };
+                    if sender != index && !reconnecting && sent_at >= replayed_before {
+                        receiver_stats
+                            .fan_out
+                            .record(started.elapsed().saturating_sub(sent_at));
+                    }
+                }
+                ClientEvent::Lost { .. } => {
+                    receiver_stats.lost.fetch_add(1, Ordering::Relaxed);
+                    reconnecting = true;
+                }
+                ClientEvent::Connected { .. } => {
+                    reconnecting = false;
+                    replayed_before = started.elapsed();
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let deadline = Instant::now() + options.duration;
+    let chatter = options.chat_interval.map(|interval| {
+        let client = client.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            // 全員が同時に話し始めないよう、最初の発言をずらす
+            sleep(interval.mul_f64((index % 10) as f64 / 10f64)).await;
+            let mut ticker = time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let content = format!(
+                    "{} {} {}",
+                    CHAT_PREFIX,
+                    index,
+                    started.elapsed().as_micros()
+                );
+                let sent_at = Instant::now();
+                match client.send_chat(content).await {
+                    Ok(()) => stats.chat.record(sent_at.elapsed()),
+                    Err(_) => {
+                        stats.oneshot_errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        })
+    });
+
+    let mut ticker = time::interval(Duration::from_secs_f64(1f64 / options.move_rate));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let transform =
+                    scripted_transform(options.path, index, started.elapsed().as_secs_f64());
+                if let Ok(true) = client.report_transform(transform) {
+                    stats.moves_sent.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            _ = sleep_until(deadline) => break,
+        }
+    }
+
+    if let Some(chatter) = chatter {
+        chatter.abort();
+    }
+    client.shutdown().await?;
+    // クライアントが終わると、イベントも届かなくなる
+    receiver.await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let options = Arc::new(Options::parse(env::args().skip(1))?);
+
+    let pkcs8 = fs::read(&options.key)
+        .with_context(|| format!("Failed to read the key for join tokens: {:?}", options.key))?;
+    let issuer = Arc::new(
+        JoinTokenIssuer::from_pkcs8(&pkcs8)
+            .with_context(|| format!("Invalid key for join tokens: {:?}", options.key))?,
+    );
+    // 手元のサーバーに対して使うことを想定しているので、証明書は検証しない
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(AllowUnknownCertVerifier::new())
+        .with_no_client_auth();
+
+    println!(
+        "Spawning {} client(s) against instance {} on {}({}) ...",
+        options.clients, options.instance, options.server_name, options.addr
+    );
+    let stats = Arc::new(Stats::default());
+    let started = Instant::now();
+    let mut bots = Vec::with_capacity(options.clients);
+    for index in 0..options.clients {
+        let delay = options
+            .ramp_up
+            .mul_f64(index as f64 / options.clients as f64);
+        let options = options.clone();
+        let issuer = issuer.clone();
+        let config = config.clone();
+        let stats = stats.clone();
+        bots.push(tokio::spawn(async move {
+            sleep_until(started + delay).await;
+            if let Err(e) = run_bot(index, options, issuer, config, stats.clone(), started).await {
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Client {} failed: {:#}", index, e);
+            }
+        }));
+    }
+    for bot in bots {
+        bot.await?;
+    }
+
+    stats.report(&options, started.elapsed());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Options> {
+        Options::parse(
+            ["127.0.0.1:3501", "1"]
+                .iter()
+                .chain(args)
+                .map(|arg| arg.to_string()),
+        )
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.instance, 1);
+        assert_eq!(options.clients, 50);
+        assert_eq!(options.chat_interval, Some(Duration::from_secs(5)));
+        assert_eq!(options.path, PathKind::Mixed);
+
+        let options = parse(&[
+            "--clients",
+            "3",
+            "--move-rate",
+            "0.5",
+            "--chat-interval",
+            "0",
+            "--path",
+            "patrol",
+        ])
+        .unwrap();
+        assert_eq!(options.clients, 3);
+        assert_eq!(options.move_rate, 0.5);
+        assert_eq!(options.chat_interval, None);
+        assert_eq!(options.path, PathKind::Patrol);
+
+        assert!(parse(&["--move-rate", "0"]).is_err());
+        assert!(parse(&["--move-rate", "NaN"]).is_err());
+        assert!(parse(&["--move-rate", "-1"]).is_err());
+        assert!(parse(&["--chat-interval", "-1"]).is_err());
+        assert!(parse(&["--clients"]).is_err());
+        assert!(parse(&["--path", "square"]).is_err());
+        assert!(Options::parse(["127.0.0.1:3501".to_string()]).is_err());
+    }
+
+    #[test]
+    fn parse_chat_message() {
+        assert_eq!(
+            parse_chat("loadbot 3 1500"),
+            Some((3, Duration::from_micros(1500)))
+        );
+        assert_eq!(parse_chat("loadbot 3"), None);
+        assert_eq!(parse_chat("hello 3 1500"), None);
+    }
+
+    #[test]
+    fn percentiles() {
+        let samples = (1..=10).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&samples, 0.5), Duration::from_millis(5));
+        assert_eq!(percentile(&samples, 0.9), Duration::from_millis(9));
+        assert_eq!(percentile(&samples, 0.99), Duration::from_millis(10));
+        assert_eq!(percentile(&samples, 1f64), Duration::from_millis(10));
+        assert_eq!(percentile(&samples, 0f64), Duration::from_millis(1));
+
+        let single = [Duration::from_millis(7)];
+        assert_eq!(percentile(&single, 0.5), Duration::from_millis(7));
+        assert_eq!(percentile(&single, 1f64), Duration::from_millis(7));
+    }
+
+    #[test]
+    fn patrol_turns_around_smoothly() {
+        let distance = |a: &StandingTransform, b: &StandingTransform| {
+            ((a.x - b.x).powi(2) + (a.z - b.z).powi(2)).sqrt()
+        };
+        // 0番目は端から歩き始めるので、10m先で折り返し、20m先で元の位置に戻る
+        for turnaround in [10f64, 20f64] {
+            let t = turnaround / WALKING_SPEED;
+            let before = scripted_transform(PathKind::Patrol, 0, t - 0.01);
+            let after = scripted_transform(PathKind::Patrol, 0, t + 0.01);
+            assert!(
+                distance(&before, &after) < WALKING_SPEED * 0.02 + 1e-9,
+                "jumped at {}m: {:?} -> {:?}",
+                turnaround,
+                before,
+                after
+            );
+        }
+        // 折り返すと、向きが逆になる
+        let t = 10f64 / WALKING_SPEED;
+        let before = scripted_transform(PathKind::Patrol, 0, t - 0.01);
+        let after = scripted_transform(PathKind::Patrol, 0, t + 0.01);
+        assert!(before.yaw != after.yaw);
+    }
+}
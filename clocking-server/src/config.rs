@@ -0,0 +1,444 @@
+//! Clocking-serverの設定です。
+//!
+//! 設定ファイル(TOML)、環境変数、コマンドライン引数の順に読み込み、後のものほど優先されます。
+//! 設定ファイルの例は`clocking-server.example.toml`を参照してください。
+//!
+//! | 環境変数 | コマンドライン引数 | 設定ファイル |
+//! | --- | --- | --- |
+//! | `CLOCKING_CONFIG` | `--config <path>` | (設定ファイルの場所) |
+//! | `PORT` | `--port <port>` | `listen`の全てのポート |
+//! | | `--listen <addr>` (複数指定可) | `listen` |
+//! | `SINGLECERTS_CERT_PATH` | `--cert <path>` | `tls.cert` |
+//! | `SINGLECERTS_KEY_PATH` | `--key <path>` | `tls.key` |
+//! | `JOIN_TOKEN_PUBLIC_KEY` | | `auth.join_token_public_key` |
+
+use std::{
+    collections::HashSet,
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use suteravr_lib::{
+    clocking::join_token::JoinTokenVerifier,
+    messaging::id::{InstanceId, WorldId},
+};
+
+use crate::errors::ConfigError;
+
+/// `--config`も`CLOCKING_CONFIG`も指定されていない場合に、存在すれば読み込むファイルです。
+pub const DEFAULT_CONFIG_PATH: &str = "./clocking-server.toml";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClockingServerConfig {
+    /// 待ち受けるアドレスです。IPv6のアドレスは`[::1]:3501`のように書きます。
+    pub listen: Vec<SocketAddr>,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub healthcheck: HealthcheckConfig,
+    pub session: SessionConfig,
+    pub channels: ChannelCapacities,
+    /// 起動時に立ち上げるインスタンスです。
+    pub instances: Vec<InstanceConfig>,
+}
+
+impl Default for ClockingServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 3501))],
+            tls: TlsConfig::default(),
+            auth: AuthConfig::default(),
+            healthcheck: HealthcheckConfig::default(),
+            session: SessionConfig::default(),
+            channels: ChannelCapacities::default(),
+            instances: vec![InstanceConfig {
+                id: 0x01,
+                world: 0x01,
+            }],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM形式の証明書(チェーン)
+    pub cert: PathBuf,
+    /// PEM形式の秘密鍵
+    pub key: PathBuf,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::from("./certs/server.crt"),
+            key: PathBuf::from("./certs/server.key"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Balancing-serverが参加トークンの署名に使う鍵の公開鍵(Ed25519、Base64)です。
+    /// 指定しない場合、ログインは全て拒否されます。
+    pub join_token_public_key: Option<String>,
+}
+
+impl AuthConfig {
+    /// 公開鍵が指定されていれば、参加トークンを検証するものを返します。
+    pub fn join_token_verifier(&self) -> Result<Option<JoinTokenVerifier>, ConfigError> {
+        self.join_token_public_key
+            .as_deref()
+            .map(|key| {
+                JoinTokenVerifier::from_base64(key).map_err(|e| ConfigError::Invalid {
+                    field: "auth.join_token_public_key".to_string(),
+                    reason: e.to_string(),
+                })
+            })
+            .transpose()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthcheckConfig {
+    /// 何も起きなかった場合に、Healthcheckを送るまでの秒数
+    pub interval_secs: u64,
+    /// これを超えて続けて応答がなければ、接続を閉じる
+    pub max_missed: u32,
+}
+
+impl HealthcheckConfig {
+    #[inline]
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+}
+
+impl Default for HealthcheckConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 30,
+            max_missed: 6,
+        }
+    }
+}
+
+/// 接続が切れたプレイヤーを引き継ぐための設定です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// 接続が切れてから、引き継ぎを待つ秒数です。`0`にすると、すぐに退出させます。
+    pub resume_grace_secs: u64,
+    /// 接続が切れている間に溜めておくイベントの数です。これを超えると、引き継ぎを待たずに退出させます。
+    pub max_replay_events: usize,
+}
+
+impl SessionConfig {
+    #[inline]
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_secs(self.resume_grace_secs)
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            resume_grace_secs: 30,
+            max_replay_events: 256,
+        }
+    }
+}
+
+/// 各タスク間のチャンネルの容量です。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelCapacities {
+    /// TCPサーバーとインスタンスマネージャーへの指示
+    pub server: usize,
+    /// インスタンスへの指示
+    pub instance: usize,
+    /// 接続ごとの送受信
+    pub connection: usize,
+}
+
+impl Default for ChannelCapacities {
+    fn default() -> Self {
+        Self {
+            server: 32,
+            instance: 32,
+            connection: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceConfig {
+    pub id: InstanceId,
+    pub world: WorldId,
+}
+
+/// コマンドライン引数で指定された設定です。
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CommandLine {
+    pub config: Option<PathBuf>,
+    pub port: Option<u16>,
+    pub listen: Vec<SocketAddr>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl CommandLine {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut command_line = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |field: &'static str| {
+                args.next().ok_or(ConfigError::Invalid {
+                    field: field.to_string(),
+                    reason: "a value is required".to_string(),
+                })
+            };
+            match arg.as_str() {
+                "--config" => command_line.config = Some(value("--config")?.into()),
+                "--port" => command_line.port = Some(parse_field("--port", &value("--port")?)?),
+                "--listen" => command_line
+                    .listen
+                    .push(parse_field("--listen", &value("--listen")?)?),
+                "--cert" => command_line.cert = Some(value("--cert")?.into()),
+                "--key" => command_line.key = Some(value("--key")?.into()),
+                _ => return Err(ConfigError::UnknownArgument(arg)),
+            }
+        }
+        Ok(command_line)
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| ConfigError::Invalid {
+        field: field.to_string(),
+        reason: format!("{:?} ({})", value, e),
+    })
+}
+
+impl ClockingServerConfig {
+    #[inline]
+    pub fn from_toml(toml: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_toml(&toml).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// 設定ファイルを読み込み、環境変数とコマンドライン引数で上書きしたものを返します。
+    pub fn resolve(command_line: CommandLine) -> Result<Self, ConfigError> {
+        let path = command_line
+            .config
+            .clone()
+            .or_else(|| env::var_os("CLOCKING_CONFIG").map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => Self::load(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::load(DEFAULT_CONFIG_PATH)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.apply_command_line(command_line);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Ok(port) = env::var("PORT") {
+            self.set_port(parse_field("PORT", &port)?);
+        }
+        if let Some(cert) = env::var_os("SINGLECERTS_CERT_PATH") {
+            self.tls.cert = cert.into();
+        }
+        if let Some(key) = env::var_os("SINGLECERTS_KEY_PATH") {
+            self.tls.key = key.into();
+        }
+        if let Ok(key) = env::var("JOIN_TOKEN_PUBLIC_KEY") {
+            self.auth.join_token_public_key = Some(key);
+        }
+        Ok(())
+    }
+
+    pub fn apply_command_line(&mut self, command_line: CommandLine) {
+        if !command_line.listen.is_empty() {
+            self.listen = command_line.listen;
+        }
+        if let Some(port) = command_line.port {
+            self.set_port(port);
+        }
+        if let Some(cert) = command_line.cert {
+            self.tls.cert = cert;
+        }
+        if let Some(key) = command_line.key {
+            self.tls.key = key;
+        }
+    }
+
+    fn set_port(&mut self, port: u16) {
+        for addr in &mut self.listen {
+            addr.set_port(port);
+        }
+    }
+
+    /// 型は合っていても使えない値がないかを確かめます。
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field: &str, reason: &str| {
+            Err(ConfigError::Invalid {
+                field: field.to_string(),
+                reason: reason.to_string(),
+            })
+        };
+
+        if self.listen.is_empty() {
+            return invalid("listen", "at least one address is required");
+        }
+        self.auth.join_token_verifier()?;
+        if self.healthcheck.interval_secs == 0 {
+            return invalid("healthcheck.interval_secs", "must be greater than 0");
+        }
+        for (field, capacity) in [
+            ("channels.server", self.channels.server),
+            ("channels.instance", self.channels.instance),
+            ("channels.connection", self.channels.connection),
+        ] {
+            if capacity == 0 {
+                return invalid(field, "must be greater than 0");
+            }
+        }
+        let mut ids = HashSet::new();
+        for (i, instance) in self.instances.iter().enumerate() {
+            if !ids.insert(instance.id) {
+                return invalid(&format!("instances[{}].id", i), "duplicated instance id");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn example_config() {
+        let config =
+            ClockingServerConfig::from_toml(include_str!("../clocking-server.example.toml"))
+                .unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.listen,
+            vec![
+                SocketAddr::from(([127, 0, 0, 1], 3501)),
+                "[::1]:3501".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.instances.len(), 2);
+    }
+
+    #[test]
+    fn empty_config_is_default() {
+        assert_eq!(
+            ClockingServerConfig::from_toml("").unwrap(),
+            ClockingServerConfig::default()
+        );
+    }
+
+    #[test]
+    fn parse_error_names_field() {
+        let error = ClockingServerConfig::from_toml("[healthcheck]\ninterval_secs = \"30\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("interval_secs"), "{}", error);
+
+        let error = ClockingServerConfig::from_toml("[channels]\nconection = 16\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("conection"), "{}", error);
+    }
+
+    #[test]
+    fn validate_names_field() {
+        let mut config = ClockingServerConfig::from_toml(
+            "[[instances]]\nid = 1\nworld = 1\n\n[[instances]]\nid = 1\nworld = 2\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid { field, .. }) if field == "instances[1].id"
+        ));
+
+        config.instances.pop();
+        config.auth.join_token_public_key = Some("not a key".to_string());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid { field, .. }) if field == "auth.join_token_public_key"
+        ));
+
+        config.auth = AuthConfig::default();
+        config.channels.connection = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid { field, .. }) if field == "channels.connection"
+        ));
+    }
+
+    #[test]
+    fn command_line_overrides() {
+        let command_line = CommandLine::parse(
+            [
+                "--listen",
+                "[::1]:4000",
+                "--port",
+                "5000",
+                "--cert",
+                "a.crt",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        let mut config = ClockingServerConfig::default();
+        config.apply_command_line(command_line);
+        assert_eq!(config.listen, vec!["[::1]:5000".parse().unwrap()]);
+        assert_eq!(config.tls.cert, PathBuf::from("a.crt"));
+        assert_eq!(config.tls.key, TlsConfig::default().key);
+    }
+
+    #[test]
+    fn command_line_errors() {
+        assert!(matches!(
+            CommandLine::parse(["--port", "http"].map(String::from)),
+            Err(ConfigError::Invalid { field, .. }) if field == "--port"
+        ));
+        assert!(matches!(
+            CommandLine::parse(["--listen"].map(String::from)),
+            Err(ConfigError::Invalid { field, .. }) if field == "--listen"
+        ));
+        assert!(matches!(
+            CommandLine::parse(["--verbose"].map(String::from)),
+            Err(ConfigError::UnknownArgument(arg)) if arg == "--verbose"
+        ));
+    }
+}
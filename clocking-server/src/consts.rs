@@ -1,15 +1,11 @@
 use once_cell::sync::Lazy;
-use std::env;
+use std::{env, path::PathBuf};
 
 pub enum SuteraEnv {
     Development,
     Production,
 }
 
-pub static PORT: Lazy<u16> = Lazy::new(|| match env::var("PORT") {
-    Ok(val) => val.parse().unwrap(),
-    Err(_) => 3501,
-});
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub static ENV: Lazy<SuteraEnv> = Lazy::new(|| match env::var("ENV") {
     Ok(val) => match val.to_lowercase().as_str() {
@@ -19,3 +15,6 @@ pub static ENV: Lazy<SuteraEnv> = Lazy::new(|| match env::var("ENV") {
     },
     Err(_) => SuteraEnv::Development,
 });
+/// 指定されている場合、接続ごとに読み書きしたフレームをこのディレクトリにキャプチャします。
+pub static CAPTURE_DIR: Lazy<Option<PathBuf>> =
+    Lazy::new(|| env::var_os("CAPTURE_DIR").map(PathBuf::from));
@@ -1,4 +1,6 @@
-use suteravr_lib::clocking::ClockingFramingError;
+use std::path::PathBuf;
+
+use suteravr_lib::clocking::{join_token::JoinTokenError, ClockingFramingError};
 use thiserror::Error;
 use tokio::sync::{mpsc::error::SendError, oneshot};
 
@@ -16,6 +18,9 @@ pub enum ClockingServerError {
 
     TcpServerError(#[from] TcpServerError),
     InstanceError(#[from] InstanceError),
+    ConfigError(#[from] ConfigError),
+    CertsError(#[from] CertsError),
+    JoinTokenError(#[from] JoinTokenError),
 
     CannotSendShutdown(anyhow::Error),
 
@@ -64,3 +69,78 @@ pub enum InstanceError {
     #[error("The oneshot reply cannot be sent.")]
     CannotSendReply,
 }
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read the config file {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse the config file {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Invalid value for `{field}`: {reason}")]
+    Invalid { field: String, reason: String },
+    #[error("Unknown argument: {0}")]
+    UnknownArgument(String),
+}
+
+/// 証明書と秘密鍵をどこから読み込もうとしたかです。
+#[derive(Debug)]
+pub enum CertsSource {
+    /// 環境変数`SINGLECERTS_CERT_PEM`/`SINGLECERTS_KEY_PEM`
+    Env,
+    CertFile(PathBuf),
+    KeyFile(PathBuf),
+    /// 証明書と秘密鍵の組み合わせ
+    Files {
+        cert: PathBuf,
+        key: PathBuf,
+    },
+}
+
+impl std::fmt::Display for CertsSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CertsSource::Env => write!(f, "SINGLECERTS_CERT_PEM/SINGLECERTS_KEY_PEM"),
+            CertsSource::CertFile(path) => write!(f, "the certificate file {:?}", path),
+            CertsSource::KeyFile(path) => write!(f, "the private key file {:?}", path),
+            CertsSource::Files { cert, key } => write!(f, "{:?} and {:?}", cert, key),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CertsErrorKind {
+    #[error(transparent)]
+    Io(std::io::Error),
+    #[error("No certificate was found.")]
+    NoCertificate,
+    #[error("No private key was found. PKCS#1, PKCS#8 and SEC1 PEM are supported.")]
+    NoPrivateKey,
+    #[error("The certificate is invalid: {0}")]
+    InvalidCertificate(webpki::Error),
+    #[error("The private key is not supported: {0}")]
+    UnsupportedKey(tokio_rustls::rustls::Error),
+    #[error("The private key does not match the certificate.")]
+    KeyMismatch,
+    #[error("Failed to generate a self-signed certificate: {0}")]
+    Generate(rcgen::Error),
+}
+
+#[derive(Debug, Error)]
+#[error("Failed to load certifications from {from}: {kind}")]
+pub struct CertsError {
+    pub from: CertsSource,
+    pub kind: CertsErrorKind,
+}
+
+impl CertsError {
+    #[inline]
+    pub fn new(from: CertsSource, kind: CertsErrorKind) -> Self {
+        Self { from, kind }
+    }
+}
@@ -14,12 +14,13 @@ use tokio::{
 };
 
 use crate::{
+    config::SessionConfig,
     errors::{ClockingServerError, InstanceError},
     instance::launch_instance,
     shutdown::ShutdownReason,
 };
 
-use super::{InstanceControl, PlayerControl};
+use super::{session::ResumeToken, InstanceControl, PlayerControl};
 
 pub enum InstancesControl {
     Shutdown(ShutdownReason),
@@ -28,15 +29,33 @@ pub enum InstancesControl {
         world: WorldId,
         reply: oneshot::Sender<Option<mpsc::Sender<InstanceControl>>>,
     },
-    // FIXME: Balancing-serverに問い合わせるか、データベースから正常なトークンを貰っているかを確認する必要があります。
-    //        現状、インスタンスIDさえ分かれば、誰でもインスタンスに入れてしまうので、セキュリティ上の問題があります。
+    /// 参加トークンは検証済みである必要があります。
     JoinInstance {
         id: InstanceId,
         control: mpsc::Sender<PlayerControl>,
-        // FIXME: 型が複雑すぎるぞ!!!とお叱りを受けるが、なおすのはあとで
-        #[allow(clippy::type_complexity)]
-        reply: oneshot::Sender<Option<((PlayerId, mpsc::Sender<InstanceControl>), Vec<PlayerId>)>>,
+        reply: oneshot::Sender<Option<Joined>>,
     },
+    /// 接続が切れたプレイヤーを、`control`の接続で引き継ぎます。
+    Resume {
+        token: ResumeToken,
+        control: mpsc::Sender<PlayerControl>,
+        reply: oneshot::Sender<Option<Resumed>>,
+    },
+}
+
+pub struct Joined {
+    pub player: PlayerId,
+    pub instance: mpsc::Sender<InstanceControl>,
+    pub players: Vec<PlayerId>,
+    pub resume_token: ResumeToken,
+}
+
+pub struct Resumed {
+    pub player: PlayerId,
+    pub instance: mpsc::Sender<InstanceControl>,
+    pub resume_token: ResumeToken,
+    /// 接続が切れている間に届いたイベントです。
+    pub missed: Vec<PlayerControl>,
 }
 
 pub struct InstanceManager {
@@ -57,6 +76,8 @@ impl InstanceManager {
 
 pub async fn launch_instance_manager(
     mut command_receiver: mpsc::Receiver<InstancesControl>,
+    channel_capacity: usize,
+    session: SessionConfig,
 ) -> Result<(), ClockingServerError> {
     let logger = EnvLogger {
         target: "instance-manager".to_string(),
@@ -72,7 +93,7 @@ pub async fn launch_instance_manager(
                     },
                     InstancesControl::SpawnNew { id, world, reply } => {
                         let instance_connection = if let hash_map::Entry::Vacant(o) = mng.instances.entry(id) {
-                            let (instance_tx, instance_rx) = mpsc::channel::<InstanceControl>(32);
+                            let (instance_tx, instance_rx) = mpsc::channel::<InstanceControl>(channel_capacity);
                             o.insert(instance_tx.clone());
                             mng.handles
                                 .build_task()
@@ -81,6 +102,7 @@ pub async fn launch_instance_manager(
                                     launch_instance(
                                         id,
                                         world,
+                                        session,
                                         instance_rx,
                                     )
                                 )?;
@@ -95,11 +117,12 @@ pub async fn launch_instance_manager(
                     InstancesControl::JoinInstance { id, reply, control } => {
                         let result = if let Some(tx) = mng.instances.get(&id) {
                             let player_id = mng.player_id_dispatch.fetch_add(1, std::sync::atomic::Ordering::Relaxed) as PlayerId;
-                            let (tx_i, rx_i) = oneshot::channel::<Vec<PlayerId>>();
+                            let (tx_i, rx_i) = oneshot::channel();
                             tx.send(InstanceControl::Join(player_id, control, tx_i))
                                 .await
                                 .map_err(|e| ClockingServerError::CannotSendShutdown(e.into()))?;
-                            Some(((player_id, tx.clone()), rx_i.await.map_err(|_| ClockingServerError::CannotReceiveReply)?))
+                            let joined = rx_i.await.map_err(|_| ClockingServerError::CannotReceiveReply)?;
+                            Some(Joined { player: player_id, instance: tx.clone(), players: joined.players, resume_token: joined.resume_token })
                         } else {
                             error!(logger, "Failed to join the instance {:?}. The instance was not found.", id);
                             None
@@ -107,6 +130,22 @@ pub async fn launch_instance_manager(
                         reply.send(result)
                             .map_err(|_| ClockingServerError::CannotSendReply)?;
                     },
+                    InstancesControl::Resume { token, reply, control } => {
+                        let result = if let Some(tx) = mng.instances.get(&token.instance) {
+                            let player_id = token.player;
+                            let (tx_i, rx_i) = oneshot::channel();
+                            tx.send(InstanceControl::Resume(token, control, tx_i))
+                                .await
+                                .map_err(|e| ClockingServerError::CannotSendShutdown(e.into()))?;
+                            rx_i.await
+                                .map_err(|_| ClockingServerError::CannotReceiveReply)?
+                                .map(|resumed| Resumed { player: player_id, instance: tx.clone(), resume_token: resumed.resume_token, missed: resumed.missed })
+                        } else {
+                            None
+                        };
+                        reply.send(result)
+                            .map_err(|_| ClockingServerError::CannotSendReply)?;
+                    },
                 }
             }
         }
@@ -1,10 +1,14 @@
 use std::collections::{hash_map::Entry, HashMap};
 
+use bytes::Bytes;
 use derivative::Derivative;
 use suteravr_lib::{
-    clocking::schemas::{
-        event::player_move::{PubPlayerMove, PushPlayerMove},
-        oneshot::chat_entry::ChatEntry,
+    clocking::{
+        messages::{EventMessage, PushPlayerMoveEvent},
+        schemas::{
+            event::player_move::{PubPlayerMove, PushPlayerMove},
+            oneshot::chat_entry::ChatEntry,
+        },
     },
     debug, error, info,
     messaging::id::{InstanceId, PlayerId, WorldId},
@@ -14,18 +18,32 @@ use suteravr_lib::{
 use tokio::{
     sync::{mpsc, oneshot},
     task,
+    time::{self, Instant},
 };
 
-use crate::{errors::InstanceError, shutdown::ShutdownReason};
+use crate::{config::SessionConfig, errors::InstanceError, shutdown::ShutdownReason};
+
+use self::session::{MissedEvents, Player, PlayerSlot, ResumeToken};
 
 pub mod manager;
+pub mod session;
 pub enum InstanceControl {
     Shutdown(ShutdownReason),
     Join(
         PlayerId,
         mpsc::Sender<PlayerControl>,
-        oneshot::Sender<Vec<PlayerId>>,
+        oneshot::Sender<JoinedInstance>,
+    ),
+    /// 接続を引き継ぎます。トークンが正しければ、新しいトークンと送り直すイベントを返します。
+    Resume(
+        ResumeToken,
+        mpsc::Sender<PlayerControl>,
+        oneshot::Sender<Option<ResumedInstance>>,
     ),
+    /// 接続が切れました。猶予期間が過ぎるまでは、参加したままにします。
+    ///
+    /// 既に別の接続に引き継がれている場合は無視するので、切れた接続の`Sender`を渡してください。
+    Detach(PlayerId, mpsc::Sender<PlayerControl>),
     Leave(PlayerId),
     ChatMesasge(ChatEntry),
     PlayerMoved(PlayerId, PubPlayerMove),
@@ -35,7 +53,22 @@ pub enum PlayerControl {
     PlayerJoined(PlayerId),
     PlayerLeft(PlayerId),
     NewChatMessage(ChatEntry),
-    PlayerMoved(PushPlayerMove),
+    /// エンコード済みの[`PushPlayerMove`]です。全員に同じペイロードを共有します。
+    PlayerMoved(PlayerId, Bytes),
+    /// セッションが別の接続に引き継がれました。受け取った接続は閉じてください。
+    Replaced,
+}
+
+pub struct JoinedInstance {
+    /// 既に参加しているプレイヤーです。
+    pub players: Vec<PlayerId>,
+    pub resume_token: ResumeToken,
+}
+
+pub struct ResumedInstance {
+    pub resume_token: ResumeToken,
+    /// 接続が切れている間に届いたイベントです。
+    pub missed: Vec<PlayerControl>,
 }
 
 #[derive(Derivative)]
@@ -43,8 +76,10 @@ pub enum PlayerControl {
 pub struct Instance {
     pub id: InstanceId,
     pub world: WorldId,
-    pub players: HashMap<PlayerId, mpsc::Sender<PlayerControl>>,
+    #[derivative(Debug = "ignore")]
+    pub players: HashMap<PlayerId, Player>,
     pub chat_history: Vec<ChatEntry>,
+    pub session: SessionConfig,
 
     #[derivative(Debug = "ignore")]
     pub logger: EnvLogger,
@@ -54,71 +89,180 @@ impl Instance {
     fn new(
         id: InstanceId,
         world: WorldId,
-        players: HashMap<PlayerId, mpsc::Sender<PlayerControl>>,
+        session: SessionConfig,
         chat_history: Vec<ChatEntry>,
         logger: EnvLogger,
     ) -> Self {
         Self {
             id,
             world,
-            players,
+            players: HashMap::new(),
             chat_history,
+            session,
             logger,
         }
     }
+
+    /// 接続しているプレイヤーです。`except`は除きます。
+    fn connected(&self, except: Option<PlayerId>) -> Vec<(PlayerId, mpsc::Sender<PlayerControl>)> {
+        self.players
+            .iter()
+            .filter(|(id, _)| Some(**id) != except)
+            .filter_map(|(id, player)| match &player.slot {
+                PlayerSlot::Connected(sender) => Some((*id, sender.clone())),
+                PlayerSlot::Detached { .. } => None,
+            })
+            .collect()
+    }
+
+    /// 接続が切れているプレイヤーの分は、引き継がれた時に送り直せるよう溜めておきます。
+    fn buffer_missed(&mut self, except: Option<PlayerId>, content: &PlayerControl) {
+        let limit = self.session.max_replay_events;
+        for (id, player) in self.players.iter_mut() {
+            if Some(*id) == except {
+                continue;
+            }
+            if let PlayerSlot::Detached { missed, .. } = &mut player.slot {
+                missed.push(content.clone(), limit);
+            }
+        }
+    }
+
+    /// 猶予期間が過ぎたか、送り直せないほどイベントが溜まったプレイヤーです。
+    fn expired(&self, now: Instant) -> Vec<PlayerId> {
+        self.players
+            .iter()
+            .filter(|(_, player)| match &player.slot {
+                PlayerSlot::Detached { expires_at, missed } => {
+                    *expires_at <= now || missed.overflowed()
+                }
+                PlayerSlot::Connected(_) => false,
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn next_expiry(&self) -> Option<Instant> {
+        self.players
+            .values()
+            .filter_map(|player| match &player.slot {
+                PlayerSlot::Detached { expires_at, .. } => Some(*expires_at),
+                PlayerSlot::Connected(_) => None,
+            })
+            .min()
+    }
 }
 
 pub async fn launch_instance(
     id: InstanceId,
     world: WorldId,
+    session: SessionConfig,
     mut command_receiver: mpsc::Receiver<InstanceControl>,
 ) -> Result<(), InstanceError> {
     let logger = EnvLogger {
         target: format!("instance-{:?}", id),
     };
-    let mut instance = Instance::new(id, world, HashMap::new(), Vec::new(), logger.clone());
+    let mut instance = Instance::new(id, world, session, Vec::new(), logger.clone());
     info!(logger, "Instance started.");
 
     loop {
+        let next_expiry = instance.next_expiry();
         tokio::select! {
+            _ = time::sleep_until(next_expiry.unwrap_or_else(Instant::now)), if next_expiry.is_some() => {
+                for player_id in instance.expired(Instant::now()) {
+                    info!(logger, "Resume grace period expired (id: {:?}).", player_id);
+                    leave(&mut instance, &logger, player_id)?;
+                }
+            },
             Some(command) = command_receiver.recv() => {
                 match command {
                     InstanceControl::Shutdown(_) => {
                         break;
                     },
                     InstanceControl::Join(player_id, sender, reply_pos) => {
+                        let resume_token = ResumeToken::generate(id, player_id);
                         match instance.players.entry(player_id) {
                             Entry::Vacant(o) => {
-                                o.insert(sender);
+                                o.insert(Player { token: resume_token.clone(), slot: PlayerSlot::Connected(sender) });
                                 info!(logger, "Player joined (id: {:?}), currently {} player(s) in instance.", player_id, instance.players.len());
-                                notify(&instance, "PlayerJoined".to_string(), &logger, player_id,  PlayerControl::PlayerJoined(player_id))?;
-                                reply_pos.send(instance.players.keys().cloned().filter(|p| *p != player_id).collect()).map_err(|_| InstanceError::CannotSendReply)?;
+                                notify(&mut instance, "PlayerJoined".to_string(), &logger, player_id,  PlayerControl::PlayerJoined(player_id))?;
+                                let players = instance.players.keys().cloned().filter(|p| *p != player_id).collect();
+                                reply_pos.send(JoinedInstance { players, resume_token }).map_err(|_| InstanceError::CannotSendReply)?;
                             },
                             Entry::Occupied(mut o) => {
-                                o.insert(sender);
+                                o.insert(Player { token: resume_token, slot: PlayerSlot::Connected(sender) });
                                 warn!(logger, "Join request received but already in instance (id: {:?}).", player_id);
                             }
                         }
                     },
+                    InstanceControl::Resume(token, sender, reply) => {
+                        let resumed = match instance.players.get_mut(&token.player) {
+                            Some(player) if player.token.matches(&token) => {
+                                player.token = ResumeToken::generate(id, token.player);
+                                let slot = std::mem::replace(&mut player.slot, PlayerSlot::Connected(sender));
+                                let missed = match slot {
+                                    PlayerSlot::Detached { missed, .. } => missed.into_replay(),
+                                    // 前の接続が切れたことに、まだ気付いていなかった。
+                                    // 生きていた場合に同じプレイヤーとして振る舞えないよう、閉じさせる
+                                    PlayerSlot::Connected(previous) => {
+                                        task::Builder::new()
+                                            .name("Replaced Notify")
+                                            .spawn(async move {
+                                                let _ = previous.send(PlayerControl::Replaced).await;
+                                            })
+                                            .map_err(InstanceError::SpawnError)?;
+                                        Vec::new()
+                                    },
+                                };
+                                info!(logger, "Player resumed (id: {:?}), replaying {} event(s).", token.player, missed.len());
+                                Some(ResumedInstance { resume_token: player.token.clone(), missed })
+                            },
+                            _ => {
+                                info!(logger, "Resume refused (id: {:?}).", token.player);
+                                None
+                            },
+                        };
+                        reply.send(resumed).map_err(|_| InstanceError::CannotSendReply)?;
+                    },
+                    InstanceControl::Detach(player_id, sender) => {
+                        let Some(player) = instance.players.get_mut(&player_id) else {
+                            continue;
+                        };
+                        if !matches!(&player.slot, PlayerSlot::Connected(current) if current.same_channel(&sender)) {
+                            // 既に別の接続に引き継がれている
+                            continue;
+                        }
+                        if instance.session.resume_grace().is_zero() {
+                            leave(&mut instance, &logger, player_id)?;
+                            continue;
+                        }
+                        player.slot = PlayerSlot::Detached {
+                            expires_at: Instant::now() + instance.session.resume_grace(),
+                            missed: MissedEvents::default(),
+                        };
+                        info!(logger, "Player disconnected (id: {:?}), waiting {:?} for resume.", player_id, instance.session.resume_grace());
+                    },
                     InstanceControl::Leave(player_id) => {
-                        instance.players.remove(&player_id);
-                        info!(logger, "Player left: (id: {:?}), currently {} player(s) in instance.", player_id, instance.players.len());
-                        notify(&instance, "PlayerLeft".to_string(), &logger, player_id,  PlayerControl::PlayerLeft(player_id))?;
+                        leave(&mut instance, &logger, player_id)?;
                     },
                     InstanceControl::PlayerMoved(player_id, pub_player_move) => {
                         debug!(logger, "PlayerMoved: {:?}", pub_player_move);
                         notify(
-                            &instance, "PlayerMoved".to_string(), &logger, player_id,
-                            PlayerControl::PlayerMoved(
+                            &mut instance, "PlayerMoved".to_string(), &logger, player_id,
+                            PlayerControl::PlayerMoved(player_id, PushPlayerMoveEvent::encode(
                                 PushPlayerMove { player: player_id, now: pub_player_move.now }
-                            )
+                            ))
                         )?;
                     }
                     InstanceControl::ChatMesasge(chat_entry) => {
                         instance.chat_history.push(chat_entry.clone());
                         info!(logger, "TextChat: {:?}", chat_entry);
-                        for (_, sender) in instance.players.iter() {
-                            sender.send(PlayerControl::NewChatMessage(chat_entry.clone())).await?;
+                        let content = PlayerControl::NewChatMessage(chat_entry);
+                        instance.buffer_missed(None, &content);
+                        for (player_id, sender) in instance.connected(None) {
+                            if let Err(e) = sender.send(content.clone()).await {
+                                warn!(logger, "Failed to send the chat message to Player {}: {:?}", player_id, e);
+                            }
                         }
                     },
                 }
@@ -129,21 +273,39 @@ pub async fn launch_instance(
     Ok::<(), InstanceError>(())
 }
 
+fn leave(
+    instance: &mut Instance,
+    logger: &EnvLogger,
+    player_id: PlayerId,
+) -> Result<(), InstanceError> {
+    if instance.players.remove(&player_id).is_none() {
+        return Ok(());
+    }
+    info!(
+        *logger,
+        "Player left: (id: {:?}), currently {} player(s) in instance.",
+        player_id,
+        instance.players.len()
+    );
+    notify(
+        instance,
+        "PlayerLeft".to_string(),
+        logger,
+        player_id,
+        PlayerControl::PlayerLeft(player_id),
+    )
+}
+
 fn notify(
-    instance: &Instance,
+    instance: &mut Instance,
     mut name: String,
     logger: &EnvLogger,
     author: PlayerId,
     content: PlayerControl,
 ) -> Result<(), InstanceError> {
-    let iter = instance
-        .players
-        .iter()
-        .filter(|(k, _)| **k != author)
-        .map(|(k, v)| (*k, v.clone()))
-        .collect::<Vec<_>>();
+    instance.buffer_missed(Some(author), &content);
     name.push_str(" Notify");
-    for (target_player_id, sender) in iter {
+    for (target_player_id, sender) in instance.connected(Some(author)) {
         let logger = logger.clone();
         let content = content.clone();
         task::Builder::new()
@@ -158,5 +320,185 @@ fn notify(
             })
             .map_err(InstanceError::SpawnError)?;
     }
+    // 溜めきれなくなったプレイヤーは、引き継げないので参加をやめさせる
+    for player_id in instance.expired(Instant::now()) {
+        warn!(
+            *logger,
+            "Too many events missed while disconnected (id: {:?}).", player_id
+        );
+        leave(instance, logger, player_id)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    struct Joined {
+        rx: mpsc::Receiver<PlayerControl>,
+        tx: mpsc::Sender<PlayerControl>,
+        resume_token: ResumeToken,
+    }
+
+    async fn join(instance: &mpsc::Sender<InstanceControl>, player: PlayerId) -> Joined {
+        let (tx, rx) = mpsc::channel(8);
+        let (reply, reply_rx) = oneshot::channel();
+        instance
+            .send(InstanceControl::Join(player, tx.clone(), reply))
+            .await
+            .unwrap();
+        Joined {
+            rx,
+            tx,
+            resume_token: reply_rx.await.unwrap().resume_token,
+        }
+    }
+
+    async fn resume(
+        instance: &mpsc::Sender<InstanceControl>,
+        token: ResumeToken,
+    ) -> (Joined, Option<Vec<PlayerControl>>) {
+        let (tx, rx) = mpsc::channel(8);
+        let (reply, reply_rx) = oneshot::channel();
+        instance
+            .send(InstanceControl::Resume(token.clone(), tx.clone(), reply))
+            .await
+            .unwrap();
+        let resumed = reply_rx.await.unwrap();
+        let resume_token = resumed
+            .as_ref()
+            .map_or(token, |resumed| resumed.resume_token.clone());
+        let joined = Joined {
+            rx,
+            tx,
+            resume_token,
+        };
+        (joined, resumed.map(|resumed| resumed.missed))
+    }
+
+    fn describe(control: &PlayerControl) -> String {
+        match control {
+            PlayerControl::PlayerJoined(id) => format!("joined {}", id),
+            PlayerControl::PlayerLeft(id) => format!("left {}", id),
+            PlayerControl::PlayerMoved(id, _) => format!("moved {}", id),
+            PlayerControl::NewChatMessage(_) => "chat".to_string(),
+            PlayerControl::Replaced => "replaced".to_string(),
+        }
+    }
+
+    fn start(session: SessionConfig) -> mpsc::Sender<InstanceControl> {
+        let (tx, rx) = mpsc::channel(8);
+        tokio::spawn(launch_instance(0x01, 0x01, session, rx));
+        tx
+    }
+
+    #[tokio::test]
+    async fn resume_replays_missed_events() {
+        let instance = start(SessionConfig::default());
+        let mut a = join(&instance, 1).await;
+        let b = join(&instance, 2).await;
+        assert_eq!(describe(&a.rx.recv().await.unwrap()), "joined 2");
+
+        instance
+            .send(InstanceControl::Detach(2, b.tx.clone()))
+            .await
+            .unwrap();
+        let _c = join(&instance, 3).await;
+        assert_eq!(describe(&a.rx.recv().await.unwrap()), "joined 3");
+
+        // 他のプレイヤーのトークンでは引き継げない
+        let (_, missed) = resume(&instance, ResumeToken::generate(0x01, 2)).await;
+        assert!(missed.is_none());
+
+        let (mut b2, missed) = resume(&instance, b.resume_token.clone()).await;
+        let missed = missed.unwrap().iter().map(describe).collect::<Vec<_>>();
+        assert_eq!(missed, vec!["joined 3"]);
+        // 使ったトークンは無効になる
+        assert!(resume(&instance, b.resume_token).await.1.is_none());
+
+        // 前の接続が遅れて切れても、引き継いだ接続はそのまま
+        instance
+            .send(InstanceControl::Detach(2, b.tx))
+            .await
+            .unwrap();
+        let _d = join(&instance, 4).await;
+        assert_eq!(describe(&b2.rx.recv().await.unwrap()), "joined 4");
+        assert_eq!(describe(&a.rx.recv().await.unwrap()), "joined 4");
+        assert!(a.rx.try_recv().is_err());
+        drop(b2.tx);
+
+        instance
+            .send(InstanceControl::Shutdown(ShutdownReason::Requested))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_replaces_live_connection() {
+        let instance = start(SessionConfig::default());
+        let mut a = join(&instance, 1).await;
+        let mut b = join(&instance, 2).await;
+        assert_eq!(describe(&a.rx.recv().await.unwrap()), "joined 2");
+
+        // 前の接続が生きたままでも引き継げるが、前の接続には閉じるよう伝える
+        let (mut b2, missed) = resume(&instance, b.resume_token.clone()).await;
+        assert_eq!(missed.unwrap().len(), 0);
+        assert_eq!(describe(&b.rx.recv().await.unwrap()), "replaced");
+
+        // 閉じた前の接続が切れても、引き継いだ接続はそのまま
+        instance
+            .send(InstanceControl::Detach(2, b.tx))
+            .await
+            .unwrap();
+        let _c = join(&instance, 3).await;
+        assert_eq!(describe(&b2.rx.recv().await.unwrap()), "joined 3");
+        assert_eq!(describe(&a.rx.recv().await.unwrap()), "joined 3");
+        assert!(a.rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn leave_without_grace() {
+        let instance = start(SessionConfig {
+            resume_grace_secs: 0,
+            ..SessionConfig::default()
+        });
+        let mut a = join(&instance, 1).await;
+        let b = join(&instance, 2).await;
+        assert_eq!(describe(&a.rx.recv().await.unwrap()), "joined 2");
+
+        instance
+            .send(InstanceControl::Detach(2, b.tx))
+            .await
+            .unwrap();
+        assert_eq!(describe(&a.rx.recv().await.unwrap()), "left 2");
+        assert!(resume(&instance, b.resume_token).await.1.is_none());
+    }
+
+    #[tokio::test]
+    async fn leave_when_too_many_events_missed() {
+        let instance = start(SessionConfig {
+            max_replay_events: 1,
+            ..SessionConfig::default()
+        });
+        let mut a = join(&instance, 1).await;
+        let b = join(&instance, 2).await;
+        assert_eq!(describe(&a.rx.recv().await.unwrap()), "joined 2");
+
+        instance
+            .send(InstanceControl::Detach(2, b.tx))
+            .await
+            .unwrap();
+        let _c = join(&instance, 3).await;
+        let _d = join(&instance, 4).await;
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(describe(&a.rx.recv().await.unwrap()));
+        }
+        received.sort();
+        assert_eq!(received, vec!["joined 3", "joined 4", "left 2"]);
+        assert!(resume(&instance, b.resume_token).await.1.is_none());
+    }
+}
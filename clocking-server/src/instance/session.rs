@@ -0,0 +1,201 @@
+//! 接続が切れたプレイヤーを、猶予期間の間だけインスタンスに残しておくための仕組みです。
+//!
+//! 接続が切れても、すぐには`PlayerLeft`を通知しません。猶予期間のうちに[`ResumeToken`]を持った
+//! 新しい接続が来れば、同じ`PlayerId`のまま引き継ぎ、その間に届くはずだったイベントを送り直します。
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use bytes::Bytes;
+use ring::rand::{SecureRandom, SystemRandom};
+use suteravr_lib::messaging::id::{InstanceId, PlayerId};
+use thiserror::Error;
+use tokio::{sync::mpsc, time::Instant};
+
+use super::PlayerControl;
+
+const SECRET_SIZE: usize = 16;
+
+/// 接続を引き継ぐためのトークンです。引き継ぐたびに新しいものに替わります。
+///
+/// クライアントには、インスタンスID、プレイヤーID、秘密の値を16進数で並べた文字列として渡します。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeToken {
+    pub instance: InstanceId,
+    pub player: PlayerId,
+    secret: [u8; SECRET_SIZE],
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Invalid resume token")]
+pub struct ParseResumeTokenError;
+
+impl ResumeToken {
+    pub fn generate(instance: InstanceId, player: PlayerId) -> Self {
+        let mut secret = [0u8; SECRET_SIZE];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .expect("The system random number generator is unavailable");
+        Self {
+            instance,
+            player,
+            secret,
+        }
+    }
+
+    /// 比べるのにかかる時間から、秘密の値を推測されないようにします。
+    pub fn matches(&self, other: &Self) -> bool {
+        let secret = self
+            .secret
+            .iter()
+            .zip(other.secret.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        self.instance == other.instance && self.player == other.player && secret == 0
+    }
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}{:08x}", self.instance, self.player)?;
+        for byte in self.secret {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = ParseResumeTokenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 16 + 8 + SECRET_SIZE * 2 || !s.is_ascii() {
+            return Err(ParseResumeTokenError);
+        }
+        let hex = |range: std::ops::Range<usize>| {
+            u64::from_str_radix(&s[range], 16).map_err(|_| ParseResumeTokenError)
+        };
+        let mut secret = [0u8; SECRET_SIZE];
+        for (i, byte) in secret.iter_mut().enumerate() {
+            *byte = hex(24 + i * 2..26 + i * 2)? as u8;
+        }
+        Ok(Self {
+            instance: hex(0..16)?,
+            player: hex(16..24)? as PlayerId,
+            secret,
+        })
+    }
+}
+
+/// 接続が切れている間に届いたイベントです。
+///
+/// 移動は最後の位置だけ分かれば良いので、プレイヤーごとに最新のものだけを残します。
+#[derive(Default)]
+pub struct MissedEvents {
+    events: Vec<PlayerControl>,
+    moves: HashMap<PlayerId, Bytes>,
+    overflowed: bool,
+}
+
+impl MissedEvents {
+    /// `limit`を超えた場合は、それ以上溜めずに[`MissedEvents::overflowed`]を返すようになります。
+    pub fn push(&mut self, control: PlayerControl, limit: usize) {
+        match control {
+            PlayerControl::PlayerMoved(player, moved) => {
+                self.moves.insert(player, moved);
+            }
+            control => {
+                if let PlayerControl::PlayerLeft(player) = control {
+                    self.moves.remove(&player);
+                }
+                if self.events.len() >= limit {
+                    self.overflowed = true;
+                    return;
+                }
+                self.events.push(control);
+            }
+        }
+    }
+
+    /// 溜めきれずに、送り直せないイベントがあるかです。
+    #[inline]
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// 届いた順に送り直すイベントです。移動は最後にまとめて送ります。
+    pub fn into_replay(self) -> Vec<PlayerControl> {
+        let moves = self
+            .moves
+            .into_iter()
+            .map(|(player, moved)| PlayerControl::PlayerMoved(player, moved));
+        self.events.into_iter().chain(moves).collect()
+    }
+}
+
+/// インスタンスに参加しているプレイヤーの接続の状態です。
+pub enum PlayerSlot {
+    Connected(mpsc::Sender<PlayerControl>),
+    /// 接続が切れて、引き継がれるのを待っています。
+    Detached {
+        expires_at: Instant,
+        missed: MissedEvents,
+    },
+}
+
+pub struct Player {
+    pub token: ResumeToken,
+    pub slot: PlayerSlot,
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn token_round_trip() {
+        let token = ResumeToken::generate(0x0123_4567_89ab_cdef, 42);
+        let parsed: ResumeToken = token.to_string().parse().unwrap();
+        assert!(parsed.matches(&token));
+        assert!(!ResumeToken::generate(token.instance, token.player).matches(&token));
+
+        assert_eq!("".parse::<ResumeToken>(), Err(ParseResumeTokenError));
+        assert_eq!(
+            "z".repeat(56).parse::<ResumeToken>(),
+            Err(ParseResumeTokenError)
+        );
+    }
+
+    #[test]
+    fn coalesce_moves() {
+        let mut missed = MissedEvents::default();
+        missed.push(PlayerControl::PlayerMoved(1, Bytes::from_static(b"a")), 8);
+        missed.push(PlayerControl::PlayerJoined(2), 8);
+        missed.push(PlayerControl::PlayerMoved(2, Bytes::from_static(b"b")), 8);
+        missed.push(PlayerControl::PlayerMoved(1, Bytes::from_static(b"c")), 8);
+        missed.push(PlayerControl::PlayerLeft(2), 8);
+        assert!(!missed.overflowed());
+
+        let replay = missed
+            .into_replay()
+            .into_iter()
+            .map(|control| match control {
+                PlayerControl::PlayerJoined(id) => format!("joined {}", id),
+                PlayerControl::PlayerLeft(id) => format!("left {}", id),
+                PlayerControl::PlayerMoved(id, moved) => format!("moved {} {:?}", id, moved),
+                PlayerControl::NewChatMessage(_) => "chat".to_string(),
+                PlayerControl::Replaced => "replaced".to_string(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(replay, vec!["joined 2", "left 2", "moved 1 b\"c\""]);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut missed = MissedEvents::default();
+        for id in 0..3 {
+            missed.push(PlayerControl::PlayerJoined(id), 2);
+        }
+        assert!(missed.overflowed());
+    }
+}
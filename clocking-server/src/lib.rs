@@ -5,10 +5,11 @@
 //! use suteravr_lib::Foo;
 //! ```
 //!
-use std::net::SocketAddr;
+use std::{env, time::Duration};
 
 use errors::ClockingServerError;
-use log::{error, info};
+use log::{error, info, warn};
+use suteravr_lib::clocking::join_token::{JoinTokenIssuer, JoinTokenVerifier};
 use tokio::{
     sync::{mpsc, oneshot},
     task,
@@ -16,22 +17,30 @@ use tokio::{
 use tokio_rustls::rustls::ServerConfig;
 
 use crate::{
-    instance::{
-        manager::{launch_instance_manager, InstancesControl},
-        InstanceControl,
-    },
+    config::{ClockingServerConfig, CommandLine, TlsConfig},
+    server::{ClockingServerBuilder, ClockingServerHandle},
     shutdown::ShutdownReason,
     signal::listen_signal,
-    tcp::{certs::SingleCerts, tcp_server, TcpServerSignal},
+    tcp::certs::SingleCerts,
 };
 
+pub mod config;
 mod consts;
 pub mod errors;
 pub mod instance;
+pub mod server;
 mod shutdown;
 mod signal;
 mod tcp;
 
+/// 開発モードで表示する参加トークンの有効期間です。
+const DEVELOPMENT_JOIN_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// 設定ファイル、環境変数、コマンドライン引数から設定を読み込んでサーバーを起動し、シグナルを受け取るまで動かし続けます。
+///
+/// SIGHUPを受け取ると、接続を切らずに証明書を読み込み直します。
+///
+/// プロセス内に組み込む場合は、[`ClockingServerBuilder`]を使ってください。
 pub async fn clocking_server() -> Result<(), ClockingServerError> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
@@ -49,88 +58,128 @@ pub async fn clocking_server() -> Result<(), ClockingServerError> {
         consts::SuteraEnv::Production => info!("Running in Production mode..."),
     }
 
+    let config =
+        ClockingServerConfig::resolve(CommandLine::parse(env::args().skip(1))?).map_err(|e| {
+            error!("Failed to load the configuration!: {}", e);
+            e
+        })?;
+
     info!("");
     info!("Loading Certifications...");
-    let single_certs = SingleCerts::new().map_err(|e| {
+    let single_certs = match *consts::ENV {
+        consts::SuteraEnv::Development => SingleCerts::load_or_generate(&config.tls),
+        consts::SuteraEnv::Production => SingleCerts::load(&config.tls),
+    }
+    .map_err(|e| {
         error!("Failed to load certifications!: {}", e);
-        error!("Ensure that ./server.crt and ./server.key exists.");
-        info!("Hint: you can generate your own by certgen.sh");
+        error!(
+            "Ensure that {:?} and {:?} exists.",
+            config.tls.cert, config.tls.key
+        );
+        info!("Hint: you can generate your own by certgen.sh, or run with ENV=development to generate one automatically.");
         e
     })?;
 
+    log_fingerprints(&single_certs);
+
     let cfg: ServerConfig = single_certs.gen_server_config()?;
 
     info!("");
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], *consts::PORT));
-
-    let (tcp_tx, tcp_rx) = mpsc::channel::<TcpServerSignal>(32);
-    let (instances_tx, instances_rx) = mpsc::channel::<InstancesControl>(32);
-    let (shutdown_tx, shutdown) = oneshot::channel::<ShutdownReason>();
+    let mut builder = ClockingServerBuilder::from_config(cfg, &config)?;
+    if config.auth.join_token_public_key.is_none() {
+        match *consts::ENV {
+            consts::SuteraEnv::Development => {
+                builder = builder.with_join_tokens(development_join_tokens(&config)?)
+            }
+            consts::SuteraEnv::Production => {
+                warn!("No public key for join tokens is configured. All logins will be refused.")
+            }
+        }
+    }
 
-    let server = task::Builder::new()
-        .name("TCP server")
-        .spawn(tcp_server(cfg, addr, tcp_rx, instances_tx.clone()))
-        .map_err(ClockingServerError::SpawnError)?;
+    let server = builder.start().await?;
 
+    let (shutdown_tx, mut shutdown) = oneshot::channel::<ShutdownReason>();
+    let (reload_tx, mut reload) = mpsc::channel::<()>(1);
     let signal = task::Builder::new()
         .name("Signal listener")
-        .spawn(listen_signal(shutdown_tx))
-        .map_err(ClockingServerError::SpawnError)?;
-
-    let instance_manager = task::Builder::new()
-        .name("Instance manager")
-        .spawn(launch_instance_manager(instances_rx))
+        .spawn(listen_signal(shutdown_tx, reload_tx))
         .map_err(ClockingServerError::SpawnError)?;
 
-    // Pre-run ------
-
-    let (instance_1_tx, instance_1_rx) =
-        oneshot::channel::<Option<mpsc::Sender<InstanceControl>>>();
-    instances_tx
-        .send(InstancesControl::SpawnNew {
-            id: 0x01,
-            world: 0x01,
-            reply: instance_1_tx,
-        })
-        .await
-        .unwrap();
-    let _instance_1_control = instance_1_rx.await.unwrap();
-
     // Shutdown -----
 
-    let reason = match shutdown.await {
-        Ok(reason) => {
-            info!("Doing graceful shutdown: {:?}", reason);
-            reason
-        }
-        Err(e) => {
-            error!("Failed to receive shutdown signal: {}", e);
-            ShutdownReason::SignalChannelClosed
+    let reason = loop {
+        tokio::select! {
+            reason = &mut shutdown => match reason {
+                Ok(reason) => break reason,
+                Err(e) => {
+                    error!("Failed to receive shutdown signal: {}", e);
+                    break ShutdownReason::SignalChannelClosed;
+                }
+            },
+            Some(()) = reload.recv() => reload_certs(&server, &config.tls).await,
         }
     };
 
-    tcp_tx
-        .send(TcpServerSignal::Shutdown(reason))
-        .await
-        .map_err(|e| {
-            error!("Failed to send shutdown signal to TCP server");
-            ClockingServerError::CannotSendShutdown(e.into())
-        })?;
-
-    instances_tx
-        .send(InstancesControl::Shutdown(reason))
-        .await
-        .map_err(|e| {
-            error!("Failed to send shutdown signal to Instances manager");
-            ClockingServerError::CannotSendShutdown(e.into())
-        })?;
-
-    server.await??;
-    instance_manager.await??;
+    server.shutdown_with(reason).await?;
     signal.await??;
 
     info!("Shutdown completed successfully. Bye!");
 
     Ok(())
 }
+
+fn log_fingerprints(certs: &SingleCerts) {
+    info!(
+        "Certification fingerprint (SHA-256): {}",
+        certs.fingerprint()
+    );
+    match certs.spki_fingerprint() {
+        Ok(fingerprint) => info!("Public key fingerprint (SHA-256): {}", fingerprint),
+        Err(e) => warn!(
+            "Failed to read the public key of the certification: {:?}",
+            e
+        ),
+    }
+}
+
+/// 開発用に一時的な鍵を作り、各インスタンスに参加するためのトークンを表示します。
+fn development_join_tokens(
+    config: &ClockingServerConfig,
+) -> Result<JoinTokenVerifier, ClockingServerError> {
+    let issuer =
+        JoinTokenIssuer::generate_pkcs8().and_then(|pkcs8| JoinTokenIssuer::from_pkcs8(&pkcs8))?;
+    warn!("Using a temporary key for join tokens. Do not use this in production!");
+    for instance in &config.instances {
+        info!(
+            "Join token for instance {:?}: {}",
+            instance.id,
+            issuer.issue_for("developer", instance.id, DEVELOPMENT_JOIN_TOKEN_TTL)
+        );
+    }
+    Ok(JoinTokenVerifier::new(issuer.public_key()))
+}
+
+/// 証明書を読み込み直して、新しい接続から使います。読み込めなかった場合は、今の証明書を使い続けます。
+async fn reload_certs(server: &ClockingServerHandle, tls: &TlsConfig) {
+    info!("Reloading certifications...");
+    let load = || {
+        let certs = SingleCerts::load(tls)?;
+        log_fingerprints(&certs);
+        Ok::<_, ClockingServerError>(certs.gen_server_config()?)
+    };
+    let cfg = match load() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!(
+                "Failed to reload certifications, keeping the current ones: {}",
+                e
+            );
+            return;
+        }
+    };
+    if let Err(e) = server.reload_tls(cfg).await {
+        error!("Failed to apply the reloaded certifications: {}", e);
+    }
+}
@@ -0,0 +1,568 @@
+//! Clocking-serverをプロセス内に組み込んで起動するためのビルダーです。
+//!
+//! ```no_run
+//! # async fn run(cfg: tokio_rustls::rustls::ServerConfig) -> Result<(), clocking_server::errors::ClockingServerError> {
+//! use clocking_server::server::ClockingServerBuilder;
+//!
+//! let server = ClockingServerBuilder::new(cfg)
+//!     .with_addr(([127, 0, 0, 1], 0).into())
+//!     .with_instance(0x01, 0x01)
+//!     .start()
+//!     .await?;
+//! println!("Listening on {}", server.local_addr());
+//! server.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{net::SocketAddr, sync::Arc};
+
+use log::{error, info, warn};
+use suteravr_lib::{
+    clocking::{join_token::JoinTokenVerifier, ConnectionLimits},
+    messaging::id::{InstanceId, WorldId},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+    task::{self, JoinHandle},
+};
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::{
+    config::{ChannelCapacities, ClockingServerConfig, HealthcheckConfig, SessionConfig},
+    errors::{ClockingServerError, ConfigError, TcpServerError},
+    instance::manager::{launch_instance_manager, InstancesControl},
+    shutdown::ShutdownReason,
+    tcp::{tcp_server, ConnectionOptions, TcpServerSignal},
+};
+
+/// 待ち受ける場所です。
+enum Listen {
+    Addr(SocketAddr),
+    Listener(TcpListener),
+}
+
+pub struct ClockingServerBuilder {
+    config: ServerConfig,
+    listen: Vec<Listen>,
+    limits: ConnectionLimits,
+    healthcheck: HealthcheckConfig,
+    session: SessionConfig,
+    channels: ChannelCapacities,
+    join_tokens: Option<JoinTokenVerifier>,
+    instances: Vec<(InstanceId, WorldId)>,
+}
+
+impl ClockingServerBuilder {
+    /// 待ち受ける場所を指定しなければ`127.0.0.1:3501`で待ち受け、インスタンスは1つも起動しません。
+    ///
+    /// [`ClockingServerBuilder::with_join_tokens`]を指定しなければ、ログインは全て拒否します。
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            config,
+            listen: Vec::new(),
+            limits: ConnectionLimits::default(),
+            healthcheck: HealthcheckConfig::default(),
+            session: SessionConfig::default(),
+            channels: ChannelCapacities::default(),
+            join_tokens: None,
+            instances: Vec::new(),
+        }
+    }
+
+    /// 設定ファイルの内容に従って組み立てます。証明書は`server_config`として読み込み済みのものを使います。
+    pub fn from_config(
+        server_config: ServerConfig,
+        config: &ClockingServerConfig,
+    ) -> Result<Self, ConfigError> {
+        let mut builder = config
+            .listen
+            .iter()
+            .fold(Self::new(server_config), |builder, addr| {
+                builder.with_addr(*addr)
+            })
+            .with_healthcheck(config.healthcheck)
+            .with_session(config.session)
+            .with_channels(config.channels);
+        if let Some(join_tokens) = config.auth.join_token_verifier()? {
+            builder = builder.with_join_tokens(join_tokens);
+        }
+        Ok(config.instances.iter().fold(builder, |builder, instance| {
+            builder.with_instance(instance.id, instance.world)
+        }))
+    }
+
+    /// `addr`で待ち受けます。ポートに`0`を指定すると、空いているポートが割り当てられます。
+    ///
+    /// 複数回呼ぶと、それぞれのアドレスで待ち受けます。
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.listen.push(Listen::Addr(addr));
+        self
+    }
+
+    /// 既にbindされている`listener`で待ち受けます。
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listen.push(Listen::Listener(listener));
+        self
+    }
+
+    pub fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_healthcheck(mut self, healthcheck: HealthcheckConfig) -> Self {
+        self.healthcheck = healthcheck;
+        self
+    }
+
+    pub fn with_session(mut self, session: SessionConfig) -> Self {
+        self.session = session;
+        self
+    }
+
+    pub fn with_channels(mut self, channels: ChannelCapacities) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    /// ログインの際に、参加トークンを`verifier`で検証します。
+    pub fn with_join_tokens(mut self, verifier: JoinTokenVerifier) -> Self {
+        self.join_tokens = Some(verifier);
+        self
+    }
+
+    /// 起動時に`world`のインスタンスを`id`で立ち上げます。
+    pub fn with_instance(mut self, id: InstanceId, world: WorldId) -> Self {
+        self.instances.push((id, world));
+        self
+    }
+
+    /// サーバーを起動します。
+    ///
+    /// 返ってきた時点で接続を受け付けられる状態になっており、指定したインスタンスも立ち上がっています。
+    pub async fn start(mut self) -> Result<ClockingServerHandle, ClockingServerError> {
+        if self.listen.is_empty() {
+            self.listen
+                .push(Listen::Addr(SocketAddr::from(([127, 0, 0, 1], 3501))));
+        }
+        let mut listeners = Vec::with_capacity(self.listen.len());
+        for listen in self.listen {
+            listeners.push(match listen {
+                Listen::Addr(addr) => TcpListener::bind(&addr)
+                    .await
+                    .map_err(TcpServerError::ListenerBindError)?,
+                Listen::Listener(listener) => listener,
+            });
+        }
+        let local_addrs = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (tcp_tx, tcp_rx) = mpsc::channel::<TcpServerSignal>(self.channels.server);
+        let (instances_tx, instances_rx) = mpsc::channel::<InstancesControl>(self.channels.server);
+        let options = ConnectionOptions {
+            limits: self.limits,
+            healthcheck: self.healthcheck,
+            channel_capacity: self.channels.connection,
+            join_tokens: self.join_tokens,
+        };
+
+        let server = task::Builder::new()
+            .name("TCP server")
+            .spawn(tcp_server(
+                self.config,
+                listeners,
+                options,
+                tcp_rx,
+                instances_tx.clone(),
+            ))
+            .map_err(ClockingServerError::SpawnError)?;
+
+        let instance_manager = task::Builder::new()
+            .name("Instance manager")
+            .spawn(launch_instance_manager(
+                instances_rx,
+                self.channels.instance,
+                self.session,
+            ))
+            .map_err(ClockingServerError::SpawnError)?;
+
+        let handle = ClockingServerHandle {
+            local_addrs,
+            tcp_tx,
+            instances_tx,
+            server,
+            instance_manager,
+        };
+
+        for (id, world) in self.instances {
+            let (reply, reply_rx) = oneshot::channel();
+            handle
+                .instances_tx
+                .send(InstancesControl::SpawnNew { id, world, reply })
+                .await
+                .map_err(TcpServerError::from)?;
+            if reply_rx
+                .await
+                .map_err(|_| ClockingServerError::CannotReceiveReply)?
+                .is_none()
+            {
+                warn!("Instance {:?} is already running, skipping...", id);
+            }
+        }
+
+        Ok(handle)
+    }
+}
+
+/// 起動したサーバーを操作します。
+///
+/// 捨てただけではサーバーは止まらないので、[`ClockingServerHandle::shutdown`]を呼んでください。
+pub struct ClockingServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    tcp_tx: mpsc::Sender<TcpServerSignal>,
+    instances_tx: mpsc::Sender<InstancesControl>,
+    server: JoinHandle<Result<(), TcpServerError>>,
+    instance_manager: JoinHandle<Result<(), ClockingServerError>>,
+}
+
+impl ClockingServerHandle {
+    /// 実際に待ち受けているアドレスです。複数ある場合は、最初に指定したものを返します。
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// 実際に待ち受けている全てのアドレスです。
+    #[inline]
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// 以降の新しい接続で`config`の証明書を使います。既存の接続はそのまま続きます。
+    ///
+    /// 返ってきた時点で、新しい接続には`config`が使われます。
+    pub async fn reload_tls(&self, config: ServerConfig) -> Result<(), ClockingServerError> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.tcp_tx
+            .send(TcpServerSignal::ReloadTls(Arc::new(config), reply))
+            .await
+            .map_err(|_| TcpServerError::ThreadDead)?;
+        reply_rx.await.map_err(|_| TcpServerError::ThreadDead)?;
+        Ok(())
+    }
+
+    /// 全ての接続とインスタンスを閉じて、サーバーを止めます。
+    pub async fn shutdown(self) -> Result<(), ClockingServerError> {
+        self.shutdown_with(ShutdownReason::Requested).await
+    }
+
+    pub(crate) async fn shutdown_with(
+        self,
+        reason: ShutdownReason,
+    ) -> Result<(), ClockingServerError> {
+        info!("Doing graceful shutdown: {:?}", reason);
+
+        self.tcp_tx
+            .send(TcpServerSignal::Shutdown(reason))
+            .await
+            .map_err(|e| {
+                error!("Failed to send shutdown signal to TCP server");
+                ClockingServerError::CannotSendShutdown(e.into())
+            })?;
+
+        self.instances_tx
+            .send(InstancesControl::Shutdown(reason))
+            .await
+            .map_err(|e| {
+                error!("Failed to send shutdown signal to Instances manager");
+                ClockingServerError::CannotSendShutdown(e.into())
+            })?;
+
+        self.server.await??;
+        self.instance_manager.await??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pretty_assertions::{assert_eq, assert_ne};
+    use suteravr_lib::{
+        clocking::{
+            client::{
+                reconnect::ReconnectPolicy, ClientEvent, ClientEvents, ClientOptions,
+                ClockingClient,
+            },
+            join_token::JoinTokenIssuer,
+        },
+        messaging::id::PlayerId,
+        util::logger::EnvLogger,
+    };
+    use tokio::{io, net::TcpStream, sync::watch, time};
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+            ClientConfig, RootCertStore,
+        },
+        TlsConnector,
+    };
+
+    use super::*;
+
+    async fn handshake(addr: SocketAddr, client_config: ClientConfig) -> std::io::Result<()> {
+        let stream = TcpStream::connect(addr).await?;
+        TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await?;
+        Ok(())
+    }
+
+    fn self_signed() -> (ServerConfig, ClientConfig) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = CertificateDer::from(cert.serialize_der().unwrap());
+        let key_der =
+            PrivateKeyDer::from(PrivatePkcs8KeyDer::from(cert.serialize_private_key_der()));
+
+        let server = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn start_and_shutdown() {
+        let (server_config, client_config) = self_signed();
+        let server = ClockingServerBuilder::new(server_config)
+            .with_addr(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .with_instance(0x01, 0x01)
+            .start()
+            .await
+            .unwrap();
+        assert_ne!(server.local_addr().port(), 0);
+
+        let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn start_with_listener() {
+        let (server_config, _) = self_signed();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = ClockingServerBuilder::new(server_config)
+            .with_listener(listener)
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(server.local_addr(), addr);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn start_from_config() {
+        let (server_config, client_config) = self_signed();
+        let config = ClockingServerConfig::from_toml(
+            "listen = [\"127.0.0.1:0\", \"127.0.0.1:0\"]\n\n[channels]\nconnection = 4\n",
+        )
+        .unwrap();
+        let server = ClockingServerBuilder::from_config(server_config, &config)
+            .unwrap()
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(server.local_addrs().len(), 2);
+
+        // どちらのアドレスでも受け付ける
+        let client_config = Arc::new(client_config);
+        for addr in server.local_addrs() {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            TlsConnector::from(client_config.clone())
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+        }
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_tls() {
+        let (old_server_config, old_client_config) = self_signed();
+        let (new_server_config, new_client_config) = self_signed();
+        let server = ClockingServerBuilder::new(old_server_config)
+            .with_addr(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .start()
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        handshake(addr, old_client_config.clone()).await.unwrap();
+        assert!(handshake(addr, new_client_config.clone()).await.is_err());
+
+        server.reload_tls(new_server_config).await.unwrap();
+        handshake(addr, new_client_config).await.unwrap();
+        assert!(handshake(addr, old_client_config).await.is_err());
+
+        server.shutdown().await.unwrap();
+    }
+
+    /// 受け付けた接続を`target`へ中継します。`cut`に送ると、その時点の接続を全て切ります。
+    async fn relay(target: SocketAddr) -> (SocketAddr, watch::Sender<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (cut, cut_rx) = watch::channel(());
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let mut server = TcpStream::connect(target).await.unwrap();
+                let mut cut = cut_rx.clone();
+                cut.borrow_and_update();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = io::copy_bidirectional(&mut client, &mut server) => {},
+                        _ = cut.changed() => {},
+                    }
+                });
+            }
+        });
+        (addr, cut)
+    }
+
+    /// `matches`に当てはまるイベントが届くまで、他のイベントを読み飛ばします。
+    async fn wait_for<T>(
+        events: &mut ClientEvents,
+        mut matches: impl FnMut(ClientEvent) -> Option<T>,
+    ) -> T {
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                let event = events.recv().await.expect("the client has finished");
+                if let Some(found) = matches(event) {
+                    return found;
+                }
+            }
+        })
+        .await
+        .expect("the expected event has not arrived")
+    }
+
+    async fn connected(events: &mut ClientEvents) -> bool {
+        wait_for(events, |event| match event {
+            ClientEvent::Connected { resumed } => Some(resumed),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn chat_from(events: &mut ClientEvents, message: &str) -> PlayerId {
+        wait_for(events, |event| match event {
+            ClientEvent::ChatMessage {
+                sender,
+                message: received,
+            } if received == message => Some(sender),
+            _ => None,
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn client_end_to_end() {
+        let (server_config, client_config) = self_signed();
+        let issuer =
+            JoinTokenIssuer::from_pkcs8(&JoinTokenIssuer::generate_pkcs8().unwrap()).unwrap();
+        let server = ClockingServerBuilder::new(server_config)
+            .with_addr(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .with_join_tokens(JoinTokenVerifier::new(issuer.public_key()))
+            .with_instance(0x01, 0x01)
+            .start()
+            .await
+            .unwrap();
+        let (relay_addr, cut) = relay(server.local_addr()).await;
+
+        let options = ClientOptions::default().with_reconnect(ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            jitter: 0.0,
+            ..Default::default()
+        });
+        let logger = EnvLogger {
+            target: "client_end_to_end".to_string(),
+        };
+        let connect = |addr: SocketAddr| {
+            ClockingClient::connect(
+                client_config.clone(),
+                "localhost",
+                addr.to_string(),
+                options,
+                logger.clone(),
+            )
+        };
+        // aliceだけ中継を通し、途中で接続を切る
+        let (alice, mut alice_events) = connect(relay_addr);
+        let (bob, mut bob_events) = connect(server.local_addr());
+
+        assert!(!connected(&mut alice_events).await);
+        let ttl = Duration::from_secs(60);
+        assert_eq!(
+            alice
+                .login(0x01, issuer.issue_for("alice", 0x01, ttl))
+                .await
+                .unwrap(),
+            Vec::<PlayerId>::new()
+        );
+        let alice_id = alice.player_id().unwrap();
+        assert!(!connected(&mut bob_events).await);
+        assert_eq!(
+            bob.login(0x01, issuer.issue_for("bob", 0x01, ttl))
+                .await
+                .unwrap(),
+            vec![alice_id]
+        );
+        let bob_id = bob.player_id().unwrap();
+
+        // 送った本人を含め、インスタンスの全員に届く
+        alice.send_chat("hello".to_string()).await.unwrap();
+        assert_eq!(chat_from(&mut bob_events, "hello").await, alice_id);
+        assert_eq!(chat_from(&mut alice_events, "hello").await, alice_id);
+
+        cut.send(()).unwrap();
+        wait_for(&mut alice_events, |event| match event {
+            ClientEvent::Lost { .. } => Some(()),
+            _ => None,
+        })
+        .await;
+        assert!(connected(&mut alice_events).await);
+        assert_eq!(alice.player_id(), Some(alice_id));
+
+        // 引き継いだセッションで、やり取りを続けられる
+        bob.send_chat("welcome back".to_string()).await.unwrap();
+        assert_eq!(chat_from(&mut alice_events, "welcome back").await, bob_id);
+        alice.send_chat("thanks".to_string()).await.unwrap();
+        assert_eq!(chat_from(&mut bob_events, "thanks").await, alice_id);
+
+        alice.shutdown().await.unwrap();
+        bob.shutdown().await.unwrap();
+        // 終了した後は、残ったイベントを読み切ると`None`になる
+        time::timeout(Duration::from_secs(10), async {
+            while alice_events.recv().await.is_some() {}
+        })
+        .await
+        .unwrap();
+        server.shutdown().await.unwrap();
+    }
+}
@@ -3,4 +3,8 @@ pub enum ShutdownReason {
     Sigint,
     Sigterm,
     SignalChannelClosed,
+    /// [`ClockingServerHandle::shutdown`][crate::server::ClockingServerHandle::shutdown]で止められた
+    Requested,
+    /// 同じプレイヤーのセッションが、別の接続に引き継がれた
+    Replaced,
 }
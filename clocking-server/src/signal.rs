@@ -1,26 +1,40 @@
-use log::info;
+use log::{info, warn};
 use tokio::signal::unix::signal;
 use tokio::signal::unix::SignalKind;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 
 use crate::shutdown::ShutdownReason;
 
+/// SIGTERM/SIGINTを受け取ると`shutdown`に送って終わります。
+///
+/// SIGHUPを受け取るたびに、証明書を読み込み直すよう`reload`に送ります。
 pub async fn listen_signal(
     shutdown: oneshot::Sender<ShutdownReason>,
+    reload: mpsc::Sender<()>,
 ) -> Result<(), std::io::Error> {
     let mut sigterm = signal(SignalKind::terminate())?;
     let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
 
-    tokio::select! {
-        _ = sigterm.recv() => {
-            info!("SIGTERM received");
-            shutdown.send(ShutdownReason::Sigterm).unwrap();
+    let reason = loop {
+        tokio::select! {
+            _ = sigterm.recv() => {
+                info!("SIGTERM received");
+                break ShutdownReason::Sigterm;
+            }
+            _ = sigint.recv() => {
+                info!("SIGINT received");
+                break ShutdownReason::Sigint;
+            }
+            _ = sighup.recv() => {
+                info!("SIGHUP received");
+                if reload.try_send(()).is_err() {
+                    warn!("Reloading is already in progress, skipping...");
+                }
+            }
         }
-        _ = sigint.recv() => {
-            info!("SIGINT received");
-            shutdown.send(ShutdownReason::Sigint).unwrap();
-        }
-    }
+    };
+    shutdown.send(reason).unwrap();
 
     info!("Shutting down... (signal)");
     Ok(())
@@ -1,23 +1,94 @@
-use log::info;
-use rustls_pemfile::{certs, pkcs8_private_keys};
+use log::{info, warn};
+use rustls_pemfile::{certs, private_key};
 use std::{
     env,
-    fs::File,
-    io::{self, BufReader, Error, ErrorKind},
-    path::PathBuf,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
 };
+use suteravr_lib::util::fingerprint::CertFingerprint;
 use tokio_rustls::rustls::{
     self,
+    crypto::ring::{default_provider, sign::any_supported_type},
     pki_types::{CertificateDer, PrivateKeyDer},
     ServerConfig,
 };
 
+use crate::{
+    config::TlsConfig,
+    errors::{CertsError, CertsErrorKind, CertsSource},
+};
+
+#[derive(Debug)]
 pub struct SingleCerts {
     pub certs: Vec<CertificateDer<'static>>,
     pub keys: PrivateKeyDer<'static>,
 }
 
-fn load_from_env() -> io::Result<Option<SingleCerts>> {
+/// 秘密鍵で署名し、証明書の公開鍵で検証できるかを確かめるためのメッセージです。
+const KEY_PAIR_CHALLENGE: &[u8] = b"SuteraVR clocking-server key pair check";
+
+fn read_certs(rd: &mut dyn BufRead) -> Result<Vec<CertificateDer<'static>>, CertsErrorKind> {
+    let certs = certs(rd)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(CertsErrorKind::Io)?;
+    if certs.is_empty() {
+        return Err(CertsErrorKind::NoCertificate);
+    }
+    Ok(certs)
+}
+
+/// PKCS#1(RSA)、PKCS#8、SEC1(EC)のいずれかの形式の秘密鍵を読み込みます。
+fn read_key(rd: &mut dyn BufRead) -> Result<PrivateKeyDer<'static>, CertsErrorKind> {
+    private_key(rd)
+        .map_err(CertsErrorKind::Io)?
+        .ok_or(CertsErrorKind::NoPrivateKey)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, CertsErrorKind> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(CertsErrorKind::Io)
+}
+
+/// 証明書チェーンの先頭の証明書が、秘密鍵と対になっているかを確かめます。
+fn verify_key_pair(
+    certs: &[CertificateDer<'_>],
+    key: &PrivateKeyDer<'_>,
+) -> Result<(), CertsErrorKind> {
+    let end_entity = certs.first().ok_or(CertsErrorKind::NoCertificate)?;
+    let end_entity =
+        webpki::EndEntityCert::try_from(end_entity).map_err(CertsErrorKind::InvalidCertificate)?;
+
+    let algorithms = default_provider().signature_verification_algorithms;
+    let signer = any_supported_type(key)
+        .map_err(CertsErrorKind::UnsupportedKey)?
+        .choose_scheme(&algorithms.supported_schemes())
+        .ok_or(CertsErrorKind::UnsupportedKey(rustls::Error::General(
+            "No signature scheme is available for the private key".to_string(),
+        )))?;
+    let signature = signer
+        .sign(KEY_PAIR_CHALLENGE)
+        .map_err(CertsErrorKind::UnsupportedKey)?;
+
+    let verified = algorithms
+        .mapping
+        .iter()
+        .filter(|(scheme, _)| *scheme == signer.scheme())
+        .flat_map(|(_, algorithms)| algorithms.iter())
+        .any(|algorithm| {
+            end_entity
+                .verify_signature(*algorithm, KEY_PAIR_CHALLENGE, &signature)
+                .is_ok()
+        });
+    if !verified {
+        return Err(CertsErrorKind::KeyMismatch);
+    }
+    Ok(())
+}
+
+fn load_from_env() -> Result<Option<SingleCerts>, CertsError> {
     let Ok(certs_env) = env::var("SINGLECERTS_CERT_PEM") else {
         return Ok(None);
     };
@@ -25,71 +96,117 @@ fn load_from_env() -> io::Result<Option<SingleCerts>> {
         return Ok(None);
     };
 
-    let keys = pkcs8_private_keys(&mut keys_env.as_bytes())
-        .next()
-        .ok_or(io::ErrorKind::InvalidInput)?
-        .map(Into::into)?;
-    Ok(Some(SingleCerts {
-        certs: certs(&mut certs_env.as_bytes())
-            .collect::<io::Result<Vec<CertificateDer<'static>>>>()?,
-        keys,
-    }))
+    let load = || {
+        let certs = read_certs(&mut certs_env.as_bytes())?;
+        let keys = read_key(&mut keys_env.as_bytes())?;
+        verify_key_pair(&certs, &keys)?;
+        Ok(SingleCerts { certs, keys })
+    };
+    load()
+        .map(Some)
+        .map_err(|kind| CertsError::new(CertsSource::Env, kind))
 }
 
-fn load_from_env_path() -> io::Result<Option<(SingleCerts, String, String)>> {
-    let Ok(certs_path) = env::var("SINGLECERTS_CERT_PATH") else {
-        return Ok(None);
-    };
-    let Ok(keys_path) = env::var("SINGLECERTS_KEY_PATH") else {
-        return Ok(None);
-    };
-    let certs_file = &PathBuf::from(&certs_path);
-    let keys_file = &PathBuf::from(&keys_path);
-    Ok(Some((
-        SingleCerts {
-            certs: certs(&mut BufReader::new(File::open(certs_file)?))
-                .collect::<io::Result<Vec<CertificateDer<'static>>>>()?,
-            keys: pkcs8_private_keys(&mut BufReader::new(File::open(keys_file)?))
-                .next()
-                .ok_or(io::ErrorKind::InvalidInput)?
-                .map(Into::into)?,
-        },
-        certs_path,
-        keys_path,
-    )))
+fn load_from_path(tls: &TlsConfig) -> Result<SingleCerts, CertsError> {
+    let certs = open(&tls.cert)
+        .and_then(|mut rd| read_certs(&mut rd))
+        .map_err(|kind| CertsError::new(CertsSource::CertFile(tls.cert.clone()), kind))?;
+    let keys = open(&tls.key)
+        .and_then(|mut rd| read_key(&mut rd))
+        .map_err(|kind| CertsError::new(CertsSource::KeyFile(tls.key.clone()), kind))?;
+    verify_key_pair(&certs, &keys).map_err(|kind| {
+        CertsError::new(
+            CertsSource::Files {
+                cert: tls.cert.clone(),
+                key: tls.key.clone(),
+            },
+            kind,
+        )
+    })?;
+    Ok(SingleCerts { certs, keys })
 }
 
-fn load_from_file() -> io::Result<Option<SingleCerts>> {
-    let certs_path = &PathBuf::from("./certs/server.crt");
-    let keys_path = &PathBuf::from("./certs/server.key");
-    Ok(Some(SingleCerts {
-        certs: certs(&mut BufReader::new(File::open(certs_path)?))
-            .collect::<io::Result<Vec<CertificateDer<'static>>>>()?,
-        keys: pkcs8_private_keys(&mut BufReader::new(File::open(keys_path)?))
-            .next()
-            .ok_or(io::ErrorKind::InvalidInput)?
-            .map(Into::into)?,
-    }))
+/// localhost向けの自己署名証明書を作り、`tls`の場所に保存します。
+fn generate_self_signed(tls: &TlsConfig) -> Result<SingleCerts, CertsErrorKind> {
+    let generated = rcgen::generate_simple_self_signed(
+        ["localhost", "127.0.0.1", "::1"].map(String::from).to_vec(),
+    )
+    .map_err(CertsErrorKind::Generate)?;
+    let cert_pem = generated
+        .serialize_pem()
+        .map_err(CertsErrorKind::Generate)?;
+    let key_pem = generated.serialize_private_key_pem();
+
+    for (path, pem, mode) in [(&tls.cert, &cert_pem, 0o644), (&tls.key, &key_pem, 0o600)] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(CertsErrorKind::Io)?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(path)
+            .and_then(|mut file| file.write_all(pem.as_bytes()))
+            .map_err(CertsErrorKind::Io)?;
+    }
+
+    Ok(SingleCerts {
+        certs: read_certs(&mut cert_pem.as_bytes())?,
+        keys: read_key(&mut key_pem.as_bytes())?,
+    })
 }
 
 impl SingleCerts {
-    pub fn new() -> io::Result<Self> {
+    /// 環境変数`SINGLECERTS_CERT_PEM`/`SINGLECERTS_KEY_PEM`があればそれを、なければ`tls`のファイルを読み込みます。
+    ///
+    /// 秘密鍵はPKCS#1(RSA)、PKCS#8、SEC1(EC)のPEMに対応しています。証明書と対になっていない場合はエラーになります。
+    pub fn load(tls: &TlsConfig) -> Result<Self, CertsError> {
         if let Some(certs) = load_from_env()? {
             info!("Certifications loaded from environment variables.");
             return Ok(certs);
         }
-        if let Some((certs, certs_path, keys_path)) = load_from_env_path()? {
-            info!("Certification loaded from {:?}", certs_path);
-            info!("Private key loaded from {:?}", keys_path);
-            return Ok(certs);
-        }
-        if let Some(certs) = load_from_file()? {
-            info!("Certification loaded from ./certs/server.crt");
-            info!("Private key loaded from ./certs/server.key");
+        let certs = load_from_path(tls)?;
+        info!("Certification loaded from {:?}", tls.cert);
+        info!("Private key loaded from {:?}", tls.key);
+        Ok(certs)
+    }
+    /// [`SingleCerts::load`]と同じですが、証明書も秘密鍵も見つからない場合は、
+    /// localhost向けの自己署名証明書を作って`tls`の場所に保存します。開発用です。
+    pub fn load_or_generate(tls: &TlsConfig) -> Result<Self, CertsError> {
+        if load_from_env()?.is_none() && !tls.cert.exists() && !tls.key.exists() {
+            let certs = generate_self_signed(tls).map_err(|kind| {
+                CertsError::new(
+                    CertsSource::Files {
+                        cert: tls.cert.clone(),
+                        key: tls.key.clone(),
+                    },
+                    kind,
+                )
+            })?;
+            warn!(
+                "Generated a self-signed certification for development: {:?}",
+                tls.cert
+            );
             return Ok(certs);
         }
-        Err(Error::from(ErrorKind::NotFound))
+        Self::load(tls)
+    }
+
+    /// 先頭の証明書のフィンガープリントです。クライアントはこれを固定して接続できます。
+    #[inline]
+    pub fn fingerprint(&self) -> CertFingerprint {
+        CertFingerprint::of(&self.certs[0])
+    }
+
+    /// 先頭の証明書の公開鍵(SPKI)のフィンガープリントです。クライアントは初めて接続したときにこれを記録します。
+    /// 証明書を更新しても、秘密鍵が同じなら変わりません。
+    pub fn spki_fingerprint(&self) -> Result<CertFingerprint, webpki::Error> {
+        let end_entity = webpki::EndEntityCert::try_from(&self.certs[0])?;
+        Ok(CertFingerprint::of(
+            end_entity.subject_public_key_info().as_ref(),
+        ))
     }
+
     pub fn gen_server_config(self) -> io::Result<ServerConfig> {
         rustls::ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
             .with_no_client_auth()
@@ -97,3 +214,71 @@ impl SingleCerts {
             .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    use super::*;
+
+    // testdata/にあるのはテスト専用の使い捨ての証明書と秘密鍵です。
+    fn testdata(cert: &str, key: &str) -> TlsConfig {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        TlsConfig {
+            cert: dir.join(cert),
+            key: dir.join(key),
+        }
+    }
+
+    #[rstest]
+    #[case::rsa_pkcs1("rsa.crt", "rsa-pkcs1.key")]
+    #[case::ec_sec1("ec.crt", "ec-sec1.key")]
+    #[case::ec_pkcs8("ec.crt", "ec-pkcs8.key")]
+    fn load_key_formats(#[case] cert: &str, #[case] key: &str) {
+        let certs = load_from_path(&testdata(cert, key)).unwrap();
+        certs.gen_server_config().unwrap();
+    }
+
+    #[test]
+    fn generate_self_signed_once() {
+        let dir = env::temp_dir().join(format!("clocking-server-certs-{}", std::process::id()));
+        let tls = TlsConfig {
+            cert: dir.join("certs/server.crt"),
+            key: dir.join("certs/server.key"),
+        };
+
+        let generated = SingleCerts::load_or_generate(&tls).unwrap();
+        // 2回目は保存したものを読み込む
+        let loaded = SingleCerts::load_or_generate(&tls).unwrap();
+        assert_eq!(generated.fingerprint(), loaded.fingerprint());
+        assert_eq!(
+            generated.spki_fingerprint().unwrap(),
+            loaded.spki_fingerprint().unwrap()
+        );
+        loaded.gen_server_config().unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reject_mismatched_key() {
+        let error = load_from_path(&testdata("rsa.crt", "ec-sec1.key")).unwrap_err();
+        assert!(matches!(error.kind, CertsErrorKind::KeyMismatch));
+        assert!(matches!(error.from, CertsSource::Files { .. }));
+    }
+
+    #[test]
+    fn errors_name_the_source() {
+        let error = load_from_path(&testdata("missing.crt", "ec-sec1.key")).unwrap_err();
+        assert!(matches!(error.kind, CertsErrorKind::Io(_)));
+        assert!(error.to_string().contains("missing.crt"), "{}", error);
+
+        // 証明書のファイルを秘密鍵として読ませる
+        let error = load_from_path(&testdata("ec.crt", "ec.crt")).unwrap_err();
+        assert!(matches!(error.kind, CertsErrorKind::NoPrivateKey));
+        assert!(
+            matches!(error.from, CertsSource::KeyFile(path) if path == testdata("", "ec.crt").key)
+        );
+    }
+}
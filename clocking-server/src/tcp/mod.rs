@@ -2,39 +2,49 @@ pub mod certs;
 pub mod requests;
 pub mod stream;
 
-use alkahest::deserialize;
+use bytes::Bytes;
 use chrono::Local;
+use futures::future::select_all;
 use log::error;
 use log::{info, warn};
 use std::sync::atomic::AtomicU64;
 use std::{io, net::SocketAddr, sync::Arc};
-use suteravr_lib::clocking::event_headers::EventTypes;
-use suteravr_lib::clocking::oneshot_headers::{
-    OneshotDirection, OneshotHeader, OneshotStep, OneshotTypes, ONESHOT_DIRECTION_MAP,
+use suteravr_lib::clocking::join_token::JoinTokenVerifier;
+use suteravr_lib::clocking::messages::{
+    EventMessage, HealthCheckPullOneshot, HealthCheckPushOneshot, LoginOneshot,
+    NegotiateCompressionOneshot, OneshotMessage, PlayerJoinedEvent, PlayerLeftEvent,
+    PubPlayerMoveEvent, PushPlayerMoveEvent, ReceiveChatMessageEvent, ResumeOneshot,
+    SendChatMessageOneshot,
 };
-use suteravr_lib::clocking::schemas::event::player_move::PubPlayerMove;
+use suteravr_lib::clocking::oneshot_headers::{OneshotDirection, OneshotStep};
 use suteravr_lib::clocking::schemas::event::update_player_being::{PlayerJoined, PlayerLeft};
 use suteravr_lib::clocking::schemas::oneshot::chat_entry::{
-    ChatEntry, SendChatMessageRequest, SendChatMessageResponse, SendableChatEntry,
+    ChatEntry, SendChatMessageResponse, SendableChatEntry,
 };
-use suteravr_lib::clocking::schemas::oneshot::login::{LoginRequest, LoginResponse};
+use suteravr_lib::clocking::schemas::oneshot::compression::CompressionResponse;
+use suteravr_lib::clocking::schemas::oneshot::error_details::{ErrorDetails, RetryHint};
+use suteravr_lib::clocking::schemas::oneshot::login::{LoginResponse, ResumeResponse};
 use suteravr_lib::clocking::sutera_header::SuteraHeader;
 use suteravr_lib::clocking::sutera_status::{SuteraStatus, SuteraStatusError};
+use suteravr_lib::clocking::ConnectionLimits;
 use suteravr_lib::messaging::id::PlayerId;
+use suteravr_lib::messaging::version::VersionCompatibility;
 use suteravr_lib::SCHEMA_VERSION;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time;
 
 use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     sync::{broadcast, mpsc::Receiver},
     task::JoinSet,
 };
 use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
 
+use crate::config::HealthcheckConfig;
 use crate::errors::TcpServerError;
 use crate::instance::manager::InstancesControl;
-use crate::instance::{InstanceControl, PlayerControl};
+use crate::instance::{session::ResumeToken, InstanceControl, PlayerControl};
 use crate::shutdown::ShutdownReason;
 use crate::tcp::requests::Request;
 use crate::tcp::stream::ClientMessageStream;
@@ -42,20 +52,34 @@ use crate::tcp::stream::ClientMessageStream;
 #[derive(Debug)]
 pub enum TcpServerSignal {
     Shutdown(ShutdownReason),
+    /// 以降の新しい接続に`ServerConfig`を使います。既存の接続はそのまま続きます。
+    ReloadTls(Arc<ServerConfig>, oneshot::Sender<()>),
+}
+
+/// 各接続に共通する設定です。
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub limits: ConnectionLimits,
+    pub healthcheck: HealthcheckConfig,
+    pub channel_capacity: usize,
+    /// 参加トークンを検証します。`None`の場合、ログインは全て拒否します。
+    pub join_tokens: Option<JoinTokenVerifier>,
 }
 
 pub async fn tcp_server(
     cfg: ServerConfig,
-    addr: SocketAddr,
+    listeners: Vec<TcpListener>,
+    options: ConnectionOptions,
     mut rx: Receiver<TcpServerSignal>,
     instances_tx: mpsc::Sender<InstancesControl>,
 ) -> Result<(), TcpServerError> {
-    let acceptor = &TlsAcceptor::from(Arc::new(cfg));
-    let listener = TcpListener::bind(&addr)
-        .await
-        .map_err(TcpServerError::ListenerBindError)?;
-
-    info!("Ready! Server running on {}", &addr);
+    let mut acceptor = TlsAcceptor::from(Arc::new(cfg));
+    for listener in &listeners {
+        let addr = listener
+            .local_addr()
+            .map_err(TcpServerError::ListenerBindError)?;
+        info!("Ready! Server running on {}", &addr);
+    }
 
     let mut connections = JoinSet::new();
     let (shutdown_tx, _) = broadcast::channel::<ShutdownReason>(1);
@@ -67,14 +91,19 @@ pub async fn tcp_server(
                     Some(TcpServerSignal::Shutdown(reason)) => {
                         break 'accepting reason;
                     }
+                    Some(TcpServerSignal::ReloadTls(cfg, reply)) => {
+                        acceptor = TlsAcceptor::from(cfg);
+                        info!("TLS configuration reloaded. New connections will use it.");
+                        let _ = reply.send(());
+                    }
                     None => {
                         warn!("Signal channel is closed.");
                         break 'accepting ShutdownReason::SignalChannelClosed;
                     }
                 }
             }
-            accepted = listener.accept() => {
-                connection_init(accepted, acceptor, &mut connections, shutdown_tx.subscribe(), instances_tx.clone()).await?;
+            (accepted, _, _) = select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))) => {
+                connection_init(accepted, &acceptor, options.clone(), &mut connections, shutdown_tx.subscribe(), instances_tx.clone()).await?;
             }
         }
     };
@@ -94,11 +123,43 @@ pub async fn tcp_server(
     Ok(())
 }
 
+/// インスタンスからの通知を、クライアントにイベントとして送ります。
+async fn forward_control(
+    message: &ClientMessageStream,
+    control: PlayerControl,
+) -> Result<(), TcpServerError> {
+    match control {
+        PlayerControl::NewChatMessage(entry) => {
+            message
+                .send_event_ok::<ReceiveChatMessageEvent>(SendableChatEntry::from(entry))
+                .await
+        }
+        PlayerControl::PlayerJoined(id) => {
+            message
+                .send_event_ok::<PlayerJoinedEvent>(PlayerJoined { joined_player: id })
+                .await
+        }
+        PlayerControl::PlayerLeft(id) => {
+            message
+                .send_event_ok::<PlayerLeftEvent>(PlayerLeft { left_player: id })
+                .await
+        }
+        PlayerControl::PlayerMoved(_, moved) => {
+            message
+                .send_encoded_event_ok::<PushPlayerMoveEvent>(moved)
+                .await
+        }
+        // 接続を閉じるのは受け取った側で行う
+        PlayerControl::Replaced => Ok(()),
+    }
+}
+
 async fn connection_init(
     accepted: io::Result<(TcpStream, SocketAddr)>,
     acceptor: &TlsAcceptor,
+    options: ConnectionOptions,
     join_set: &mut JoinSet<()>,
-    mut shutdown_rx: broadcast::Receiver<ShutdownReason>,
+    shutdown_rx: broadcast::Receiver<ShutdownReason>,
     instances_tx: mpsc::Sender<InstancesControl>,
 ) -> Result<(), TcpServerError> {
     let Ok((stream, peer_addr)) = accepted else {
@@ -116,19 +177,50 @@ async fn connection_init(
             .await
             .map_err(TcpServerError::AcceptError)?;
         info!("Connection from {} is established.", peer_addr);
+        serve_connection(stream, peer_addr, options, shutdown_rx, instances_tx).await
+    };
+
+    join_set
+        .build_task()
+        .name(format!("Acceptor {}", peer_addr).as_str())
+        .spawn(async move {
+            match fut.await {
+                Ok(_) => {
+                    info!("Connection from {} is closed.", peer_addr);
+                }
+                Err(e) => {
+                    warn!("Failed in Acceptor {} ({})", peer_addr, e);
+                }
+            }
+        })
+        .map_err(TcpServerError::SpawnError)?;
 
-        let mut login_status: Option<(PlayerId, mpsc::Sender<InstanceControl>)> = None;
-        let (control_tx, mut control) = mpsc::channel::<PlayerControl>(32);
+    Ok(())
+}
 
-        let mut healthcheck_missed_count = 0;
+/// TLSの接続が確立した後、接続が閉じるまでクライアントとやり取りします。
+async fn serve_connection<S: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static>(
+    stream: S,
+    peer_addr: SocketAddr,
+    options: ConnectionOptions,
+    mut shutdown_rx: broadcast::Receiver<ShutdownReason>,
+    instances_tx: mpsc::Sender<InstancesControl>,
+) -> Result<(), TcpServerError> {
+    let mut login_status: Option<(PlayerId, mpsc::Sender<InstanceControl>)> = None;
+    let (control_tx, mut control) = mpsc::channel::<PlayerControl>(options.channel_capacity);
+    let mut server_shutdown = false;
 
-        let (mut message, mut stream_handle) = ClientMessageStream::new(stream, peer_addr)?;
+    let (mut message, mut stream_handle) =
+        ClientMessageStream::new(stream, peer_addr, options.limits, options.channel_capacity)?;
+    // 途中で失敗しても、参加したインスタンスには接続が切れたことを必ず伝える
+    let result = async {
+        let mut healthcheck_missed_count = 0;
         let message_id_dispatcher = AtomicU64::new(0);
         loop {
             tokio::select! {
-                _ = time::sleep(std::time::Duration::from_secs(30)) => {
+                _ = time::sleep(options.healthcheck.interval()) => {
                     healthcheck_missed_count += 1;
-                    if healthcheck_missed_count > 6 {
+                    if healthcheck_missed_count > options.healthcheck.max_missed {
                         warn!("{} Healthcheck missed {} times, maybe talking to a dead client, closing the connection...", peer_addr, healthcheck_missed_count);
                         message.shutdown(ShutdownReason::SignalChannelClosed).await?;
                         stream_handle.await??;
@@ -142,47 +234,37 @@ async fn connection_init(
                             version: SCHEMA_VERSION,
                         },
                         sutera_status: SuteraStatus::Ok,
-                        oneshot_header: OneshotHeader {
-                            step: OneshotStep::Request,
-                            message_type: OneshotTypes::Connection_HealthCheck_Push,
-                            message_id: message_id_dispatcher.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
-                        },
-                        payload: Vec::new()
+                        oneshot_header: HealthCheckPushOneshot::request_header(
+                            message_id_dispatcher.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                        ),
+                        payload: Bytes::new()
                     }).await?;
 
                 },
                 Some(control) = control.recv() => {
-                    match control {
-                        PlayerControl::NewChatMessage(entry) => {
-                            message.send_event_ok(
-                                EventTypes::TextChat_ReceiveChatMessage_Push,
-                                SendableChatEntry::from(entry)
-                            ).await?;
-                        },
-                        PlayerControl::PlayerJoined(id) => {
-                            message.send_event_ok(
-                                EventTypes::Instance_PlayerJoined_Push,
-                                PlayerJoined { joined_player: id }
-                            ).await?;
-                        }
-                        PlayerControl::PlayerLeft(id) => {
-                            message.send_event_ok(
-                                EventTypes::Instance_PlayerLeft_Push,
-                                PlayerLeft { left_player: id }
-                            ).await?;
-                        },
-                        PlayerControl::PlayerMoved(moved) => {
-                            message.send_event_ok(
-                                EventTypes::Instance_PushPlayerMove_Push,
-                                moved,
-                            ).await?;
-                        }
+                    if let PlayerControl::Replaced = control {
+                        info!("{} The session has been taken over by another connection, closing...", peer_addr);
+                        // 引き継いだ接続が同じプレイヤーになるので、こちらは参加していないものとする
+                        login_status = None;
+                        message.shutdown(ShutdownReason::Replaced).await?;
+                        stream_handle.await??;
+                        break;
                     }
+                    forward_control(&message, control).await?;
                 },
                 Some(request) = message.recv() => {
                     match request {
-                        Request::Event(request) if request.event_header.message_type == EventTypes::Instance_PubPlayerMove_Pull => {
-                            let Ok(payload) = deserialize::<PubPlayerMove, PubPlayerMove>(&request.payload) else {
+                        Request::Oneshot(request) if request.version_compatibility() == VersionCompatibility::Incompatible => {
+                            warn!("{} Unsupported schema version {} (server: {}), rejecting...", peer_addr, request.sutera_header.version, SCHEMA_VERSION);
+                            if request.oneshot_header.step == OneshotStep::Request {
+                                request.send_reply_version_not_supported().await?;
+                            }
+                        },
+                        Request::Event(request) if request.version_compatibility() == VersionCompatibility::Incompatible => {
+                            warn!("{} Unsupported schema version {} (server: {}), skipping event...", peer_addr, request.sutera_header.version, SCHEMA_VERSION);
+                        },
+                        Request::Event(request) if request.event_header.message_type.is::<PubPlayerMoveEvent>() => {
+                            let Ok(payload) = PubPlayerMoveEvent::decode(&request.payload) else {
                                 warn!("Failed to deserialize PubPlayerMove, skipping...");
                                 continue;
                             };
@@ -192,39 +274,107 @@ async fn connection_init(
                                 warn!("Received PubPlayerMove from unauthenticated client, skipping...");
                             }
                         },
-                        Request::Oneshot(request) if ONESHOT_DIRECTION_MAP[request.oneshot_header.message_type] == OneshotDirection::Push => {
-                            if request.oneshot_header.message_type == OneshotTypes::Connection_HealthCheck_Push {
+                        Request::Oneshot(request) if request.oneshot_header.message_type.direction() == Some(OneshotDirection::Push) => {
+                            if let Some(status) = request.sutera_status.as_ref().filter(|status| **status != SuteraStatus::Ok) {
+                                warn!("{} Client responded to {:?} with {:?} ({:?})", peer_addr, request.oneshot_header.message_type, status, ErrorDetails::decode(&request.payload));
+                            }
+                            if request.oneshot_header.message_type.is::<HealthCheckPushOneshot>() {
                                 healthcheck_missed_count = 0;
                                 info!("{} Healthcheck!", peer_addr);
                             }
                             // TODO: Implement push message handling
                             continue;
                         },
-                        Request::Oneshot(request) if request.oneshot_header.message_type == OneshotTypes::Connection_HealthCheck_Pull => {
-                            request.send_reply(Vec::new()).await?;
+                        Request::Oneshot(request) if request.oneshot_header.message_type.is::<HealthCheckPullOneshot>() => {
+                            request.send_typed_reply::<HealthCheckPullOneshot>(()).await?;
                         }
-                        Request::Oneshot(request) if request.oneshot_header.message_type == OneshotTypes::Authentication_Login_Pull => {
-                            let Ok(payload) = deserialize::<LoginRequest, LoginRequest>(&request.payload) else {
+                        Request::Oneshot(request) if request.oneshot_header.message_type.is::<NegotiateCompressionOneshot>() => {
+                            let Ok(payload) = request.payload_as::<NegotiateCompressionOneshot>() else {
                                 request.send_reply_bad_request().await?;
                                 continue;
                             };
+                            // 圧縮を有効にしてから返答し、クライアントが圧縮しはじめる前に受け付けられるようにする
+                            let response = match payload.choose() {
+                                Some(encoding) => {
+                                    info!("{} Compression enabled: {:?}", peer_addr, encoding);
+                                    message.enable_compression(encoding).await?;
+                                    CompressionResponse::Enabled(encoding.code())
+                                },
+                                None => CompressionResponse::Disabled,
+                            };
+                            request.send_typed_reply::<NegotiateCompressionOneshot>(response).await?;
+                        }
+                        Request::Oneshot(request) if request.oneshot_header.message_type.is::<LoginOneshot>() => {
+                            let Ok(payload) = request.payload_as::<LoginOneshot>() else {
+                                request.send_reply_error(SuteraStatusError::BadRequest, ErrorDetails::new("login.malformed", "The login request is malformed.")).await?;
+                                continue;
+                            };
+                            // 参加し直すと前のプレイヤーが離脱も一時離脱もできなくなるので、1つの接続では1度だけ参加できる
+                            if login_status.is_some() {
+                                request.send_reply_error(SuteraStatusError::BadRequest, ErrorDetails::new("login.already_logged_in", "This connection has already joined an instance.")).await?;
+                                continue;
+                            }
+                            let Some(join_tokens) = &options.join_tokens else {
+                                warn!("{} Login refused: no public key for join tokens is configured.", peer_addr);
+                                request.send_reply_error(SuteraStatusError::Unauthorized, ErrorDetails::new("login.unavailable", "This server does not accept logins.")).await?;
+                                continue;
+                            };
+                            let claims = match join_tokens.verify_now(&payload.join_token, payload.instance) {
+                                Ok(claims) => claims,
+                                Err(e) => {
+                                    info!("{} Login refused: {}", peer_addr, e);
+                                    let (status, details) = e.to_status();
+                                    request.send_reply_error(status, details).await?;
+                                    continue;
+                                }
+                            };
+                            info!("{} Logging in as {:?} to instance {:?}", peer_addr, claims.user, claims.instance);
                             let (reply, reply_recv) = oneshot::channel();
-                            instances_tx.send(InstancesControl::JoinInstance { id: payload.join_token, reply, control: control_tx.clone() }).await?;
-                            if let Some((auth, list)) = reply_recv.await.map_err(TcpServerError::CannotReceiveFromInstanceManager)? {
-                                let id = auth.0;
-                                login_status = Some(auth);
-                                request.serialize_and_send_reply(LoginResponse::Ok(id, list)).await?;
+                            instances_tx.send(InstancesControl::JoinInstance { id: claims.instance, reply, control: control_tx.clone() }).await?;
+                            if let Some(joined) = reply_recv.await.map_err(TcpServerError::CannotReceiveFromInstanceManager)? {
+                                login_status = Some((joined.player, joined.instance));
+                                request.send_typed_reply::<LoginOneshot>(LoginResponse::Ok(joined.player, joined.players, joined.resume_token.to_string())).await?;
                             } else {
-                                request.serialize_and_send_reply(LoginResponse::BadToken).await?;
+                                request.send_typed_reply::<LoginOneshot>(LoginResponse::BadToken).await?;
                             }
                         }
-                        Request::Oneshot(request) if request.oneshot_header.message_type == OneshotTypes::TextChat_SendMessage_Pull => {
-                            let Ok(payload) = deserialize::<SendChatMessageRequest, SendChatMessageRequest>(&request.payload) else {
-                                request.send_reply_bad_request().await?;
+                        Request::Oneshot(request) if request.oneshot_header.message_type.is::<ResumeOneshot>() => {
+                            let Ok(payload) = request.payload_as::<ResumeOneshot>() else {
+                                request.send_reply_error(SuteraStatusError::BadRequest, ErrorDetails::new("resume.malformed", "The resume request is malformed.")).await?;
+                                continue;
+                            };
+                            if login_status.is_some() {
+                                request.send_reply_error(SuteraStatusError::BadRequest, ErrorDetails::new("resume.already_logged_in", "This connection has already joined an instance.")).await?;
+                                continue;
+                            }
+                            let Ok(token) = payload.resume_token.parse::<ResumeToken>() else {
+                                request.send_reply_error(SuteraStatusError::Unauthorized, ErrorDetails::new("resume.bad_token", "The resume token is invalid.").with_retry(RetryHint::AfterReauthentication)).await?;
+                                continue;
+                            };
+                            let (reply, reply_recv) = oneshot::channel();
+                            instances_tx.send(InstancesControl::Resume { token, reply, control: control_tx.clone() }).await?;
+                            let Some(resumed) = reply_recv.await.map_err(TcpServerError::CannotReceiveFromInstanceManager)? else {
+                                info!("{} Resume refused.", peer_addr);
+                                request.send_reply_error(SuteraStatusError::AuthenticationHasBeenExpired, ErrorDetails::new("resume.expired", "The session has expired. Please log in again.").with_retry(RetryHint::AfterReauthentication)).await?;
+                                continue;
+                            };
+                            info!("{} Resumed as player {:?}", peer_addr, resumed.player);
+                            login_status = Some((resumed.player, resumed.instance));
+                            request.send_typed_reply::<ResumeOneshot>(ResumeResponse::Ok(resumed.player, resumed.resume_token.to_string())).await?;
+                            for control in resumed.missed {
+                                forward_control(&message, control).await?;
+                            }
+                        }
+                        Request::Oneshot(request) if request.oneshot_header.message_type.is::<SendChatMessageOneshot>() => {
+                            let Ok(payload) = request.payload_as::<SendChatMessageOneshot>() else {
+                                request.send_reply_error(SuteraStatusError::BadRequest, ErrorDetails::new("chat.malformed", "The chat message is malformed.")).await?;
                                 continue;
                             };
                             let Some((player_id, instance_tx)) = &login_status else {
-                                request.send_reply_unauthorized().await?;
+                                request.send_reply_error(
+                                    SuteraStatusError::Unauthorized,
+                                    ErrorDetails::new("chat.not_logged_in", "You need to log in before sending chat messages.").with_retry(RetryHint::AfterReauthentication),
+                                ).await?;
                                 continue;
                             };
 
@@ -235,11 +385,15 @@ async fn connection_init(
                             };
 
                             instance_tx.send(InstanceControl::ChatMesasge(entry)).await?;
-                            request.serialize_and_send_reply(SendChatMessageResponse::Ok).await?;
+                            request.send_typed_reply::<SendChatMessageOneshot>(SendChatMessageResponse::Ok).await?;
 
                         }
+                        Request::Oneshot(request) if request.oneshot_header.step == OneshotStep::Response => {
+                            warn!("{} Received response for unknown oneshot: {:?} ({:?}), skipping...", peer_addr, request.oneshot_header.message_type, request.sutera_status);
+                        },
                         Request::Oneshot(request) => {
-                            request.send_reply_failed(SuteraStatus::Error(SuteraStatusError::Unimplemented)).await?;
+                            let message = format!("{:?} is not implemented by this server.", request.oneshot_header.message_type);
+                            request.send_reply_error(SuteraStatusError::Unimplemented, ErrorDetails::new("unimplemented", message)).await?;
                         },
                         Request::Event(event) => {
                             error!("Received unexpected event: {:?}, skipping...", event);
@@ -251,32 +405,242 @@ async fn connection_init(
                     break;
                 },
                 Ok(reason) = shutdown_rx.recv() => {
+                    server_shutdown = true;
                     message.shutdown(reason).await?;
                     stream_handle.await??; break;
                 }
             }
         }
-        if let Some((player_id, instance_tx)) = login_status {
-            instance_tx.send(InstanceControl::Leave(player_id)).await?;
-        }
-
         Ok::<(), TcpServerError>(())
+    }
+    .await;
+
+    if let Some((player_id, instance_tx)) = login_status {
+        // サーバーを止める場合以外は、同じプレイヤーとして接続し直せるよう猶予を残す
+        let control = if server_shutdown {
+            InstanceControl::Leave(player_id)
+        } else {
+            InstanceControl::Detach(player_id, control_tx)
+        };
+        let detached = instance_tx.send(control).await;
+        result?;
+        detached?;
+        return Ok(());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::atomic::AtomicBool,
+        task::{Context, Poll},
+        time::Duration,
     };
 
-    join_set
-        .build_task()
-        .name(format!("Acceptor {}", peer_addr).as_str())
-        .spawn(async move {
-            match fut.await {
-                Ok(_) => {
-                    info!("Connection from {} is closed.", peer_addr);
-                }
-                Err(e) => {
-                    warn!("Failed in Acceptor {} ({})", peer_addr, e);
-                }
+    use suteravr_lib::{
+        clocking::{
+            buffer::{ContentHeader, FrameBuffer, ReceivePayload},
+            join_token::JoinTokenIssuer,
+            schemas::oneshot::login::LoginRequest,
+            traits::MessageAuthor,
+            ClockingConnection,
+        },
+        util::logger::EnvLogger,
+    };
+    use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+    use super::*;
+    use crate::{config::HealthcheckConfig, instance::manager::Joined};
+
+    /// `broken`を立てると、書き込みだけが失敗するようになります。
+    struct BreakableStream {
+        inner: DuplexStream,
+        broken: Arc<AtomicBool>,
+    }
+
+    impl BreakableStream {
+        fn check(&self) -> io::Result<()> {
+            if self.broken.load(std::sync::atomic::Ordering::Relaxed) {
+                return Err(io::ErrorKind::BrokenPipe.into());
             }
-        })
-        .map_err(TcpServerError::SpawnError)?;
+            Ok(())
+        }
+    }
 
-    Ok(())
+    impl AsyncRead for BreakableStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for BreakableStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.check()?;
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.check()?;
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    fn options(issuer: &JoinTokenIssuer) -> ConnectionOptions {
+        ConnectionOptions {
+            limits: ConnectionLimits::default(),
+            healthcheck: HealthcheckConfig::default(),
+            channel_capacity: 8,
+            join_tokens: Some(JoinTokenVerifier::new(issuer.public_key())),
+        }
+    }
+
+    async fn send_login(
+        client: &mut ClockingConnection<DuplexStream>,
+        issuer: &JoinTokenIssuer,
+        message_id: u64,
+    ) {
+        client
+            .write_message(
+                SuteraHeader {
+                    version: SCHEMA_VERSION,
+                },
+                None,
+                ContentHeader::Oneshot(LoginOneshot::request_header(message_id)),
+                LoginOneshot::encode_request(LoginRequest {
+                    instance: 0x01,
+                    join_token: issuer.issue_for("alice", 0x01, Duration::from_secs(60)),
+                }),
+            )
+            .await
+            .unwrap();
+    }
+
+    /// Oneshotの返答が届くまで、他のメッセージを読み飛ばします。
+    async fn read_response(
+        client: &mut ClockingConnection<DuplexStream>,
+        frame_buffer: &mut FrameBuffer<EnvLogger>,
+    ) -> ReceivePayload {
+        loop {
+            let frame = client.read_frame().await.unwrap().unwrap();
+            let Some(received) = frame_buffer.append(frame, MessageAuthor::Server) else {
+                continue;
+            };
+            if matches!(&received.content_header, ContentHeader::Oneshot(header) if header.step == OneshotStep::Response)
+            {
+                return received;
+            }
+        }
+    }
+
+    fn joined(player: PlayerId) -> (Option<Joined>, mpsc::Receiver<InstanceControl>) {
+        let (instance_tx, instance_rx) = mpsc::channel(8);
+        let joined = Joined {
+            player,
+            instance: instance_tx,
+            players: Vec::new(),
+            resume_token: ResumeToken::generate(0x01, player),
+        };
+        (Some(joined), instance_rx)
+    }
+
+    #[tokio::test]
+    async fn detach_when_write_fails() {
+        let issuer =
+            JoinTokenIssuer::from_pkcs8(&JoinTokenIssuer::generate_pkcs8().unwrap()).unwrap();
+        let options = options(&issuer);
+        let (client, server) = duplex(4096);
+        let broken = Arc::new(AtomicBool::new(false));
+        let (instances_tx, mut instances_rx) = mpsc::channel(8);
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        tokio::spawn(serve_connection(
+            BreakableStream {
+                inner: server,
+                broken: broken.clone(),
+            },
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            options,
+            shutdown_rx,
+            instances_tx,
+        ));
+
+        let mut client = ClockingConnection::new(client, MessageAuthor::Server);
+        send_login(&mut client, &issuer, 0).await;
+
+        let Some(InstancesControl::JoinInstance { control, reply, .. }) = instances_rx.recv().await
+        else {
+            panic!("JoinInstance is expected");
+        };
+        // ログインの返答から先は、クライアントに届かない
+        broken.store(true, std::sync::atomic::Ordering::Relaxed);
+        let (joined, mut instance_rx) = joined(7);
+        assert!(reply.send(joined).is_ok());
+        for _ in 0..4 {
+            let _ = control.try_send(PlayerControl::PlayerJoined(8));
+        }
+
+        let detached = time::timeout(Duration::from_secs(5), instance_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(detached, Some(InstanceControl::Detach(7, _))));
+    }
+
+    #[tokio::test]
+    async fn reject_second_login() {
+        let issuer =
+            JoinTokenIssuer::from_pkcs8(&JoinTokenIssuer::generate_pkcs8().unwrap()).unwrap();
+        let (client, server) = duplex(4096);
+        let (instances_tx, mut instances_rx) = mpsc::channel(8);
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        tokio::spawn(serve_connection(
+            server,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            options(&issuer),
+            shutdown_rx,
+            instances_tx,
+        ));
+        let mut client = ClockingConnection::new(client, MessageAuthor::Server);
+        let mut frame_buffer = FrameBuffer::new(EnvLogger {
+            target: "reject_second_login".to_string(),
+        });
+
+        send_login(&mut client, &issuer, 0).await;
+        let Some(InstancesControl::JoinInstance { reply, .. }) = instances_rx.recv().await else {
+            panic!("JoinInstance is expected");
+        };
+        let (joined, mut instance_rx) = joined(7);
+        assert!(reply.send(joined).is_ok());
+        let response = read_response(&mut client, &mut frame_buffer).await;
+        assert_eq!(response.sutera_status, Some(SuteraStatus::Ok));
+
+        send_login(&mut client, &issuer, 1).await;
+        let response = read_response(&mut client, &mut frame_buffer).await;
+        assert!(response.sutera_status.unwrap().is_error());
+        assert_eq!(
+            ErrorDetails::decode(&response.payload).unwrap().code,
+            "login.already_logged_in"
+        );
+        // 2人目としては参加しない
+        assert!(instances_rx.try_recv().is_err());
+
+        // 切れると、最初に参加したプレイヤーが一時離脱する
+        drop(client);
+        let detached = time::timeout(Duration::from_secs(5), instance_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(detached, Some(InstanceControl::Detach(7, _))));
+    }
 }
@@ -1,12 +1,17 @@
-use alkahest::{Formula, Serialize};
+use alkahest::{DeserializeError, Formula, Serialize};
+use bytes::Bytes;
 use derivative::Derivative;
 use suteravr_lib::{
     clocking::{
+        compression::ContentEncoding,
         event_headers::{EventRequest, EventResponse},
+        messages::OneshotMessage,
         oneshot_headers::{OneshotHeader, OneshotStep},
+        schemas::oneshot::error_details::ErrorDetails,
         sutera_header::SuteraHeader,
-        sutera_status::{SuteraStatus, SuteraStatusError},
+        sutera_status::{SuteraStatus, SuteraStatusError, SuteraStatusWarning},
     },
+    messaging::version::VersionCompatibility,
     util::serialize_to_new_vec,
     SCHEMA_VERSION,
 };
@@ -22,14 +27,18 @@ pub enum Request {
 pub enum Response {
     Oneshot(OneshotResponse),
     Event(EventResponse),
+    /// 以降のContentを圧縮して送り、圧縮されたContentを受け付けるようにします。
+    EnableCompression(ContentEncoding),
 }
 
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct OneshotRequest {
     pub sutera_header: SuteraHeader,
+    /// クライアントからのレスポンスにのみ付けられます。省略された場合は`None`です。
+    pub sutera_status: Option<SuteraStatus>,
     pub oneshot_header: OneshotHeader,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 
     #[derivative(Debug = "ignore")]
     reply: mpsc::Sender<Response>,
@@ -40,19 +49,21 @@ pub struct OneshotResponse {
     pub sutera_header: SuteraHeader,
     pub sutera_status: SuteraStatus,
     pub oneshot_header: OneshotHeader,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
 
 impl OneshotRequest {
     #[inline]
     pub fn new(
         sutera_header: SuteraHeader,
+        sutera_status: Option<SuteraStatus>,
         oneshot_header: OneshotHeader,
-        payload: Vec<u8>,
+        payload: Bytes,
         reply: mpsc::Sender<Response>,
     ) -> Self {
         Self {
             sutera_header,
+            sutera_status,
             oneshot_header,
             payload,
             reply,
@@ -64,11 +75,28 @@ impl OneshotRequest {
         self,
         payload: T,
     ) -> Result<(), TcpServerError> {
-        self.send_reply(serialize_to_new_vec(payload)).await
+        self.send_reply(serialize_to_new_vec(payload).into()).await
     }
 
+    /// ペイロードを`M`のリクエストとして解釈します。
     #[inline]
-    pub async fn send_reply(self, payload: Vec<u8>) -> Result<(), TcpServerError> {
+    pub fn payload_as<M: OneshotMessage>(&self) -> Result<M::Request, DeserializeError> {
+        debug_assert!(self.oneshot_header.message_type.is::<M>());
+        M::decode_request(&self.payload)
+    }
+
+    /// `M`のレスポンスとして返信します。
+    #[inline]
+    pub async fn send_typed_reply<M: OneshotMessage>(
+        self,
+        payload: M::Response,
+    ) -> Result<(), TcpServerError> {
+        debug_assert!(self.oneshot_header.message_type.is::<M>());
+        self.send_reply(M::encode_response(payload)).await
+    }
+
+    #[inline]
+    pub async fn send_reply(self, payload: Bytes) -> Result<(), TcpServerError> {
         let response = self.to_reply(payload);
         self.reply
             .send(Response::Oneshot(response))
@@ -87,6 +115,21 @@ impl OneshotRequest {
         Ok(())
     }
 
+    /// 理由を詳しく伝えるために、[`ErrorDetails`]を付けてエラーを返信します。
+    #[inline]
+    pub async fn send_reply_error(
+        self,
+        error: SuteraStatusError,
+        details: ErrorDetails,
+    ) -> Result<(), TcpServerError> {
+        let response = self.to_reply_error(error, details);
+        self.reply
+            .send(Response::Oneshot(response))
+            .await
+            .map_err(TcpServerError::CannotSendResponse)?;
+        Ok(())
+    }
+
     #[inline]
     pub async fn send_reply_bad_request(self) -> Result<(), TcpServerError> {
         self.send_reply_failed(SuteraStatus::Error(SuteraStatusError::BadRequest))
@@ -98,13 +141,36 @@ impl OneshotRequest {
             .await
     }
 
+    /// リクエストのスキーマバージョンが、サーバーのものと互換性があるかを調べます。
+    #[inline]
+    pub fn version_compatibility(&self) -> VersionCompatibility {
+        SCHEMA_VERSION.compatibility_with(&self.sutera_header.version)
+    }
+
     #[inline]
-    pub fn to_reply(&self, payload: Vec<u8>) -> OneshotResponse {
+    pub async fn send_reply_version_not_supported(self) -> Result<(), TcpServerError> {
+        let message = format!(
+            "The schema version {} is not supported by this server ({}). Please update your client.",
+            self.sutera_header.version, SCHEMA_VERSION
+        );
+        self.send_reply_error(
+            SuteraStatusError::SchemaVersionNotSupported,
+            ErrorDetails::new("schema_version_not_supported", message),
+        )
+        .await
+    }
+
+    #[inline]
+    pub fn to_reply(&self, payload: Bytes) -> OneshotResponse {
         OneshotResponse {
             sutera_header: SuteraHeader {
                 version: SCHEMA_VERSION,
             },
-            sutera_status: SuteraStatus::Ok,
+            // 互換性はあるが一致していない場合は、処理した上でその旨を伝える
+            sutera_status: match self.version_compatibility() {
+                VersionCompatibility::Exact => SuteraStatus::Ok,
+                _ => SuteraStatus::Warning(SuteraStatusWarning::SchemaVersionNotExactlyMatched),
+            },
             oneshot_header: OneshotHeader {
                 step: OneshotStep::Response,
                 message_type: self.oneshot_header.message_type,
@@ -114,19 +180,37 @@ impl OneshotRequest {
         }
     }
 
+    /// エラーの場合は、その種類の既定の[`ErrorDetails`]をペイロードとして付けます。
     #[inline]
     pub fn to_reply_failed(&self, fail_status: SuteraStatus) -> OneshotResponse {
+        match fail_status {
+            SuteraStatus::Error(error) => self.to_reply_error(error, error.into()),
+            fail_status => self.to_reply_with_status(fail_status, Bytes::new()),
+        }
+    }
+
+    #[inline]
+    pub fn to_reply_error(
+        &self,
+        error: SuteraStatusError,
+        details: ErrorDetails,
+    ) -> OneshotResponse {
+        self.to_reply_with_status(SuteraStatus::Error(error), details.encode())
+    }
+
+    #[inline]
+    fn to_reply_with_status(&self, sutera_status: SuteraStatus, payload: Bytes) -> OneshotResponse {
         OneshotResponse {
             sutera_header: SuteraHeader {
                 version: SCHEMA_VERSION,
             },
-            sutera_status: fail_status,
+            sutera_status,
             oneshot_header: OneshotHeader {
                 step: OneshotStep::Response,
                 message_type: self.oneshot_header.message_type,
                 message_id: self.oneshot_header.message_id,
             },
-            payload: Vec::<u8>::new(),
+            payload,
         }
     }
 }
@@ -1,15 +1,20 @@
-use alkahest::{Formula, Serialize};
+use bytes::Bytes;
+use chrono::Local;
 use std::net::SocketAddr;
 use suteravr_lib::{
     clocking::{
         buffer::{ContentHeader, FrameBuffer},
-        event_headers::{EventDirection, EventHeader, EventRequest, EventResponse, EventTypes},
+        capture::FrameCapture,
+        compression::{ContentCompression, ContentEncoding},
+        event_headers::{EventRequest, EventResponse},
+        messages::EventMessage,
         sutera_header::SuteraHeader,
         sutera_status::SuteraStatus,
         traits::MessageAuthor,
-        ClockingConnection, ClockingFrameUnit,
+        ClockingConnection, ConnectionLimits,
     },
-    util::{logger::EnvLogger, serialize_to_new_vec},
+    info,
+    util::logger::EnvLogger,
     warn, SCHEMA_VERSION,
 };
 use tokio::{
@@ -21,7 +26,10 @@ use tokio::{
     task::{Builder, JoinHandle},
 };
 
-use crate::{errors::TcpServerError, shutdown::ShutdownReason, tcp::requests::OneshotRequest};
+use crate::{
+    consts::CAPTURE_DIR, errors::TcpServerError, shutdown::ShutdownReason,
+    tcp::requests::OneshotRequest,
+};
 
 use super::requests::{OneshotResponse, Request, Response};
 
@@ -36,11 +44,13 @@ impl ClientMessageStream {
     pub fn new<W: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static>(
         stream: W,
         peer_addr: SocketAddr,
+        limits: ConnectionLimits,
+        channel_capacity: usize,
     ) -> Result<(Self, JoinHandle<Result<(), TcpServerError>>), TcpServerError> {
-        let mut connection = ClockingConnection::new(stream, MessageAuthor::Client);
+        let mut connection = ClockingConnection::with_limits(stream, MessageAuthor::Client, limits);
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<ShutdownReason>();
-        let (receive_tx, receive_rx) = mpsc::channel::<Request>(32);
-        let (send_tx, send_rx) = mpsc::channel::<Response>(32);
+        let (receive_tx, receive_rx) = mpsc::channel::<Request>(channel_capacity);
+        let (send_tx, send_rx) = mpsc::channel::<Response>(channel_capacity);
         let reply = send_tx.clone();
         let logger = EnvLogger {
             target: format!("stream {}", peer_addr),
@@ -50,6 +60,20 @@ impl ClientMessageStream {
             .name(format!("Stream of {}", peer_addr).as_str())
             .spawn(async move {
                 let connection = &mut connection;
+                if let Some(dir) = &*CAPTURE_DIR {
+                    let path = dir.join(format!(
+                        "{}-{}.capture",
+                        Local::now().format("%Y%m%d-%H%M%S"),
+                        peer_addr.to_string().replace([':', '[', ']'], "_")
+                    ));
+                    match FrameCapture::create(&path, MessageAuthor::Client).await {
+                        Ok((capture, _)) => {
+                            info!(logger, "Capturing frames into {}", path.display());
+                            connection.set_capture(Some(capture));
+                        }
+                        Err(e) => warn!(logger, "Failed to create a capture file: {}", e),
+                    }
+                }
                 let mut shutdown = shutdown_rx;
                 let mut frame_buffer = FrameBuffer::new(logger.clone());
                 let receive = receive_tx;
@@ -60,16 +84,27 @@ impl ClientMessageStream {
                         Some(response) = send.recv() => {
                             match response {
                                 Response::Oneshot(oneshot) => {
-                                    connection.write_frame(&ClockingFrameUnit::SuteraHeader(oneshot.sutera_header)).await?;
-                                    connection.write_frame(&ClockingFrameUnit::SuteraStatus(oneshot.sutera_status)).await?;
-                                    connection.write_frame(&ClockingFrameUnit::OneshotHeaders(oneshot.oneshot_header)).await?;
-                                    connection.write_frame(&ClockingFrameUnit::Content(oneshot.payload)).await?;
+                                    connection.write_message(
+                                        oneshot.sutera_header,
+                                        Some(oneshot.sutera_status),
+                                        ContentHeader::Oneshot(oneshot.oneshot_header),
+                                        oneshot.payload,
+                                    ).await?;
                                 },
                                 Response::Event(event) => {
-                                    connection.write_frame(&ClockingFrameUnit::SuteraHeader(event.sutera_header)).await?;
-                                    connection.write_frame(&ClockingFrameUnit::SuteraStatus(event.sutera_status)).await?;
-                                    connection.write_frame(&ClockingFrameUnit::EventHeader(event.event_header)).await?;
-                                    connection.write_frame(&ClockingFrameUnit::Content(event.payload)).await?;
+                                    connection.write_message(
+                                        event.sutera_header,
+                                        Some(event.sutera_status),
+                                        ContentHeader::Event(event.event_header),
+                                        event.payload,
+                                    ).await?;
+                                },
+                                Response::EnableCompression(encoding) => {
+                                    connection.set_compression(ContentCompression {
+                                        accept: true,
+                                        send: Some(encoding),
+                                        ..*connection.compression()
+                                    });
                                 },
                             }
                         },
@@ -77,14 +112,12 @@ impl ClientMessageStream {
                             match read {
                                 Ok(Some(payload)) => {
                                     if let Some(received) = frame_buffer.append(payload, MessageAuthor::Client) {
-                                        if received.sutera_status.is_some() {
-                                            panic!("Received message contains sutera_header! (Maybe frame_buffer has bugs.)")
-                                        }
                                         match received.content_header {
                                             ContentHeader::Oneshot(oneshot_header) => {
                                                 receive.send(
                                                     Request::Oneshot(OneshotRequest::new(
                                                         received.sutera_header,
+                                                        received.sutera_status,
                                                         oneshot_header,
                                                         received.payload,
                                                         reply.clone()
@@ -148,21 +181,28 @@ impl ClientMessageStream {
     }
 
     #[inline]
-    pub async fn send_event_ok<T: Formula + Serialize<T>>(
+    pub async fn send_event_ok<M: EventMessage>(
+        &self,
+        payload: M::Payload,
+    ) -> Result<(), TcpServerError> {
+        self.send_encoded_event_ok::<M>(M::encode(payload)).await
+    }
+
+    /// エンコード済みのペイロードをイベントとして送ります。
+    ///
+    /// 同じペイロードを複数のプレイヤーへ送る場合に、エンコードと確保を一度で済ませるために使います。
+    #[inline]
+    pub async fn send_encoded_event_ok<M: EventMessage>(
         &self,
-        event_type: EventTypes,
-        payload: T,
+        payload: Bytes,
     ) -> Result<(), TcpServerError> {
         let response = EventResponse {
             sutera_header: SuteraHeader {
                 version: SCHEMA_VERSION,
             },
             sutera_status: SuteraStatus::Ok,
-            event_header: EventHeader {
-                direction: EventDirection::Push,
-                message_type: event_type,
-            },
-            payload: serialize_to_new_vec(payload),
+            event_header: M::header(),
+            payload,
         };
         self.send_tx
             .send(Response::Event(response))
@@ -171,6 +211,21 @@ impl ClientMessageStream {
         Ok(())
     }
 
+    /// Contentの圧縮を有効にします。
+    ///
+    /// 交渉のレスポンスを送る前に呼び出してください。
+    #[inline]
+    pub async fn enable_compression(
+        &self,
+        encoding: ContentEncoding,
+    ) -> Result<(), TcpServerError> {
+        self.send_tx
+            .send(Response::EnableCompression(encoding))
+            .await
+            .map_err(TcpServerError::CannotSendResponse)?;
+        Ok(())
+    }
+
     pub async fn shutdown(self, reason: ShutdownReason) -> Result<(), TcpServerError> {
         self.shutdown_tx
             .send(reason)
@@ -1,9 +1,11 @@
+use bytes::Bytes;
+
 use crate::clocking::ClockingFrameUnit;
 use crate::util::logger::Logger;
-use crate::{debug, error, warn};
+use crate::{debug, warn};
 
 use super::event_headers::EventHeader;
-use super::oneshot_headers::OneshotHeader;
+use super::oneshot_headers::{OneshotHeader, OneshotStep};
 use super::sutera_header::SuteraHeader;
 use super::sutera_status::SuteraStatus;
 use super::traits::MessageAuthor;
@@ -24,7 +26,7 @@ pub struct ReceivePayload {
     pub sutera_header: SuteraHeader,
     pub sutera_status: Option<SuteraStatus>,
     pub content_header: ContentHeader,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
 
 impl<T: Logger> FrameBuffer<T> {
@@ -52,9 +54,50 @@ impl<T: Logger> FrameBuffer<T> {
         self.buffer.clear()
     }
 
+    /// 溜まっているヘッダーを取り出し、1つのメッセージとして組み立てます。
+    ///
+    /// ヘッダーは複製せずにバッファから移動するので、呼び出し後バッファは空になります。
     #[inline]
-    fn get_into(&self, index: usize) -> Option<ClockingFrameUnit> {
-        self.buffer.get(index).cloned()
+    fn take_message(&mut self, author: MessageAuthor, payload: Bytes) -> Option<ReceivePayload> {
+        let mut frames = self.buffer.drain(..);
+        let Some(ClockingFrameUnit::SuteraHeader(sutera_header)) = frames.next() else {
+            return None;
+        };
+        let (sutera_status, content_header) = match (author, frames.next()?) {
+            (_, ClockingFrameUnit::SuteraStatus(sutera_status)) => {
+                (Some(sutera_status), frames.next()?)
+            }
+            // クライアントからのメッセージでは、SuteraStatusは省略できる
+            (MessageAuthor::Client, content_header) => (None, content_header),
+            (MessageAuthor::Server, _) => return None,
+        };
+        let content_header = match content_header {
+            ClockingFrameUnit::OneshotHeaders(oneshot_header) => {
+                ContentHeader::Oneshot(oneshot_header)
+            }
+            ClockingFrameUnit::EventHeader(event_header) => ContentHeader::Event(event_header),
+            _ => return None,
+        };
+        // クライアントがSuteraStatusを付けられるのは、Oneshotのレスポンスだけ
+        if author == MessageAuthor::Client
+            && sutera_status.is_some()
+            && !matches!(
+                &content_header,
+                ContentHeader::Oneshot(header) if header.step == OneshotStep::Response
+            )
+        {
+            warn!(
+                self.logger,
+                "SuteraStatus is only allowed for oneshot responses from clients."
+            );
+            return None;
+        }
+        Some(ReceivePayload {
+            sutera_header,
+            sutera_status,
+            content_header,
+            payload,
+        })
     }
 
     #[inline]
@@ -64,13 +107,6 @@ impl<T: Logger> FrameBuffer<T> {
         author: MessageAuthor,
     ) -> Option<ReceivePayload> {
         match payload {
-            ClockingFrameUnit::SuteraStatus(_) if author == MessageAuthor::Client => {
-                error!(
-                    self.logger,
-                    "Unexpected SuteraStatus of ClockingConnection!"
-                );
-                unreachable!();
-            }
             ClockingFrameUnit::SuteraHeader(_) => {
                 let len = self.len();
                 if self.len() != 0 {
@@ -84,61 +120,18 @@ impl<T: Logger> FrameBuffer<T> {
             }
             ClockingFrameUnit::Content(payload) => {
                 let len = self.len();
-                if len
-                    != match author {
-                        MessageAuthor::Client => 2,
-                        MessageAuthor::Server => 3,
-                    }
-                {
+                let expected = match author {
+                    MessageAuthor::Client => 2..=3,
+                    MessageAuthor::Server => 3..=3,
+                };
+                if !expected.contains(&len) {
                     warn!(self.logger, "Unexpected content, Skipped {} frame(s).", len);
                     self.clear();
                     return None;
                 }
-                let Some(ClockingFrameUnit::SuteraHeader(sutera_header)) = self.get_into(0) else {
-                    return None;
-                };
-                let Ok(sutera_status) = (if author == MessageAuthor::Client {
-                    Ok(None)
-                } else {
-                    match self.get_into(1) {
-                        Some(ClockingFrameUnit::SuteraStatus(sutera_status)) => {
-                            Ok(Some(sutera_status))
-                        }
-                        _ => Err(()),
-                    }
-                }) else {
-                    return None;
-                };
-                match self.get_into(match author {
-                    MessageAuthor::Client => 1,
-                    MessageAuthor::Server => 2,
-                }) {
-                    Some(ClockingFrameUnit::OneshotHeaders(oneshot_header)) => {
-                        let request = ReceivePayload {
-                            sutera_header,
-                            sutera_status,
-                            content_header: ContentHeader::Oneshot(oneshot_header),
-                            payload,
-                        };
-                        debug!(self.logger, "Receive: {:?}", &request);
-                        self.clear();
-                        return Some(request);
-                    }
-                    Some(ClockingFrameUnit::EventHeader(event_header)) => {
-                        let request = ReceivePayload {
-                            sutera_header,
-                            sutera_status,
-                            content_header: ContentHeader::Event(event_header),
-                            payload,
-                        };
-                        debug!(self.logger, "Receive: {:?}", &request);
-                        self.clear();
-                        return Some(request);
-                    }
-                    Some(_) | None => {
-                        return None;
-                    }
-                }
+                let request = self.take_message(author, payload)?;
+                debug!(self.logger, "Receive: {:?}", &request);
+                return Some(request);
             }
             _ => {
                 self.push(payload);
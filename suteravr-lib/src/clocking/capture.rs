@@ -0,0 +1,459 @@
+//! [`ClockingConnection`][super::ClockingConnection]で読み書きした[`ClockingFrameUnit`]を記録・再生するための仕組みです。
+//!
+//! キャプチャファイルは、先頭のヘッダーに続いて、フレームごとのレコードが並んだものです。
+//!
+//! | 位置 | 内容 |
+//! | --- | --- |
+//! | ヘッダー | `SuteraCapture` / 形式のバージョン(u8) / 受信したフレームの送信者(u8) |
+//! | レコード | 時刻(i64, UNIX時間のマイクロ秒) / 方向(u8) / フレームの種類(u8) / 長さ(u32) / フレーム |
+//!
+//! Contentは展開された状態で記録されるので、圧縮の有無にかかわらず中身をそのまま読めます。
+
+use std::{
+    io::{self, Cursor},
+    mem::size_of,
+    path::Path,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use chrono::{DateTime, TimeZone, Utc};
+use enum_map::{enum_map, Enum, EnumMap};
+use once_cell::sync::Lazy;
+use thiserror::Error;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::util::search_from_enum;
+
+use super::{
+    codec::ClockingCodec,
+    event_headers::EventHeader,
+    oneshot_headers::OneshotHeader,
+    sutera_header::SuteraHeader,
+    sutera_status::SuteraStatus,
+    traits::{ClockingFrame, MessageAuthor},
+    ClockingFrameUnit,
+};
+
+const CAPTURE_MAGIC: &[u8; 13] = b"SuteraCapture";
+const CAPTURE_FORMAT_VERSION: u8 = 0x01;
+const CAPTURE_HEADER_SIZE: usize = CAPTURE_MAGIC.len() + 2;
+const RECORD_HEADER_SIZE: usize = size_of::<i64>() + 2 + size_of::<u32>();
+
+#[derive(Error, Debug)]
+pub enum CaptureError {
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    #[error("This is not a capture file.")]
+    NotACapture,
+    #[error("Unsupported capture format version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("The capture file is broken.")]
+    Broken,
+}
+
+/// フレームを読んだのか、書いたのかを表します。
+#[derive(Enum, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CaptureDirection {
+    Read,
+    Written,
+}
+
+static CAPTURE_DIRECTION_MAP: Lazy<EnumMap<CaptureDirection, u8>> = Lazy::new(|| {
+    enum_map! {
+        CaptureDirection::Read => 0x00,
+        CaptureDirection::Written => 0x01,
+    }
+});
+
+static MESSAGE_AUTHOR_MAP: Lazy<EnumMap<MessageAuthor, u8>> = Lazy::new(|| {
+    enum_map! {
+        MessageAuthor::Client => 0x00,
+        MessageAuthor::Server => 0x01,
+    }
+});
+
+impl CaptureDirection {
+    /// この方向のフレームの送信者を返します。`author`はコネクションで受信したフレームの送信者です。
+    #[inline]
+    fn author(&self, author: MessageAuthor) -> MessageAuthor {
+        match (self, author) {
+            (CaptureDirection::Read, author) => author,
+            (CaptureDirection::Written, MessageAuthor::Client) => MessageAuthor::Server,
+            (CaptureDirection::Written, MessageAuthor::Server) => MessageAuthor::Client,
+        }
+    }
+}
+
+/// キャプチャに記録された1つのフレームです。
+#[derive(Debug, PartialEq, Clone)]
+pub struct CapturedFrame {
+    pub timestamp: DateTime<Utc>,
+    pub direction: CaptureDirection,
+    pub frame: ClockingFrameUnit,
+}
+
+impl CapturedFrame {
+    /// `author`は、キャプチャしたコネクションで受信したフレームの送信者です。
+    ///
+    /// 書き込んだフレームの送信者は、その反対になります。
+    #[inline]
+    pub fn author(&self, author: MessageAuthor) -> MessageAuthor {
+        self.direction.author(author)
+    }
+
+    fn encode(&self, codec: &mut ClockingCodec, dst: &mut BytesMut) -> io::Result<()> {
+        let (kind, frame) = match &self.frame {
+            ClockingFrameUnit::Content(content) => (0x04, content.clone()),
+            ClockingFrameUnit::Unfragmented(content) => (0x05, content.clone()),
+            frame => {
+                let mut encoded = BytesMut::new();
+                tokio_util::codec::Encoder::encode(codec, frame, &mut encoded)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let kind = match frame {
+                    ClockingFrameUnit::SuteraHeader(_) => 0x00,
+                    ClockingFrameUnit::SuteraStatus(_) => 0x01,
+                    ClockingFrameUnit::OneshotHeaders(_) => 0x02,
+                    _ => 0x03,
+                };
+                (kind, encoded.freeze())
+            }
+        };
+        dst.reserve(RECORD_HEADER_SIZE + frame.len());
+        dst.put_i64(self.timestamp.timestamp_micros());
+        dst.put_u8(CAPTURE_DIRECTION_MAP[self.direction]);
+        dst.put_u8(kind);
+        dst.put_u32(frame.len() as u32);
+        dst.put_slice(&frame);
+        Ok(())
+    }
+
+    fn decode(src: &mut BytesMut, author: MessageAuthor) -> Result<Option<Self>, CaptureError> {
+        if src.len() < RECORD_HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut buf = Cursor::new(&src[..]);
+        let timestamp = buf.get_i64();
+        let direction = buf.get_u8();
+        let kind = buf.get_u8();
+        let length = buf.get_u32() as usize;
+        if buf.remaining() < length {
+            return Ok(None);
+        }
+
+        let timestamp = Utc
+            .timestamp_micros(timestamp)
+            .single()
+            .ok_or(CaptureError::Broken)?;
+        let direction =
+            search_from_enum(*CAPTURE_DIRECTION_MAP, &direction).ok_or(CaptureError::Broken)?;
+        src.advance(RECORD_HEADER_SIZE);
+        let data = src.split_to(length).freeze();
+
+        let frame_author = direction.author(author);
+        let mut cursor = Cursor::new(&data[..]);
+        let frame = match kind {
+            0x00 => {
+                SuteraHeader::parse_frame(&mut cursor, &()).map(ClockingFrameUnit::SuteraHeader)
+            }
+            0x01 => {
+                SuteraStatus::parse_frame(&mut cursor, &()).map(ClockingFrameUnit::SuteraStatus)
+            }
+            0x02 => OneshotHeader::parse_frame(&mut cursor, &frame_author)
+                .map(ClockingFrameUnit::OneshotHeaders),
+            0x03 => EventHeader::parse_frame(&mut cursor, &frame_author)
+                .map(ClockingFrameUnit::EventHeader),
+            0x04 => Some(ClockingFrameUnit::Content(data.clone())),
+            0x05 => Some(ClockingFrameUnit::Unfragmented(data.clone())),
+            _ => None,
+        }
+        .ok_or(CaptureError::Broken)?;
+
+        Ok(Some(Self {
+            timestamp,
+            direction,
+            frame,
+        }))
+    }
+}
+
+/// [`ClockingConnection`][super::ClockingConnection]に取り付けて、フレームを記録するためのタップです。
+///
+/// 記録は別のタスクで書き出されるので、コネクションの読み書きを待たせることはありません。
+/// すべての`FrameCapture`がdropされると、書き出しのタスクは残りを書き終えてから終了します。
+#[derive(Clone)]
+pub struct FrameCapture {
+    author: MessageAuthor,
+    tx: mpsc::UnboundedSender<CapturedFrame>,
+}
+
+impl FrameCapture {
+    /// `writer`にキャプチャを書き出すタスクを起動します。
+    ///
+    /// `author`には、取り付けるコネクションと同じものを指定してください。
+    pub fn spawn<W: AsyncWrite + Unpin + Send + 'static>(
+        writer: W,
+        author: MessageAuthor,
+    ) -> (Self, JoinHandle<io::Result<()>>) {
+        let (tx, mut rx) = mpsc::unbounded_channel::<CapturedFrame>();
+        let handle = tokio::spawn(async move {
+            let mut writer = BufWriter::new(writer);
+            let mut codec = ClockingCodec::new(author);
+            let mut buffer = BytesMut::with_capacity(4096);
+
+            writer.write_all(CAPTURE_MAGIC).await?;
+            writer.write_u8(CAPTURE_FORMAT_VERSION).await?;
+            writer.write_u8(MESSAGE_AUTHOR_MAP[author]).await?;
+
+            while let Some(captured) = rx.recv().await {
+                buffer.clear();
+                captured.encode(&mut codec, &mut buffer)?;
+                // 溜まっている記録はまとめて書き出す
+                while let Ok(captured) = rx.try_recv() {
+                    captured.encode(&mut codec, &mut buffer)?;
+                }
+                writer.write_all(&buffer).await?;
+                writer.flush().await?;
+            }
+            Ok(())
+        });
+        (Self { author, tx }, handle)
+    }
+
+    /// `path`にファイルを作成して、キャプチャを書き出すタスクを起動します。
+    pub async fn create(
+        path: impl AsRef<Path>,
+        author: MessageAuthor,
+    ) -> io::Result<(Self, JoinHandle<io::Result<()>>)> {
+        Ok(Self::spawn(File::create(path).await?, author))
+    }
+
+    #[inline]
+    pub fn author(&self) -> MessageAuthor {
+        self.author
+    }
+
+    /// フレームを記録します。書き出しのタスクが終了している場合は何もしません。
+    #[inline]
+    pub fn record(&self, direction: CaptureDirection, frame: &ClockingFrameUnit) {
+        let _ = self.tx.send(CapturedFrame {
+            timestamp: Utc::now(),
+            direction,
+            frame: frame.clone(),
+        });
+    }
+}
+
+/// キャプチャファイルを読み込みます。
+pub struct CaptureReader<R: AsyncRead + Unpin> {
+    reader: R,
+    buffer: BytesMut,
+    author: MessageAuthor,
+}
+
+impl<R: AsyncRead + Unpin> CaptureReader<R> {
+    /// ヘッダーを読み込んで、キャプチャファイルであることを確かめます。
+    pub async fn new(mut reader: R) -> Result<Self, CaptureError> {
+        let mut header = [0u8; CAPTURE_HEADER_SIZE];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(CaptureError::NotACapture)
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if &header[..CAPTURE_MAGIC.len()] != CAPTURE_MAGIC {
+            return Err(CaptureError::NotACapture);
+        }
+        let version = header[CAPTURE_MAGIC.len()];
+        if version != CAPTURE_FORMAT_VERSION {
+            return Err(CaptureError::UnsupportedVersion(version));
+        }
+        let author = search_from_enum(*MESSAGE_AUTHOR_MAP, &header[CAPTURE_MAGIC.len() + 1])
+            .ok_or(CaptureError::Broken)?;
+
+        Ok(Self {
+            reader,
+            buffer: BytesMut::with_capacity(4096),
+            author,
+        })
+    }
+
+    /// キャプチャしたコネクションで受信したフレームの送信者です。
+    #[inline]
+    pub fn author(&self) -> MessageAuthor {
+        self.author
+    }
+
+    /// 次のフレームを読み込みます。ファイルの終わりに達した場合は`None`を返します。
+    pub async fn next(&mut self) -> Result<Option<CapturedFrame>, CaptureError> {
+        loop {
+            if let Some(captured) = CapturedFrame::decode(&mut self.buffer, self.author)? {
+                return Ok(Some(captured));
+            }
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(CaptureError::Broken);
+            }
+        }
+    }
+}
+
+impl CaptureReader<File> {
+    #[inline]
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, CaptureError> {
+        Self::new(File::open(path).await?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+    use tokio::io::duplex;
+
+    use crate::{
+        clocking::{
+            buffer::ContentHeader,
+            oneshot_headers::{OneshotStep, OneshotTypes},
+            sutera_status::SuteraStatusError,
+            ClockingConnection,
+        },
+        messaging::version::Version,
+    };
+
+    use super::*;
+
+    fn frames() -> Vec<(CaptureDirection, ClockingFrameUnit)> {
+        vec![
+            (
+                CaptureDirection::Read,
+                ClockingFrameUnit::SuteraHeader(SuteraHeader {
+                    version: Version::new(1, 2, 3),
+                }),
+            ),
+            (
+                CaptureDirection::Read,
+                ClockingFrameUnit::OneshotHeaders(OneshotHeader {
+                    step: OneshotStep::Request,
+                    message_type: OneshotTypes::Authentication_Login_Pull.into(),
+                    message_id: 0x1234,
+                }),
+            ),
+            (
+                CaptureDirection::Read,
+                ClockingFrameUnit::Content(Bytes::from_static(b"Wao!")),
+            ),
+            (
+                CaptureDirection::Read,
+                ClockingFrameUnit::Unfragmented(Bytes::from_static(b"garbage")),
+            ),
+            (
+                CaptureDirection::Written,
+                ClockingFrameUnit::SuteraStatus(SuteraStatus::Error(SuteraStatusError::BadRequest)),
+            ),
+            (
+                CaptureDirection::Written,
+                ClockingFrameUnit::OneshotHeaders(OneshotHeader {
+                    step: OneshotStep::Response,
+                    message_type: OneshotTypes::Authentication_Login_Pull.into(),
+                    message_id: 0x1234,
+                }),
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn capture_reflective() {
+        let (writer, reader) = duplex(64 * 1024);
+        let (capture, handle) = FrameCapture::spawn(writer, MessageAuthor::Client);
+        for (direction, frame) in frames() {
+            capture.record(direction, &frame);
+        }
+        drop(capture);
+        handle.await.unwrap().unwrap();
+
+        let mut reader = CaptureReader::new(reader).await.unwrap();
+        assert_eq!(reader.author(), MessageAuthor::Client);
+        let mut captured = vec![];
+        while let Some(frame) = reader.next().await.unwrap() {
+            captured.push((frame.direction, frame.frame));
+        }
+        assert_eq!(captured, frames());
+    }
+
+    #[tokio::test]
+    async fn capture_connection_tap() {
+        let (server, client) = duplex(64 * 1024);
+        let (capture_writer, capture_reader) = duplex(64 * 1024);
+        let (capture, handle) = FrameCapture::spawn(capture_writer, MessageAuthor::Client);
+
+        let mut server = ClockingConnection::new(server, MessageAuthor::Client);
+        let mut client = ClockingConnection::new(client, MessageAuthor::Server);
+        server.set_capture(Some(capture));
+
+        let header = SuteraHeader {
+            version: Version::new(1, 2, 3),
+        };
+        let oneshot = OneshotHeader {
+            step: OneshotStep::Request,
+            message_type: OneshotTypes::Authentication_Login_Pull.into(),
+            message_id: 0x1234,
+        };
+        client
+            .write_message(
+                header.clone(),
+                None,
+                ContentHeader::Oneshot(oneshot.clone()),
+                Bytes::from_static(b"Wao!"),
+            )
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            server.read_frame().await.unwrap().unwrap();
+        }
+        drop(server);
+        handle.await.unwrap().unwrap();
+
+        let mut reader = CaptureReader::new(capture_reader).await.unwrap();
+        let mut captured = vec![];
+        while let Some(frame) = reader.next().await.unwrap() {
+            captured.push((frame.direction, frame.frame));
+        }
+        assert_eq!(
+            captured,
+            vec![
+                (
+                    CaptureDirection::Read,
+                    ClockingFrameUnit::SuteraHeader(header)
+                ),
+                (
+                    CaptureDirection::Read,
+                    ClockingFrameUnit::OneshotHeaders(oneshot)
+                ),
+                (
+                    CaptureDirection::Read,
+                    ClockingFrameUnit::Content(Bytes::from_static(b"Wao!"))
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_non_capture() {
+        assert!(matches!(
+            CaptureReader::new(&b"SuteraVR"[..]).await,
+            Err(CaptureError::NotACapture)
+        ));
+        assert!(matches!(
+            CaptureReader::new(&b"NotSuteraCapture"[..]).await,
+            Err(CaptureError::NotACapture)
+        ));
+    }
+}
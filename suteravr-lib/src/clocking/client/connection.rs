@@ -0,0 +1,679 @@
+//! 接続を見張り、切れた場合は接続し直すタスクです。
+
+use std::{collections::HashMap, future::Future, io, sync::Arc, time::Duration};
+
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    time,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{self, pki_types::ServerName, ClientConfig},
+    TlsConnector,
+};
+
+use crate::{
+    clocking::{
+        buffer::{ContentHeader, FrameBuffer},
+        compression::{ContentCompression, ContentEncoding},
+        messages::{
+            EventMessage as _, HealthCheckPushOneshot, NegotiateCompressionOneshot,
+            OneshotMessage as _, PlayerJoinedEvent, PlayerLeftEvent, PushPlayerMoveEvent,
+            ReceiveChatMessageEvent, ResumeOneshot,
+        },
+        oneshot_headers::OneshotStep,
+        schemas::oneshot::{
+            compression::{CompressionRequest, CompressionResponse},
+            login::{ResumeRequest, ResumeResponse},
+        },
+        sutera_status::{SuteraStatus, SuteraStatusError},
+        traits::MessageAuthor,
+        ClockingConnection, ClockingFramingError,
+    },
+    debug, error, info,
+    messaging::{id::MessageId, version::VersionCompatibility},
+    util::logger::Logger,
+    warn, SCHEMA_VERSION,
+};
+
+use super::{
+    error::ClockingClientError,
+    reconnect::OfflineQueue,
+    requests::{
+        oneshot_response, oneshot_response_failed, send_oneshot, EventMessage, OneshotRequest,
+        OneshotResponse, Request, Response,
+    },
+    ClientEvent, ClientOptions, ClientState, LOGIN_TIMEOUT,
+};
+
+/// TCPの接続とTLSのハンドシェイクを待つ時間です。
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 1つの接続が終わった理由です。
+enum SessionEnd {
+    /// 終了を求められた
+    Shutdown,
+    /// 接続が切れた
+    Lost(String),
+    /// 使えるようになる前に切れた。接続に失敗したものとして数える
+    Unusable(String),
+}
+
+/// 証明書を受け入れられなかった場合は、接続し直しても結果は変わりません。
+fn is_certificate_error(e: &ClockingClientError) -> bool {
+    let ClockingClientError::ConnectingError(e) = e else {
+        return false;
+    };
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<rustls::Error>())
+        .is_some_and(|e| matches!(e, rustls::Error::InvalidCertificate(_)))
+}
+
+async fn connect_tls(
+    connector: &TlsConnector,
+    name: ServerName<'static>,
+    addr: &str,
+) -> Result<TlsStream<TcpStream>, ClockingClientError> {
+    let connecting = async {
+        let stream = TcpStream::connect(addr).await?;
+        connector.connect(name, stream).await
+    };
+    time::timeout(CONNECT_TIMEOUT, connecting)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+        .map_err(ClockingClientError::ConnectingError)
+}
+
+/// 書き込めなかったリクエストです。接続し直した後に送り直せるよう、エラーと一緒に返します。
+struct WriteError {
+    request: Request,
+    error: ClockingFramingError,
+}
+
+async fn write_oneshot(
+    connection: &mut ClockingConnection<TlsStream<TcpStream>>,
+    oneshot: &OneshotRequest,
+) -> Result<(), ClockingFramingError> {
+    connection
+        .write_message(
+            oneshot.sutera_header.clone(),
+            oneshot.sutera_status.clone(),
+            ContentHeader::Oneshot(oneshot.oneshot_header.clone()),
+            oneshot.payload.clone(),
+        )
+        .await
+}
+
+/// リクエストを接続に書き込みます。
+async fn write_request<L: Logger>(
+    connection: &mut ClockingConnection<TlsStream<TcpStream>>,
+    reply_senders: &mut HashMap<MessageId, oneshot::Sender<Response>>,
+    request: Request,
+    logger: &L,
+) -> Result<(), WriteError> {
+    match request {
+        Request::Oneshot(oneshot) => {
+            if let Err(error) = write_oneshot(connection, &oneshot).await {
+                return Err(WriteError {
+                    request: Request::Oneshot(oneshot),
+                    error,
+                });
+            }
+        }
+        Request::OneshotWithReply(oneshot, sender) => {
+            // 待つのをやめたリクエストが残っていれば、ここで片付ける
+            reply_senders.retain(|_, sender| !sender.is_closed());
+            let message_id = oneshot.oneshot_header.message_id;
+            if reply_senders.contains_key(&message_id) {
+                error!(*logger, "MessageId {:?} is already occupied!", message_id);
+                // 先に送ったリクエストの返答と区別できないので、送らずに断る
+                let _ = sender.send(Response::Failed(Box::new(
+                    ClockingClientError::DuplicateMessageId(message_id),
+                )));
+                return Ok(());
+            }
+            if let Err(error) = write_oneshot(connection, &oneshot).await {
+                return Err(WriteError {
+                    request: Request::OneshotWithReply(oneshot, sender),
+                    error,
+                });
+            }
+            reply_senders.insert(message_id, sender);
+        }
+        Request::Event(event) => {
+            let written = connection
+                .write_message(
+                    event.sutera_header.clone(),
+                    None,
+                    ContentHeader::Event(event.event_header.clone()),
+                    event.payload.clone(),
+                )
+                .await;
+            if let Err(error) = written {
+                return Err(WriteError {
+                    request: Request::Event(event),
+                    error,
+                });
+            }
+        }
+        Request::EnableCompression(encoding) => {
+            connection.set_compression(ContentCompression {
+                send: Some(encoding),
+                ..*connection.compression()
+            });
+        }
+        Request::CancelOneshot(message_id) => {
+            if reply_senders.remove(&message_id).is_some() {
+                debug!(*logger, "Oneshot request {:?} is cancelled.", message_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// サーバーからのOneshotへの返答を組み立てます。
+fn answer_oneshot<L: Logger>(request: OneshotResponse, logger: &L) -> OneshotRequest {
+    match request.oneshot_header.message_type {
+        t if t.is::<HealthCheckPushOneshot>() => {
+            oneshot_response::<HealthCheckPushOneshot>(request, ())
+        }
+        _ => {
+            error!(
+                *logger,
+                "Unknown or unimplemented oneshot message type: {:?}",
+                request.oneshot_header.message_type
+            );
+            oneshot_response_failed(
+                request,
+                SuteraStatus::Error(SuteraStatusError::Unimplemented),
+            )
+        }
+    }
+}
+
+/// 受け取ったイベントを[`ClientEvent`]に読み替えます。
+///
+/// 読めなかったイベントは、接続を切らずに[`ClientEvent::Unknown`]として渡します。
+fn decode_event<L: Logger>(event: EventMessage, logger: &L) -> ClientEvent {
+    let message_type = event.event_header.message_type;
+    let decoded = match message_type {
+        t if t.is::<ReceiveChatMessageEvent>() => ReceiveChatMessageEvent::decode(&event.payload)
+            .map(|entry| ClientEvent::ChatMessage {
+                sender: entry.sender,
+                message: entry.message,
+            }),
+        t if t.is::<PlayerJoinedEvent>() => PlayerJoinedEvent::decode(&event.payload)
+            .map(|joined| ClientEvent::PlayerJoined(joined.joined_player)),
+        t if t.is::<PlayerLeftEvent>() => PlayerLeftEvent::decode(&event.payload)
+            .map(|left| ClientEvent::PlayerLeft(left.left_player)),
+        t if t.is::<PushPlayerMoveEvent>() => {
+            PushPlayerMoveEvent::decode(&event.payload).map(|moved| ClientEvent::PlayerMoved {
+                player: moved.player,
+                now: moved.now,
+            })
+        }
+        _ => return ClientEvent::Unknown(event),
+    };
+    decoded.unwrap_or_else(|e| {
+        warn!(
+            *logger,
+            "Failed to decode the event {:?}: {:?}", message_type, e
+        );
+        ClientEvent::Unknown(event)
+    })
+}
+
+/// 接続が切れても、諦めるまで接続し直し続けます。
+pub(super) struct Supervisor<L: Logger> {
+    pub logger: L,
+    pub options: ClientOptions,
+    pub state: ClientState,
+    pub events: mpsc::Sender<ClientEvent>,
+    pub send_rx: mpsc::Receiver<Request>,
+    pub shutdown_rx: oneshot::Receiver<()>,
+    pub queue: OfflineQueue,
+}
+
+impl<L: Logger + Clone + Send + Sync + 'static> Supervisor<L> {
+    /// 受け取る側がいなくなっていても、接続は続けます。
+    async fn emit(&self, event: ClientEvent) {
+        let _ = self.events.send(event).await;
+    }
+
+    /// 終了を求められるまで接続を続けます。諦めた場合は[`ClientEvent::GaveUp`]を送ってから終わります。
+    pub async fn run(
+        self,
+        config: ClientConfig,
+        name: String,
+        addr: String,
+    ) -> Result<(), ClockingClientError> {
+        let events = self.events.clone();
+        let result = self.supervise(config, name, addr).await;
+        if let Err(e) = &result {
+            let _ = events
+                .send(ClientEvent::GaveUp {
+                    reason: e.to_string(),
+                })
+                .await;
+        }
+        result
+    }
+
+    async fn supervise(
+        mut self,
+        config: ClientConfig,
+        name: String,
+        addr: String,
+    ) -> Result<(), ClockingClientError> {
+        let policy = self.options.reconnect;
+        let connector = TlsConnector::from(Arc::new(config));
+        let dnsname = ServerName::try_from(name.clone())
+            .map_err(|_| ClockingClientError::InvalidServerName(name.clone()))?;
+
+        // 続けて接続に失敗した回数
+        let mut failures = 0u32;
+        // 一度でも接続できていれば、次の接続では前のセッションを引き継ぐ
+        let mut established = false;
+        loop {
+            self.emit(ClientEvent::Connecting { failures }).await;
+            info!(self.logger, "Connecting to {}({}) ...", name, addr);
+
+            let Some(connected) = self
+                .offline(connect_tls(&connector, dnsname.clone(), &addr))
+                .await
+            else {
+                return Ok(());
+            };
+            let reason = match connected {
+                Ok(stream) => {
+                    // TLSまでは受け付けてすぐに切るサーバーにも、間隔を空けて接続し直す
+                    let reason = match self.run_session(stream, established).await {
+                        Ok(SessionEnd::Shutdown) => return Ok(()),
+                        Ok(SessionEnd::Lost(reason)) => {
+                            failures = 0;
+                            established = true;
+                            reason
+                        }
+                        Ok(SessionEnd::Unusable(reason)) => {
+                            failures += 1;
+                            reason
+                        }
+                        Err(e) => {
+                            failures += 1;
+                            e.to_string()
+                        }
+                    };
+                    warn!(self.logger, "Connection lost: {}", reason);
+                    self.emit(ClientEvent::Lost {
+                        reason: reason.clone(),
+                    })
+                    .await;
+                    reason
+                }
+                Err(e) if is_certificate_error(&e) => return Err(e),
+                Err(e) => {
+                    failures += 1;
+                    warn!(self.logger, "Failed to connect: {}", e);
+                    e.to_string()
+                }
+            };
+
+            if !policy.should_retry(failures) {
+                return Err(ClockingClientError::GaveUp { failures, reason });
+            }
+            let delay = policy.next_delay(failures.saturating_sub(1));
+            info!(self.logger, "Reconnecting in {:?} ...", delay);
+            if self.offline(time::sleep(delay)).await.is_none() {
+                return Ok(());
+            }
+            let dropped = self.queue.take_dropped();
+            if dropped > 0 {
+                warn!(
+                    self.logger,
+                    "Dropped {} request(s) while the connection was lost.", dropped
+                );
+            }
+        }
+    }
+
+    /// `future`を待つ間に送られたリクエストを溜めておきます。終了を求められた場合は`None`を返します。
+    async fn offline<F: Future>(&mut self, future: F) -> Option<F::Output> {
+        tokio::pin!(future);
+        loop {
+            tokio::select! {
+                output = &mut future => return Some(output),
+                Some(request) = self.send_rx.recv() => self.queue.push(request),
+                _shutdown = &mut self.shutdown_rx => return None,
+            }
+        }
+    }
+
+    /// 1つの接続で、切れるまでメッセージをやり取りします。
+    ///
+    /// `resume`の場合は、溜めておいたリクエストを送る前に前のセッションを引き継ぎます。
+    async fn run_session(
+        &mut self,
+        stream: TlsStream<TcpStream>,
+        resume: bool,
+    ) -> Result<SessionEnd, ClockingClientError> {
+        let logger = self.logger.clone();
+        let state = self.state.clone();
+        let mut reply_senders = HashMap::<MessageId, oneshot::Sender<Response>>::new();
+
+        info!(logger, "Connection established!");
+
+        let mut connection =
+            ClockingConnection::with_limits(stream, MessageAuthor::Server, self.options.limits);
+        let mut frame_buffer = FrameBuffer::new(logger.clone());
+        // サーバーへの返答や圧縮の切り替えなど、この接続の中でだけ意味を持つリクエスト
+        let (reply, mut internal_rx) = mpsc::channel::<Request>(32);
+
+        // 圧縮されたContentは交渉の前から受け付けておき、サーバーが有効にした直後のレスポンスも読めるようにする
+        connection.set_compression(ContentCompression {
+            accept: true,
+            ..Default::default()
+        });
+        let negotiation = OneshotRequest::typed::<NegotiateCompressionOneshot>(
+            state.next_message_id(),
+            CompressionRequest::supported(),
+        );
+        let (negotiation_tx, negotiation_rx) = oneshot::channel::<Response>();
+        reply_senders.insert(negotiation.oneshot_header.message_id, negotiation_tx);
+        connection
+            .write_message(
+                negotiation.sutera_header,
+                None,
+                ContentHeader::Oneshot(negotiation.oneshot_header),
+                negotiation.payload,
+            )
+            .await?;
+        let negotiation_logger = logger.clone();
+        let enable_compression = reply.clone();
+        tokio::spawn(async move {
+            let Ok(Response::Oneshot(response)) = negotiation_rx.await else {
+                return Ok(());
+            };
+            // 古いサーバーは交渉に対応していないので、圧縮せずに続ける
+            if response.sutera_status.is_error() {
+                info!(
+                    negotiation_logger,
+                    "Compression is not available: {:?}", response.sutera_status
+                );
+                return Ok(());
+            }
+            match NegotiateCompressionOneshot::decode_response(&response.payload)? {
+                CompressionResponse::Enabled(code) => {
+                    let Some(encoding) = ContentEncoding::from_code(code) else {
+                        warn!(negotiation_logger, "Unknown compression: {}", code);
+                        return Ok(());
+                    };
+                    info!(negotiation_logger, "Compression enabled: {:?}", encoding);
+                    enable_compression
+                        .send(Request::EnableCompression(encoding))
+                        .await
+                        .map_err(ClockingClientError::CannotSendRequest)?;
+                }
+                CompressionResponse::Disabled => {
+                    info!(negotiation_logger, "Compression is disabled by the server.");
+                }
+            }
+            Ok::<(), ClockingClientError>(())
+        });
+
+        // 前のセッションを引き継ぐまでは、溜めておいたリクエストを送らない
+        let (ready_tx, mut ready_rx) = oneshot::channel::<bool>();
+        let mut ready = false;
+        // サーバーから何も届かないまま切れた場合は、使える接続ではなかったとみなす
+        let mut responded = false;
+        let resume_token = if resume { state.resume_token() } else { None };
+        let resuming = resume_token.is_some();
+        match resume_token {
+            Some(resume_token) => {
+                let resume_logger = logger.clone();
+                let send = reply.clone();
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let result = send_oneshot::<ResumeOneshot, _>(
+                        &resume_logger,
+                        send,
+                        state.next_message_id(),
+                        ResumeRequest { resume_token },
+                        LOGIN_TIMEOUT,
+                    )
+                    .await;
+                    let resumed = match result {
+                        Ok(ResumeResponse::Ok(player_id, resume_token)) => {
+                            info!(resume_logger, "Session resumed as player {}", player_id);
+                            state.set_session(player_id, resume_token);
+                            true
+                        }
+                        // 猶予期間を過ぎたなどで断られた場合は、参加し直すしかない
+                        Err(e @ ClockingClientError::ErrorStatus { .. }) => {
+                            warn!(resume_logger, "Failed to resume the session: {}", e);
+                            state.clear_session();
+                            false
+                        }
+                        Err(e) => {
+                            warn!(resume_logger, "Failed to resume the session: {}", e);
+                            false
+                        }
+                    };
+                    let _ = ready_tx.send(resumed);
+                });
+            }
+            None => {
+                let _ = ready_tx.send(false);
+            }
+        }
+
+        let end = loop {
+            tokio::select! {
+                Some(request) = internal_rx.recv() => {
+                    // この接続の中でだけ意味を持つので、送れなければ捨てる
+                    if let Err(e) = write_request(&mut connection, &mut reply_senders, request, &logger).await {
+                        break SessionEnd::Lost(e.error.to_string());
+                    }
+                },
+                Some(request) = self.queue.next(&mut self.send_rx), if ready => {
+                    if let Err(WriteError { request, error }) = write_request(&mut connection, &mut reply_senders, request, &logger).await {
+                        // 接続し直した後に、最初に送り直す
+                        self.queue.push_front(request);
+                        break SessionEnd::Lost(error.to_string());
+                    }
+                },
+                resumed = &mut ready_rx, if !ready => {
+                    ready = true;
+                    if let Ok(resumed) = resumed {
+                        // 溜めておいたリクエストは前のセッションのものなので、ログインしていない接続には送らない
+                        if resuming && !resumed && !self.queue.is_empty() {
+                            warn!(logger, "Dropped {} request(s) queued while offline because the session could not be resumed.", self.queue.clear());
+                        }
+                        if !self.queue.is_empty() {
+                            info!(logger, "Sending {} request(s) queued while offline.", self.queue.len());
+                        }
+                        self.emit(ClientEvent::Connected { resumed }).await;
+                    }
+                },
+                read = connection.read_frame() => {
+                    let payload = match read {
+                        Ok(Some(payload)) => payload,
+                        Ok(None) => {
+                            break SessionEnd::Lost("The server closed the connection.".to_string());
+                        }
+                        Err(e) => {
+                            warn!(logger, "{}", e);
+                            break SessionEnd::Lost(e.to_string());
+                        }
+                    };
+                    let Some(received) = frame_buffer.append(payload, MessageAuthor::Server) else {
+                        continue;
+                    };
+                    responded = true;
+                    let Some(sutera_status) = received.sutera_status else {
+                        // frame_bufferに不具合があるかもしれないので、読み直せるよう接続し直す
+                        let e = ClockingClientError::MissingSuteraStatus;
+                        error!(logger, "{}", e);
+                        break SessionEnd::Lost(e.to_string());
+                    };
+                    let received_version = received.sutera_header.version;
+                    let previous_version = state.server_version.lock().unwrap().replace(received_version);
+                    if previous_version != Some(received_version) {
+                        match SCHEMA_VERSION.compatibility_with(&received_version) {
+                            VersionCompatibility::Exact => {},
+                            VersionCompatibility::Compatible => {
+                                warn!(logger, "Server schema version {} is not exactly matched with {}.", received_version, SCHEMA_VERSION);
+                            },
+                            VersionCompatibility::Incompatible => {
+                                error!(logger, "Server schema version {} is not supported by this client ({}).", received_version, SCHEMA_VERSION);
+                            },
+                        }
+                    }
+                    match received.content_header {
+                        ContentHeader::Event(event_header) => {
+                            let event = decode_event(EventMessage::new(
+                                received.sutera_header,
+                                event_header,
+                                received.payload,
+                            ), &logger);
+                            self.emit(event).await;
+                        }
+                        ContentHeader::Oneshot(oneshot_header) => {
+                            let step = oneshot_header.step;
+                            let message_id = oneshot_header.message_id;
+                            let response = OneshotResponse::new(
+                                received.sutera_header,
+                                sutera_status,
+                                oneshot_header,
+                                received.payload,
+                            );
+                            // 種類が分からなくても、Responseであれば自分が送ったリクエストへの返答である
+                            if step != OneshotStep::Response {
+                                // internal_rxはこのループでしか読まないので、溜まって詰まらないよう直接書き込む
+                                let answer = Request::Oneshot(answer_oneshot(response, &logger));
+                                if let Err(e) = write_request(&mut connection, &mut reply_senders, answer, &logger).await {
+                                    break SessionEnd::Lost(e.error.to_string());
+                                }
+                                continue;
+                            }
+                            let Some(sender) = reply_senders.remove(&message_id) else {
+                                // タイムアウトなどで、既に待つのをやめたリクエストへの返答
+                                debug!(logger, "Discarded a late response for {:?}.", message_id);
+                                continue;
+                            };
+                            if sender.send(Response::Oneshot(response)).is_err() {
+                                debug!(logger, "Discarded a late response for {:?}.", message_id);
+                            }
+                        },
+                    }
+                },
+                _shutdown = &mut self.shutdown_rx => {
+                    break SessionEnd::Shutdown;
+                }
+            }
+        };
+
+        // 返答を待っているリクエストは、Senderを捨てることで打ち切られる
+        if !reply_senders.is_empty() {
+            info!(
+                logger,
+                "Cancelling {} pending oneshot request(s).",
+                reply_senders.len()
+            );
+            reply_senders.clear();
+        }
+
+        if let Err(e) = connection.shutdown_stream().await {
+            debug!(logger, "Failed to shut down the stream: {}", e);
+        }
+        Ok(match end {
+            SessionEnd::Lost(reason) if !(ready && responded) => SessionEnd::Unusable(reason),
+            end => end,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+            ServerConfig,
+        },
+        TlsAcceptor,
+    };
+
+    use crate::{
+        clocking::client::{
+            allow_unknown_cert::AllowUnknownCertVerifier, reconnect::ReconnectPolicy,
+            ClockingClient,
+        },
+        util::logger::EnvLogger,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn back_off_when_dropped_before_ready() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(cert.serialize_der().unwrap())],
+                PrivateKeyDer::from(PrivatePkcs8KeyDer::from(cert.serialize_private_key_der())),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                // ハンドシェイクだけ済ませて、何も返さずに切る
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(AllowUnknownCertVerifier::new())
+            .with_no_client_auth();
+        let options = ClientOptions::default().with_reconnect(ReconnectPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            jitter: 0.0,
+            max_retries: Some(2),
+            ..Default::default()
+        });
+        let (client, mut events) = ClockingClient::connect(
+            config,
+            "localhost",
+            addr.to_string(),
+            options,
+            EnvLogger {
+                target: "back_off_when_dropped_before_ready".to_string(),
+            },
+        );
+
+        let mut failures = Vec::new();
+        let gave_up = time::timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.recv().await {
+                match event {
+                    ClientEvent::Connecting { failures: count } => failures.push(count),
+                    ClientEvent::GaveUp { .. } => return true,
+                    _ => {}
+                }
+            }
+            false
+        })
+        .await
+        .unwrap();
+        assert!(gave_up);
+        // TLSまで通っても、使えないまま切れた接続は失敗として数える
+        assert_eq!(failures, vec![0, 1, 2]);
+        client.shutdown().await.unwrap();
+    }
+}
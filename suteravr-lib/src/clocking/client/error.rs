@@ -0,0 +1,61 @@
+use crate::{
+    clocking::{
+        schemas::oneshot::error_details::ErrorDetails, sutera_status::SuteraStatus,
+        ClockingFramingError,
+    },
+    messaging::id::MessageId,
+};
+use alkahest::DeserializeError;
+use thiserror::Error;
+use tokio::sync::{mpsc::error::SendError, oneshot};
+
+use super::requests::{Request, Response};
+
+#[derive(Debug, Error)]
+pub enum ClockingClientError {
+    #[error("ClockingConnection is not initialized or already closed.")]
+    ConnectionNotFound,
+    #[error("There is no session to resume.")]
+    NoSession,
+    #[error("The server rejected the login.")]
+    LoginRejected,
+    #[error("The response cannot be sent.")]
+    CannotSendResponse(SendError<Response>),
+    #[error("The request cannot be sent.")]
+    CannotSendRequest(SendError<Request>),
+    #[error(transparent)]
+    ConnectingError(std::io::Error),
+    #[error("{0:?} is not a valid server name.")]
+    InvalidServerName(String),
+    #[error("Gave up connecting after {failures} failed attempt(s): {reason}")]
+    GaveUp { failures: u32, reason: String },
+    #[error(transparent)]
+    ShutdownRecvError(#[from] oneshot::error::RecvError),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    FramingError(#[from] ClockingFramingError),
+    #[error("Failed to deserialize the message.")]
+    DeserializeError(DeserializeError),
+    #[error("The server responded with an error: {status:?} ({details:?})")]
+    ErrorStatus {
+        status: SuteraStatus,
+        details: Option<ErrorDetails>,
+    },
+    #[error("The oneshot request {0:?} timed out.")]
+    Timeout(MessageId),
+    #[error("The oneshot request {0:?} was cancelled because the connection was closed.")]
+    OneshotCancelled(MessageId),
+    #[error("MessageId {0:?} is already used by another oneshot request.")]
+    DuplicateMessageId(MessageId),
+    #[error("The oneshot request {0:?} received a response which is not a oneshot.")]
+    UnexpectedResponse(MessageId),
+    #[error("The received message doesn't contain sutera_status.")]
+    MissingSuteraStatus,
+}
+
+impl From<DeserializeError> for ClockingClientError {
+    fn from(e: DeserializeError) -> Self {
+        Self::DeserializeError(e)
+    }
+}
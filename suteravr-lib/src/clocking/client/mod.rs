@@ -0,0 +1,429 @@
+//! Godotに依存しない、Clocking-serverのクライアントです。
+//!
+//! Godotのクライアントのほか、負荷試験のボットやCLIツール、結合テストから使います。
+//!
+//! ```no_run
+//! use suteravr_lib::{
+//!     clocking::client::{ClientEvent, ClientOptions, ClockingClient},
+//!     util::logger::EnvLogger,
+//! };
+//! # async fn run(config: tokio_rustls::rustls::ClientConfig) {
+//! let logger = EnvLogger {
+//!     target: "client".to_string(),
+//! };
+//! let (client, mut events) = ClockingClient::connect(
+//!     config,
+//!     "localhost",
+//!     "127.0.0.1:3501",
+//!     ClientOptions::default(),
+//!     logger,
+//! );
+//! while let Some(event) = events.recv().await {
+//!     if let ClientEvent::Connected { resumed: false } = event {
+//!         client.login(1, "<join token>".to_string()).await.unwrap();
+//!     }
+//! }
+//! # }
+//! ```
+
+pub mod allow_unknown_cert;
+mod connection;
+pub mod error;
+pub mod pinned_cert;
+pub mod reconnect;
+pub mod requests;
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Stream;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::{JoinError, JoinHandle},
+};
+use tokio_rustls::rustls::ClientConfig;
+
+use crate::{
+    clocking::{
+        messages::{
+            LoginOneshot, OneshotMessage, PubPlayerMoveEvent, ResumeOneshot, SendChatMessageOneshot,
+        },
+        schemas::{
+            event::player_move::PubPlayerMove,
+            oneshot::{
+                chat_entry::SendChatMessageRequest,
+                login::{LoginRequest, LoginResponse, ResumeRequest, ResumeResponse},
+            },
+        },
+        ConnectionLimits,
+    },
+    info,
+    messaging::{
+        id::{InstanceId, MessageId, PlayerId},
+        player::{StandingTransform, StandingTransformEncoder},
+        version::Version,
+    },
+    util::logger::Logger,
+    warn,
+};
+
+use self::{
+    connection::Supervisor,
+    error::ClockingClientError,
+    reconnect::{OfflineQueue, ReconnectPolicy},
+    requests::{send_oneshot, EventMessage, Request},
+};
+
+/// Oneshotの返答を待つ既定の時間です。
+pub const DEFAULT_ONESHOT_TIMEOUT: Duration = Duration::from_secs(10);
+/// ログインはサーバー側でインスタンスへの参加を伴うので、長めに待ちます。
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 接続の設定です。
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClientOptions {
+    pub limits: ConnectionLimits,
+    pub reconnect: ReconnectPolicy,
+}
+
+impl ClientOptions {
+    pub fn with_limits(self, limits: ConnectionLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    pub fn with_reconnect(self, reconnect: ReconnectPolicy) -> Self {
+        Self { reconnect, ..self }
+    }
+}
+
+/// 接続をまたいで共有する状態です。
+///
+/// [`ClockingClient::connect_with_state`]に渡せば、接続を作り直しても同じセッションを引き継げます。
+#[derive(Debug, Clone, Default)]
+pub struct ClientState {
+    server_version: Arc<Mutex<Option<Version>>>,
+    message_id_dispatch: Arc<AtomicU64>,
+    player_id: Arc<Mutex<Option<PlayerId>>>,
+    /// 接続し直したときに、同じプレイヤーとしてインスタンスに戻るために使います。
+    resume_token: Arc<Mutex<Option<String>>>,
+}
+
+impl ClientState {
+    /// 接続先のサーバーが使っているスキーマバージョンです。まだ何も受け取っていなければ`None`です。
+    pub fn server_version(&self) -> Option<Version> {
+        *self.server_version.lock().unwrap()
+    }
+
+    pub fn player_id(&self) -> Option<PlayerId> {
+        *self.player_id.lock().unwrap()
+    }
+
+    pub fn resume_token(&self) -> Option<String> {
+        self.resume_token.lock().unwrap().clone()
+    }
+
+    pub fn next_message_id(&self) -> MessageId {
+        self.message_id_dispatch.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn set_session(&self, player_id: PlayerId, resume_token: String) {
+        self.player_id.lock().unwrap().replace(player_id);
+        self.resume_token.lock().unwrap().replace(resume_token);
+    }
+
+    fn clear_session(&self) {
+        self.player_id.lock().unwrap().take();
+        self.resume_token.lock().unwrap().take();
+    }
+}
+
+/// [`ClockingClient`]が受け取ったイベントと、接続の状態の変化です。
+#[derive(Debug)]
+pub enum ClientEvent {
+    /// 接続を試みる前です。`failures`は続けて失敗した回数です。
+    Connecting {
+        failures: u32,
+    },
+    /// 接続できて、リクエストを送れるようになりました。
+    ///
+    /// 接続し直した後に前のセッションを引き継げなかった場合、`resumed`は`false`になります。
+    /// その場合は[`ClockingClient::login`]で参加し直してください。
+    /// 接続が切れている間に溜めておいたリクエストは、送らずに捨てられます。
+    Connected {
+        resumed: bool,
+    },
+    /// 接続が切れました。この後、自動で接続し直します。
+    Lost {
+        reason: String,
+    },
+    /// 接続し直すのを諦めました。これが最後のイベントです。
+    GaveUp {
+        reason: String,
+    },
+    ChatMessage {
+        sender: PlayerId,
+        message: String,
+    },
+    PlayerJoined(PlayerId),
+    PlayerLeft(PlayerId),
+    PlayerMoved {
+        player: PlayerId,
+        now: StandingTransform,
+    },
+    /// このクライアントが解釈しない種類のイベントです。
+    Unknown(EventMessage),
+}
+
+/// [`ClientEvent`]を受け取ります。
+///
+/// 読まずに溜め込むと、受信が止まることに注意してください。
+pub struct ClientEvents {
+    rx: mpsc::Receiver<ClientEvent>,
+}
+
+impl ClientEvents {
+    /// 次のイベントを待ちます。クライアントが終了した後は`None`を返します。
+    pub async fn recv(&mut self) -> Option<ClientEvent> {
+        self.rx.recv().await
+    }
+}
+
+impl Stream for ClientEvents {
+    type Item = ClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+type SupervisorHandle = JoinHandle<Result<(), ClockingClientError>>;
+
+/// Clocking-serverとの接続です。
+///
+/// 接続が切れた場合は[`ClientOptions::reconnect`]に従って接続し直し、その間に送ったリクエストは
+/// 接続し直した後に送ります。クローンはすべて同じ接続を指し、すべて捨てると接続を閉じます。
+#[derive(Clone)]
+pub struct ClockingClient<L: Logger> {
+    logger: L,
+    state: ClientState,
+    send_tx: mpsc::Sender<Request>,
+    shutdown_tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    handle: Arc<Mutex<Option<SupervisorHandle>>>,
+    encoder: Arc<Mutex<StandingTransformEncoder>>,
+}
+
+impl<L: Logger + Clone + Send + Sync + 'static> ClockingClient<L> {
+    /// 接続を見張るタスクを起動します。Tokioのランタイムの中で呼んでください。
+    ///
+    /// 接続の状態は、返した[`ClientEvents`]に[`ClientEvent::Connecting`]などとして届きます。
+    pub fn connect(
+        config: ClientConfig,
+        name: impl Into<String>,
+        addr: impl Into<String>,
+        options: ClientOptions,
+        logger: L,
+    ) -> (Self, ClientEvents) {
+        Self::connect_with_state(config, name, addr, options, ClientState::default(), logger)
+    }
+
+    /// 前の接続の[`ClientState`]を引き継いで接続します。
+    pub fn connect_with_state(
+        config: ClientConfig,
+        name: impl Into<String>,
+        addr: impl Into<String>,
+        options: ClientOptions,
+        state: ClientState,
+        logger: L,
+    ) -> (Self, ClientEvents) {
+        info!(logger, "Making connection...");
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let (send_tx, send_rx) = mpsc::channel::<Request>(32);
+        let (events_tx, events_rx) = mpsc::channel::<ClientEvent>(256);
+
+        let supervisor = Supervisor {
+            logger: logger.clone(),
+            options,
+            state: state.clone(),
+            events: events_tx,
+            send_rx,
+            shutdown_rx,
+            queue: OfflineQueue::new(options.reconnect.max_queued_requests),
+        };
+        let handle_logger = logger.clone();
+        let (name, addr) = (name.into(), addr.into());
+        let handle = tokio::spawn(async move {
+            let result = supervisor.run(config, name, addr).await;
+            match &result {
+                Ok(_) => info!(handle_logger, "Connection successfully finished."),
+                Err(e) => warn!(handle_logger, "Connection failed: {}", e),
+            }
+            result
+        });
+
+        let client = Self {
+            logger,
+            state,
+            send_tx,
+            shutdown_tx: Arc::new(Mutex::new(Some(shutdown_tx))),
+            handle: Arc::new(Mutex::new(Some(handle))),
+            encoder: Arc::new(Mutex::new(StandingTransformEncoder::new())),
+        };
+        (client, ClientEvents { rx: events_rx })
+    }
+
+    #[inline]
+    pub fn state(&self) -> &ClientState {
+        &self.state
+    }
+
+    #[inline]
+    pub fn player_id(&self) -> Option<PlayerId> {
+        self.state.player_id()
+    }
+
+    #[inline]
+    pub fn server_version(&self) -> Option<Version> {
+        self.state.server_version()
+    }
+
+    /// `M`のリクエストを送り、`timeout`までに返ってきたレスポンスを返します。
+    pub async fn send_oneshot<M: OneshotMessage>(
+        &self,
+        request: M::Request,
+        timeout: Duration,
+    ) -> Result<M::Response, ClockingClientError> {
+        send_oneshot::<M, _>(
+            &self.logger,
+            self.send_tx.clone(),
+            self.state.next_message_id(),
+            request,
+            timeout,
+        )
+        .await
+    }
+
+    /// `M`のイベントを送ります。
+    pub async fn send_event<M: crate::clocking::messages::EventMessage>(
+        &self,
+        payload: M::Payload,
+    ) -> Result<(), ClockingClientError> {
+        self.send_tx
+            .send(Request::Event(EventMessage::typed::<M>(payload)))
+            .await
+            .map_err(ClockingClientError::CannotSendRequest)
+    }
+
+    /// `instance`に参加し、既に参加しているプレイヤーを返します。
+    ///
+    /// `join_token`はBalancing-serverが`instance`のために発行したものです。
+    pub async fn login(
+        &self,
+        instance: InstanceId,
+        join_token: String,
+    ) -> Result<Vec<PlayerId>, ClockingClientError> {
+        info!(self.logger, "Joining instance: {}", instance);
+        let response = self
+            .send_oneshot::<LoginOneshot>(
+                LoginRequest {
+                    instance,
+                    join_token,
+                },
+                LOGIN_TIMEOUT,
+            )
+            .await?;
+        info!(self.logger, "Instance Joined: {:?}", response);
+        match response {
+            LoginResponse::Ok(player_id, players, resume_token) => {
+                self.state.set_session(player_id, resume_token);
+                Ok(players)
+            }
+            LoginResponse::BadToken => Err(ClockingClientError::LoginRejected),
+        }
+    }
+
+    /// 切れる前と同じプレイヤーとしてインスタンスに戻ります。
+    ///
+    /// 自動で接続し直した場合は先に引き継ぐので、呼ぶ必要はありません。
+    /// [`Self::connect_with_state`]で接続を作り直した場合に使います。
+    pub async fn resume(&self) -> Result<PlayerId, ClockingClientError> {
+        let resume_token = self
+            .state
+            .resume_token()
+            .ok_or(ClockingClientError::NoSession)?;
+        let result = self
+            .send_oneshot::<ResumeOneshot>(ResumeRequest { resume_token }, LOGIN_TIMEOUT)
+            .await;
+        match result {
+            Ok(ResumeResponse::Ok(player_id, resume_token)) => {
+                info!(self.logger, "Session resumed as player {}", player_id);
+                self.state.set_session(player_id, resume_token);
+                Ok(player_id)
+            }
+            Err(e) => {
+                warn!(self.logger, "Failed to resume the session: {}", e);
+                self.state.clear_session();
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn send_chat(&self, content: String) -> Result<(), ClockingClientError> {
+        self.send_oneshot::<SendChatMessageOneshot>(
+            SendChatMessageRequest { content },
+            DEFAULT_ONESHOT_TIMEOUT,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// 自分の位置を伝えます。
+    ///
+    /// 毎フレーム呼べるように、前に送った位置からあまり動いていなければ送りません。
+    /// 送るのが詰まっている場合も、次の位置で追いつけば良いので送りません。送った場合は`true`を返します。
+    pub fn report_transform(
+        &self,
+        transform: StandingTransform,
+    ) -> Result<bool, ClockingClientError> {
+        let mut encoder = self.encoder.lock().unwrap();
+        encoder.push(transform);
+        let Some(now) = encoder.payload() else {
+            return Ok(false);
+        };
+        // 送れなかった位置は送ったことにせず、止まった後でも最後の位置が届くようにする
+        match self
+            .send_tx
+            .try_send(Request::Event(EventMessage::typed::<PubPlayerMoveEvent>(
+                PubPlayerMove { now: now.clone() },
+            ))) {
+            Ok(()) => {
+                encoder.commit(now);
+                Ok(true)
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => Ok(false),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                Err(ClockingClientError::ConnectionNotFound)
+            }
+        }
+    }
+
+    /// 接続を閉じ、見張っているタスクが終わるのを待ちます。
+    pub async fn shutdown(&self) -> Result<(), JoinError> {
+        // 接続し直すのを諦めていれば、既に終わっている
+        if let Some(shutdown_tx) = self.shutdown_tx.lock().unwrap().take() {
+            let _ = shutdown_tx.send(());
+        }
+        let handle = self.handle.lock().unwrap().take();
+        if let Some(handle) = handle {
+            let _ = handle.await?;
+        }
+        Ok(())
+    }
+}
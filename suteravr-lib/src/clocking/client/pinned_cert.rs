@@ -0,0 +1,312 @@
+//! 初めて接続したときの証明書を信頼する(Trust On First Use)ための`ServerCertVerifier`です。
+//!
+//! 自己署名証明書のサーバーにも接続できるように、初回の接続で公開鍵(SPKI)のフィンガープリントを
+//! [`KnownHosts`]に記録し、以降は同じ公開鍵でなければ接続を拒否します。
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::util::fingerprint::CertFingerprint;
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, ring::default_provider, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+};
+
+/// 照合した結果です。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PinCheck {
+    /// 記録されている公開鍵と一致した
+    Matched,
+    /// 初めて接続したので記録した
+    Pinned,
+    /// 記録されている公開鍵と異なる
+    Mismatch {
+        expected: CertFingerprint,
+        actual: CertFingerprint,
+    },
+}
+
+/// 接続したことのあるサーバーの公開鍵のフィンガープリントを保存します。
+///
+/// ファイルには1行に1つ、`<サーバー名> <フィンガープリント>`の形式で保存します。`#`から始まる行は無視します。
+#[derive(Debug, Default)]
+pub struct KnownHosts {
+    path: Option<PathBuf>,
+    hosts: Mutex<HashMap<String, CertFingerprint>>,
+}
+
+impl KnownHosts {
+    /// ファイルに保存しないストアを作ります。
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// `path`から読み込みます。ファイルがなければ空のストアとして扱い、初めて記録するときに作ります。
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let hosts = match fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path),
+            hosts: Mutex::new(hosts),
+        })
+    }
+
+    fn parse(contents: &str) -> io::Result<HashMap<String, CertFingerprint>> {
+        let invalid = |line: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("known hosts: line {} is malformed", line + 1),
+            )
+        };
+        let mut hosts = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (host, fingerprint) = line.split_once(' ').ok_or_else(|| invalid(i))?;
+            let fingerprint = fingerprint.parse().map_err(|_| invalid(i))?;
+            hosts.insert(host.to_string(), fingerprint);
+        }
+        Ok(hosts)
+    }
+
+    fn save(&self, hosts: &HashMap<String, CertFingerprint>) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut hosts = hosts.iter().collect::<Vec<_>>();
+        hosts.sort_by(|a, b| a.0.cmp(b.0));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        for (host, fingerprint) in hosts {
+            writeln!(file, "{} {}", host, fingerprint)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, host: &str) -> Option<CertFingerprint> {
+        self.hosts.lock().unwrap().get(host).copied()
+    }
+
+    /// `host`の公開鍵を照合します。記録がなければ`actual`を記録します。
+    pub fn check_or_pin(&self, host: &str, actual: CertFingerprint) -> io::Result<PinCheck> {
+        let mut hosts = self.hosts.lock().unwrap();
+        match hosts.get(host) {
+            Some(expected) if *expected == actual => Ok(PinCheck::Matched),
+            Some(expected) => Ok(PinCheck::Mismatch {
+                expected: *expected,
+                actual,
+            }),
+            None => {
+                hosts.insert(host.to_string(), actual);
+                self.save(&hosts)?;
+                Ok(PinCheck::Pinned)
+            }
+        }
+    }
+
+    /// `host`の記録を消します。サーバーの鍵を正当に入れ替えた場合に使います。
+    pub fn forget(&self, host: &str) -> io::Result<bool> {
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.remove(host).is_none() {
+            return Ok(false);
+        }
+        self.save(&hosts)?;
+        Ok(true)
+    }
+}
+
+/// 証明書の公開鍵(SPKI)のフィンガープリントです。証明書を更新しても、鍵が同じなら変わりません。
+pub fn spki_fingerprint(cert: &CertificateDer<'_>) -> Result<CertFingerprint, rustls::Error> {
+    let cert = webpki::EndEntityCert::try_from(cert)
+        .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+    Ok(CertFingerprint::of(cert.subject_public_key_info().as_ref()))
+}
+
+/// [`KnownHosts`]で公開鍵を固定する`ServerCertVerifier`です。
+///
+/// 証明書の発行者や有効期限は確かめず、公開鍵が記録と一致するか(初回は記録できたか)だけを確かめます。
+#[derive(Debug)]
+pub struct TofuCertVerifier {
+    known_hosts: Arc<KnownHosts>,
+    provider: CryptoProvider,
+}
+
+impl TofuCertVerifier {
+    pub fn new(known_hosts: Arc<KnownHosts>) -> Arc<Self> {
+        Arc::new(Self {
+            known_hosts,
+            provider: default_provider(),
+        })
+    }
+}
+
+impl ServerCertVerifier for TofuCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = spki_fingerprint(end_entity)?;
+        match self
+            .known_hosts
+            .check_or_pin(&server_name.to_str(), actual)
+            .map_err(|e| rustls::Error::General(format!("Failed to save known hosts: {}", e)))?
+        {
+            PinCheck::Matched | PinCheck::Pinned => Ok(ServerCertVerified::assertion()),
+            PinCheck::Mismatch { .. } => Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn generate(names: &[&str]) -> CertificateDer<'static> {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        CertificateDer::from(
+            rcgen::generate_simple_self_signed(names)
+                .unwrap()
+                .serialize_der()
+                .unwrap(),
+        )
+    }
+
+    fn verify(
+        verifier: &TofuCertVerifier,
+        cert: &CertificateDer<'_>,
+        name: &'static str,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        verifier.verify_server_cert(
+            cert,
+            &[],
+            &ServerName::try_from(name).unwrap(),
+            &[],
+            UnixTime::now(),
+        )
+    }
+
+    #[test]
+    fn pin_on_first_use() {
+        let known_hosts = Arc::new(KnownHosts::in_memory());
+        let verifier = TofuCertVerifier::new(known_hosts.clone());
+        let cert = generate(&["localhost"]);
+
+        verify(&verifier, &cert, "localhost").unwrap();
+        assert_eq!(
+            known_hosts.get("localhost"),
+            Some(spki_fingerprint(&cert).unwrap())
+        );
+        verify(&verifier, &cert, "localhost").unwrap();
+    }
+
+    #[test]
+    fn refuse_on_mismatch() {
+        let known_hosts = Arc::new(KnownHosts::in_memory());
+        let verifier = TofuCertVerifier::new(known_hosts.clone());
+        let pinned = generate(&["localhost"]);
+        let other = generate(&["localhost"]);
+
+        verify(&verifier, &pinned, "localhost").unwrap();
+        assert!(matches!(
+            verify(&verifier, &other, "localhost"),
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure
+            ))
+        ));
+        // 別のサーバーとしては初めてなので受け入れる
+        verify(&verifier, &other, "example.com").unwrap();
+
+        assert!(known_hosts.forget("localhost").unwrap());
+        verify(&verifier, &other, "localhost").unwrap();
+    }
+
+    #[test]
+    fn known_hosts_file() {
+        let path = env::temp_dir().join(format!("suteravr-known-hosts-{}", std::process::id()));
+        let cert = generate(&["localhost"]);
+        let fingerprint = spki_fingerprint(&cert).unwrap();
+
+        let known_hosts = KnownHosts::open(&path).unwrap();
+        assert_eq!(
+            known_hosts.check_or_pin("localhost", fingerprint).unwrap(),
+            PinCheck::Pinned
+        );
+        let reopened = KnownHosts::open(&path).unwrap();
+        assert_eq!(
+            reopened.check_or_pin("localhost", fingerprint).unwrap(),
+            PinCheck::Matched
+        );
+
+        fs::write(&path, "# comment\nlocalhost not-a-fingerprint\n").unwrap();
+        assert_eq!(
+            KnownHosts::open(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
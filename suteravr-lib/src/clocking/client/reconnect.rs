@@ -0,0 +1,304 @@
+//! 接続が切れたときに、間隔を空けながら接続し直すための仕組みです。
+//!
+//! 接続し直すまでの間に送ろうとしたリクエストは[`OfflineQueue`]に溜めておき、
+//! 接続し直した後にまとめて送ります。
+
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use crate::clocking::messages::PubPlayerMoveEvent;
+use tokio::sync::mpsc;
+
+use super::requests::Request;
+
+/// 接続し直すまでに待つ時間と、諦めるまでの回数を決めます。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    /// 最初に接続し直すまでに待つ時間です。
+    pub initial_delay: Duration,
+    /// 待つ時間の上限です。
+    pub max_delay: Duration,
+    /// 続けて失敗するごとに、待つ時間を何倍にするかです。
+    pub multiplier: f64,
+    /// 待つ時間を乱数で最大どれだけ縮めるかの割合(0.0〜1.0)です。
+    ///
+    /// サーバーが落ちたときに、全員が同じ間隔で一斉に接続し直すのを避けます。
+    pub jitter: f64,
+    /// 続けて失敗しても接続し直す回数です。`None`の場合は諦めません。
+    pub max_retries: Option<u32>,
+    /// 接続が切れている間に溜めておくリクエストの数です。溢れた場合は古いものから捨てます。
+    pub max_queued_requests: usize,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_retries: Some(10),
+            max_queued_requests: 64,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// `retry`回目(0から)の接続の前に待つ時間です。`random`は0.0以上1.0未満の値です。
+    pub fn delay(&self, retry: u32, random: f64) -> Duration {
+        let exponent = retry.min(i32::MAX as u32) as i32;
+        let delay = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        Duration::from_secs_f64(delay * (1.0 - self.jitter.clamp(0.0, 1.0) * random))
+    }
+
+    /// [`Self::delay`]に乱数を与えたものです。
+    pub fn next_delay(&self, retry: u32) -> Duration {
+        self.delay(retry, jitter_random())
+    }
+
+    /// 続けて`failures`回失敗した後に、まだ接続し直すかです。
+    pub fn should_retry(&self, failures: u32) -> bool {
+        !matches!(self.max_retries, Some(max) if failures > max)
+    }
+}
+
+/// 0.0以上1.0未満の乱数です。待つ時間をずらせれば良いので、暗号用の乱数は使いません。
+fn jitter_random() -> f64 {
+    // RandomStateは作るたびに異なる鍵を使うので、空のハッシュ値も毎回変わる
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// 接続が切れている間に送ろうとしたリクエストです。
+pub struct OfflineQueue {
+    requests: VecDeque<Request>,
+    capacity: usize,
+    dropped: usize,
+}
+
+impl OfflineQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            requests: VecDeque::new(),
+            capacity: capacity.max(1),
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, request: Request) {
+        match &request {
+            Request::CancelOneshot(message_id) => {
+                self.requests.retain(|queued| {
+                    !matches!(queued, Request::OneshotWithReply(oneshot, _)
+                        if oneshot.oneshot_header.message_id == *message_id)
+                });
+                return;
+            }
+            // 位置は最後のものだけ送れば良い
+            Request::Event(event) if event.event_header.message_type.is::<PubPlayerMoveEvent>() => {
+                self.requests.retain(|queued| {
+                    !matches!(queued, Request::Event(queued)
+                        if queued.event_header.message_type.is::<PubPlayerMoveEvent>())
+                });
+            }
+            _ => {}
+        }
+        // 返答を待つのをやめたリクエストは、送っても仕方がない
+        self.requests.retain(
+            |queued| !matches!(queued, Request::OneshotWithReply(_, reply) if reply.is_closed()),
+        );
+        if self.requests.len() >= self.capacity {
+            // 捨てたリクエストの返答を待っていれば、Senderを捨てることで打ち切られる
+            self.requests.pop_front();
+            self.dropped += 1;
+        }
+        self.requests.push_back(request);
+    }
+
+    /// 送れなかったリクエストを、次に送るものとして戻します。
+    ///
+    /// 溜めておいたどのリクエストよりも古いので、溢れる場合はこれを捨てます。
+    pub fn push_front(&mut self, request: Request) {
+        if self.requests.len() >= self.capacity {
+            self.dropped += 1;
+            return;
+        }
+        self.requests.push_front(request);
+    }
+
+    /// 溜めておいたリクエストを全て捨て、その数を返します。
+    ///
+    /// 返答を待っているリクエストは、Senderを捨てることで打ち切られます。
+    pub fn clear(&mut self) -> usize {
+        let cleared = self.requests.len();
+        self.requests.clear();
+        cleared
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// 溢れて捨てたリクエストの数を返し、数え直します。
+    pub fn take_dropped(&mut self) -> usize {
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// 溜めておいたリクエストを先に返し、なくなれば`rx`から次のリクエストを待ちます。
+    ///
+    /// `tokio::select!`で途中で打ち切っても、リクエストは失われません。
+    pub async fn next(&mut self, rx: &mut mpsc::Receiver<Request>) -> Option<Request> {
+        if let Some(request) = self.requests.pop_front() {
+            return Some(request);
+        }
+        rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+    use tokio::sync::oneshot;
+
+    use crate::{
+        clocking::{
+            client::requests::{EventMessage, OneshotRequest, Response},
+            messages::{EventMessage as _, SendChatMessageOneshot},
+            schemas::oneshot::chat_entry::SendChatMessageRequest,
+            sutera_header::SuteraHeader,
+        },
+        SCHEMA_VERSION,
+    };
+
+    use super::*;
+
+    fn chat(message_id: u64) -> (Request, oneshot::Receiver<Response>) {
+        let (tx, rx) = oneshot::channel();
+        let request = OneshotRequest::typed::<SendChatMessageOneshot>(
+            message_id,
+            SendChatMessageRequest {
+                content: format!("message {}", message_id),
+            },
+        );
+        (Request::OneshotWithReply(request, tx), rx)
+    }
+
+    /// 位置が変わったことだけ分かれば良いので、ペイロードには印だけ入れる
+    fn moved(mark: u8) -> Request {
+        Request::Event(EventMessage::new(
+            SuteraHeader {
+                version: SCHEMA_VERSION,
+            },
+            PubPlayerMoveEvent::header(),
+            Bytes::from(vec![mark]),
+        ))
+    }
+
+    fn describe(request: &Request) -> String {
+        match request {
+            Request::OneshotWithReply(oneshot, _) => {
+                format!("oneshot {}", oneshot.oneshot_header.message_id)
+            }
+            Request::Event(event) => format!("moved {}", event.payload[0]),
+            _ => "other".to_string(),
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_retries: Some(3),
+            max_queued_requests: 8,
+        };
+        assert_eq!(policy.delay(0, 0.0), Duration::from_secs(1));
+        assert_eq!(policy.delay(2, 0.0), Duration::from_secs(4));
+        assert_eq!(policy.delay(10, 0.0), Duration::from_secs(10));
+        assert_eq!(policy.delay(u32::MAX, 0.0), Duration::from_secs(10));
+        // 乱数で縮めても、半分より短くはならない
+        assert_eq!(policy.delay(2, 0.5), Duration::from_secs(3));
+        assert!(policy.next_delay(2) >= Duration::from_secs(2));
+        assert!(policy.next_delay(2) <= Duration::from_secs(4));
+
+        assert!(policy.should_retry(3));
+        assert!(!policy.should_retry(4));
+        assert!(ReconnectPolicy {
+            max_retries: None,
+            ..policy
+        }
+        .should_retry(u32::MAX));
+    }
+
+    #[tokio::test]
+    async fn queue_while_offline() {
+        let mut queue = OfflineQueue::new(3);
+        let (first, first_rx) = chat(1);
+        let (second, _second_rx) = chat(2);
+        let (given_up, given_up_rx) = chat(3);
+        drop(given_up_rx);
+
+        queue.push(first);
+        queue.push(moved(1));
+        queue.push(given_up);
+        queue.push(moved(2));
+        assert_eq!(queue.len(), 2);
+
+        queue.push(second);
+        queue.push(Request::CancelOneshot(2));
+        assert_eq!(queue.take_dropped(), 0);
+
+        let (third, _third_rx) = chat(4);
+        let (fourth, _fourth_rx) = chat(5);
+        queue.push(third);
+        queue.push(fourth);
+        assert_eq!(queue.take_dropped(), 1);
+        // 溢れて捨てられたリクエストの返答は、もう届かない
+        assert!(first_rx.await.is_err());
+
+        let (tx, mut rx) = mpsc::channel(1);
+        tx.send(moved(3)).await.unwrap();
+        let mut sent = Vec::new();
+        while !queue.is_empty() {
+            sent.push(describe(&queue.next(&mut rx).await.unwrap()));
+        }
+        sent.push(describe(&queue.next(&mut rx).await.unwrap()));
+        assert_eq!(sent, vec!["moved 2", "oneshot 4", "oneshot 5", "moved 3"]);
+    }
+
+    #[tokio::test]
+    async fn put_back_unsent_request() {
+        let mut queue = OfflineQueue::new(2);
+        let (first, _first_rx) = chat(1);
+        let (second, _second_rx) = chat(2);
+        queue.push(first);
+        queue.push(moved(1));
+
+        let (_tx, mut rx) = mpsc::channel(1);
+        let unsent = queue.next(&mut rx).await.unwrap();
+        queue.push_front(unsent);
+        assert_eq!(describe(&queue.next(&mut rx).await.unwrap()), "oneshot 1");
+
+        // 溢れる場合は、戻そうとした古いリクエストを捨てる
+        queue.push(second);
+        queue.push_front(moved(2));
+        assert_eq!(queue.take_dropped(), 1);
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(queue.clear(), 2);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use derivative::Derivative;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::{
+    clocking::{
+        compression::ContentEncoding,
+        event_headers::EventHeader,
+        messages::{self, OneshotMessage},
+        oneshot_headers::{OneshotHeader, OneshotStep},
+        schemas::oneshot::error_details::ErrorDetails,
+        sutera_header::SuteraHeader,
+        sutera_status::SuteraStatus,
+    },
+    error,
+    messaging::id::MessageId,
+    util::logger::Logger,
+    warn, SCHEMA_VERSION,
+};
+
+use super::error::ClockingClientError;
+
+pub enum Response {
+    Oneshot(OneshotResponse),
+    Event(EventMessage),
+    /// リクエストを送れなかったので、返答は届きません。
+    Failed(Box<ClockingClientError>),
+}
+
+pub enum Request {
+    Oneshot(OneshotRequest),
+    OneshotWithReply(OneshotRequest, oneshot::Sender<Response>),
+    Event(EventMessage),
+    /// 交渉が済んだので、以降のContentを圧縮して送ります。
+    EnableCompression(ContentEncoding),
+    /// 返答を待つのをやめたので、`reply_senders`から取り除きます。
+    ///
+    /// 後から返答が届いた場合は、読み捨てられます。
+    CancelOneshot(MessageId),
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct EventMessage {
+    pub sutera_header: SuteraHeader,
+    pub event_header: EventHeader,
+    pub payload: Bytes,
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct OneshotResponse {
+    pub sutera_header: SuteraHeader,
+    pub sutera_status: SuteraStatus,
+    pub oneshot_header: OneshotHeader,
+    pub payload: Bytes,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct OneshotRequest {
+    pub sutera_header: SuteraHeader,
+    /// サーバーからのOneshotに返答する場合にのみ指定できます。省略した場合は`Ok`とみなされます。
+    pub sutera_status: Option<SuteraStatus>,
+    pub oneshot_header: OneshotHeader,
+    pub payload: Bytes,
+}
+
+impl OneshotRequest {
+    #[inline]
+    pub fn new(sutera_header: SuteraHeader, oneshot_header: OneshotHeader, payload: Bytes) -> Self {
+        Self {
+            sutera_header,
+            sutera_status: None,
+            oneshot_header,
+            payload,
+        }
+    }
+
+    /// `M`のリクエストを組み立てます。
+    #[inline]
+    pub fn typed<M: OneshotMessage>(message_id: MessageId, payload: M::Request) -> Self {
+        Self {
+            sutera_header: SuteraHeader {
+                version: SCHEMA_VERSION,
+            },
+            sutera_status: None,
+            oneshot_header: M::request_header(message_id),
+            payload: M::encode_request(payload),
+        }
+    }
+}
+impl OneshotResponse {
+    /// エラーのレスポンスに付けられた詳細を読み取ります。
+    ///
+    /// 古いサーバーは詳細を付けないので、その場合は`None`になります。
+    #[inline]
+    pub fn error_details(&self) -> Option<ErrorDetails> {
+        if !self.sutera_status.is_error() {
+            return None;
+        }
+        ErrorDetails::decode(&self.payload)
+    }
+
+    #[inline]
+    pub fn new(
+        sutera_header: SuteraHeader,
+        sutera_status: SuteraStatus,
+        oneshot_header: OneshotHeader,
+        payload: Bytes,
+    ) -> Self {
+        Self {
+            sutera_header,
+            sutera_status,
+            oneshot_header,
+            payload,
+        }
+    }
+}
+
+impl EventMessage {
+    #[inline]
+    pub fn new(sutera_header: SuteraHeader, event_header: EventHeader, payload: Bytes) -> Self {
+        Self {
+            sutera_header,
+            event_header,
+            payload,
+        }
+    }
+
+    /// `M`のイベントを組み立てます。
+    #[inline]
+    pub fn typed<M: messages::EventMessage>(payload: M::Payload) -> Self {
+        Self {
+            sutera_header: SuteraHeader {
+                version: SCHEMA_VERSION,
+            },
+            event_header: M::header(),
+            payload: M::encode(payload),
+        }
+    }
+}
+
+/// サーバーからのOneshotへの返答を組み立てます。
+pub fn oneshot_response<M: OneshotMessage>(
+    response: OneshotResponse,
+    payload: M::Response,
+) -> OneshotRequest {
+    debug_assert!(response.oneshot_header.message_type.is::<M>());
+    OneshotRequest {
+        sutera_header: SuteraHeader {
+            version: SCHEMA_VERSION,
+        },
+        sutera_status: None,
+        oneshot_header: M::response_header(response.oneshot_header.message_id),
+        payload: M::encode_response(payload),
+    }
+}
+
+/// サーバーからのOneshotを処理できなかったことを、`fail_status`で返答します。
+///
+/// エラーの場合は、その種類の既定の[`ErrorDetails`]をペイロードとして付けます。
+pub fn oneshot_response_failed(
+    response: OneshotResponse,
+    fail_status: SuteraStatus,
+) -> OneshotRequest {
+    OneshotRequest {
+        sutera_header: SuteraHeader {
+            version: SCHEMA_VERSION,
+        },
+        oneshot_header: OneshotHeader {
+            step: OneshotStep::Response,
+            message_type: response.oneshot_header.message_type,
+            message_id: response.oneshot_header.message_id,
+        },
+        payload: match fail_status {
+            SuteraStatus::Error(error) => ErrorDetails::from(error).encode(),
+            _ => Bytes::new(),
+        },
+        sutera_status: Some(fail_status),
+    }
+}
+
+/// リクエストを送り、`timeout`までに返ってきたレスポンスを返します。
+///
+/// 期限を過ぎた場合はリクエストを取り消して[`ClockingClientError::Timeout`]を、
+/// 返答の前に接続が閉じた場合は[`ClockingClientError::OneshotCancelled`]を返します。
+pub async fn send_oneshot_request<L: Logger>(
+    logger: &L,
+    send: mpsc::Sender<Request>,
+    request: OneshotRequest,
+    timeout: Duration,
+) -> Result<OneshotResponse, ClockingClientError> {
+    let message_id = request.oneshot_header.message_id;
+    let (tx, rx) = oneshot::channel::<Response>();
+    send.send(Request::OneshotWithReply(request, tx))
+        .await
+        .map_err(ClockingClientError::CannotSendRequest)?;
+
+    let received = match time::timeout(timeout, rx).await {
+        Ok(Ok(received)) => received,
+        Ok(Err(_)) => return Err(ClockingClientError::OneshotCancelled(message_id)),
+        Err(_) => {
+            warn!(
+                *logger,
+                "Oneshot request {:?} timed out after {:?}.", message_id, timeout
+            );
+            // 接続が既に閉じていれば、取り消すものも残っていない
+            let _ = send.send(Request::CancelOneshot(message_id)).await;
+            return Err(ClockingClientError::Timeout(message_id));
+        }
+    };
+    let oneshot = match received {
+        Response::Oneshot(oneshot) => oneshot,
+        Response::Failed(e) => return Err(*e),
+        Response::Event(_) => {
+            error!(
+                *logger,
+                "rx of messageId {:?} not received Oneshot!", message_id
+            );
+            return Err(ClockingClientError::UnexpectedResponse(message_id));
+        }
+    };
+    if oneshot.sutera_status.is_error() {
+        let details = oneshot.error_details();
+        if let Some(details) = &details {
+            warn!(
+                *logger,
+                "The server rejected the request ({}): {}", details.code, details.message
+            );
+        }
+        return Err(ClockingClientError::ErrorStatus {
+            status: oneshot.sutera_status,
+            details,
+        });
+    }
+    Ok(oneshot)
+}
+
+/// `M`のリクエストを送り、返ってきたレスポンスを`M`のレスポンスとして解釈します。
+pub async fn send_oneshot<M: OneshotMessage, L: Logger>(
+    logger: &L,
+    send: mpsc::Sender<Request>,
+    message_id: MessageId,
+    request: M::Request,
+    timeout: Duration,
+) -> Result<M::Response, ClockingClientError> {
+    let response = send_oneshot_request(
+        logger,
+        send,
+        OneshotRequest::typed::<M>(message_id, request),
+        timeout,
+    )
+    .await?;
+    Ok(M::decode_response(&response.payload)?)
+}
@@ -0,0 +1,681 @@
+use std::{
+    io::{self, Cursor},
+    mem::size_of,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::FutureExt;
+use memchr::memmem::Finder;
+use once_cell::sync::Lazy;
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    compression::{ContentCompression, ContentEncoding},
+    event_headers::EventHeader,
+    oneshot_headers::OneshotHeader,
+    sutera_header::SuteraHeader,
+    sutera_status::SuteraStatus,
+    traits::{ClockingFrame, MessageAuthor},
+    ClockingFrameUnit, ClockingFramingError, ConnectionLimits,
+};
+
+/// Unfragmentedとして一度に切り出す最大のバイト数です。
+const UNFRAGMENTED_CHUNK_SIZE: usize = 1024;
+
+/// 圧縮されたContentの前に置かれる目印です。
+///
+/// 通常のContentの先頭は長さ(u64)の最上位バイトなので、上限を考えれば`0x04`になることはありません。
+const COMPRESSED_CONTENT_MARKER: u8 = 0x04;
+
+/// SuteraHeaderのprefixを線形時間で探すためのFinderです。
+static SUTERA_HEADER_FINDER: Lazy<Finder<'static>> =
+    Lazy::new(|| Finder::new(SuteraHeader::PREFIX));
+
+enum ConnectionContext {
+    None,
+    /// 再同期中です。値は、バッファの先頭からSuteraHeaderの始まりがないと確認済みのバイト数です。
+    Unfragmented(usize),
+    WaitStatus,
+    /// クライアントからのメッセージでは、SuteraStatusは省略できます。
+    WaitOptionalStatus,
+    WaitMessageType,
+    WaitContent,
+}
+
+/// Clocking-Serverの通信形式を、[`ClockingFrameUnit`]単位で読み書きするための[`Decoder`]/[`Encoder`]です。
+///
+/// [`tokio_util::codec::Framed`]などと組み合わせることで、
+/// [`ClockingConnection`][super::ClockingConnection]を使わずに任意のトランスポートの上でやりとりできます。
+///
+/// **authorには、名前の通り「メッセージの送信者」が格納されることに注意してください。**
+/// たとえば、サーバー側で動いている場合は、[`MessageAuthor::Client`]を`author`に指定する必要があります。
+pub struct ClockingCodec {
+    author: MessageAuthor,
+    context: ConnectionContext,
+    limits: ConnectionLimits,
+    compression: ContentCompression,
+    unfragmented_bytes: usize,
+}
+
+impl ClockingCodec {
+    pub fn new(author: MessageAuthor) -> Self {
+        Self::with_limits(author, ConnectionLimits::default())
+    }
+
+    pub fn with_limits(author: MessageAuthor, limits: ConnectionLimits) -> Self {
+        Self {
+            author,
+            context: ConnectionContext::None,
+            limits,
+            compression: ContentCompression::default(),
+            unfragmented_bytes: 0,
+        }
+    }
+
+    #[inline]
+    pub fn author(&self) -> MessageAuthor {
+        self.author
+    }
+
+    #[inline]
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    #[inline]
+    pub fn compression(&self) -> &ContentCompression {
+        &self.compression
+    }
+
+    /// Contentの圧縮の設定を変更します。次に読み書きするフレームから反映されます。
+    #[inline]
+    pub fn set_compression(&mut self, compression: ContentCompression) {
+        self.compression = compression;
+    }
+
+    #[inline]
+    fn parse_frame(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<ClockingFrameUnit>, ClockingFramingError> {
+        // 状態が変わった場合は、再帰せずにループで読み直す
+        loop {
+            let mut buf = Cursor::new(&src[..]);
+            match self.context {
+                ConnectionContext::None => {
+                    let remaining = buf.remaining();
+
+                    // SuteraHeader (通信のはじまりの目印) を探す
+                    // この時点で、SuteraHeaderの最小サイズよりも小さい場合は、次のバッファも読む
+                    if remaining < SuteraHeader::MIN_FRAME_SIZE {
+                        return Ok(None);
+                    }
+
+                    // 先頭からSuteraHeaderが成立していれば文句なしでOK
+                    if let Some(header) = SuteraHeader::parse_frame_unchecked(&mut buf, &()) {
+                        src.advance(buf.position() as usize);
+                        self.unfragmented_bytes = 0;
+                        self.context = match self.author {
+                            MessageAuthor::Server => ConnectionContext::WaitStatus,
+                            MessageAuthor::Client => ConnectionContext::WaitOptionalStatus,
+                        };
+                        return Ok(Some(ClockingFrameUnit::SuteraHeader(header)));
+                    }
+
+                    if remaining < SuteraHeader::MAX_FRAME_SIZE {
+                        return Ok(None);
+                    }
+
+                    // 処理がここまで流れている時点で、
+                    // 最後にフレームが成立してから直ちにヘッダーが来ていないから何かがおかしい
+                    //
+                    // ただ、一応次にどこかでSuteraHeaderが来るかもしれないので、
+                    // バッファのどこかにSuteraHeaderを検知できたらそれまでのところをUnfragmentedとする
+                    // (先頭にはSuteraHeaderがないことが分かっている)
+                    self.context = ConnectionContext::Unfragmented(1);
+                }
+                ConnectionContext::Unfragmented(scanned) => {
+                    // `scanned`より前にはSuteraHeaderの始まりがないことが分かっているので、
+                    // そこから先だけをprefixで検索する
+                    let scanned = scanned.min(src.len());
+                    match SUTERA_HEADER_FINDER.find(&src[scanned..]) {
+                        Some(found) => {
+                            let found = scanned + found;
+                            if found == 0 {
+                                self.context = ConnectionContext::None;
+                                continue;
+                            }
+                            // 一度に切り出す大きさは抑え、残りは次回に回す
+                            let len = found.min(UNFRAGMENTED_CHUNK_SIZE);
+                            self.context = if len == found {
+                                ConnectionContext::None
+                            } else {
+                                ConnectionContext::Unfragmented(found - len)
+                            };
+                            return self.take_unfragmented(src, len).map(Some);
+                        }
+                        None => {
+                            // 末尾はprefixの途中かもしれないので、その手前までを確認済みとする
+                            let scanned = scanned
+                                .max(src.len().saturating_sub(SuteraHeader::PREFIX.len() - 1));
+
+                            // 認識されていない状態でバッファが増えつづけると危険なので、
+                            // 定期的にUnfragmentedとして処理する
+                            if scanned >= UNFRAGMENTED_CHUNK_SIZE {
+                                self.context = ConnectionContext::Unfragmented(
+                                    scanned - UNFRAGMENTED_CHUNK_SIZE,
+                                );
+                                return self
+                                    .take_unfragmented(src, UNFRAGMENTED_CHUNK_SIZE)
+                                    .map(Some);
+                            }
+                            self.context = ConnectionContext::Unfragmented(scanned);
+                            return Ok(None);
+                        }
+                    }
+                }
+                ConnectionContext::WaitStatus => {
+                    if let Some(status) = SuteraStatus::parse_frame(&mut buf, &()) {
+                        self.context = ConnectionContext::WaitMessageType;
+                        src.advance(buf.position() as usize);
+                        return Ok(Some(ClockingFrameUnit::SuteraStatus(status)));
+                    }
+
+                    if src.len() < SuteraStatus::MAX_FRAME_SIZE {
+                        return Ok(None);
+                    }
+                    self.context = ConnectionContext::Unfragmented(0);
+                }
+                ConnectionContext::WaitOptionalStatus => {
+                    // SuteraStatusの後には必ずヘッダーが続くので、どちらであっても最大の大きさまでは届く
+                    if src.len() < SuteraStatus::MAX_FRAME_SIZE {
+                        return Ok(None);
+                    }
+                    // OneshotHeader・EventHeaderの先頭は、SuteraStatusとして解釈できないので見分けられる
+                    self.context = ConnectionContext::WaitMessageType;
+                    if let Some(status) = SuteraStatus::parse_frame(&mut buf, &()) {
+                        src.advance(buf.position() as usize);
+                        return Ok(Some(ClockingFrameUnit::SuteraStatus(status)));
+                    }
+                }
+                ConnectionContext::WaitMessageType => {
+                    if let Some(header) = OneshotHeader::parse_frame(&mut buf, &self.author) {
+                        self.context = ConnectionContext::WaitContent;
+                        src.advance(buf.position() as usize);
+                        return Ok(Some(ClockingFrameUnit::OneshotHeaders(header)));
+                    }
+                    buf.set_position(0);
+                    if let Some(header) = EventHeader::parse_frame(&mut buf, &self.author) {
+                        self.context = ConnectionContext::WaitContent;
+                        src.advance(buf.position() as usize);
+                        return Ok(Some(ClockingFrameUnit::EventHeader(header)));
+                    }
+
+                    if src.len() < OneshotHeader::MAX_FRAME_SIZE {
+                        return Ok(None);
+                    }
+                    self.context = ConnectionContext::Unfragmented(0);
+                }
+                ConnectionContext::WaitContent => {
+                    // 圧縮を受け付けている場合のみ、目印を確認する
+                    let encoding = if self.compression.accept
+                        && buf.chunk().first() == Some(&COMPRESSED_CONTENT_MARKER)
+                    {
+                        if buf.remaining() < 2 {
+                            return Ok(None);
+                        }
+                        buf.advance(1);
+                        let Some(encoding) = ContentEncoding::from_code(buf.get_u8()) else {
+                            self.context = ConnectionContext::Unfragmented(0);
+                            continue;
+                        };
+                        Some(encoding)
+                    } else {
+                        None
+                    };
+
+                    if buf.remaining() <= size_of::<u64>() {
+                        return Ok(None);
+                    }
+
+                    // 送信するデータの長さを読む
+                    // 長さは二回同じものが出力される。
+                    // 同じものの場合のみ入力を受け付け、違うものの場合Contentを読んでいないと考えUnfragmentedに
+                    let content_length = buf.get_u64();
+                    let remaining = buf.remaining();
+                    if remaining < size_of::<u64>() {
+                        if buf.copy_to_bytes(remaining)
+                            != content_length.to_be_bytes()[0..remaining]
+                        {
+                            // 与えられた入力が違う場合はその時点で却下
+                            self.context = ConnectionContext::Unfragmented(0);
+                            continue;
+                        }
+                        // 二回目の長さが最後まで届いていないが、届いていたところまではあっている場合
+                        // 続きを待つ
+                        return Ok(None);
+                    }
+
+                    let content_length_check = buf.get_u64();
+                    if content_length != content_length_check {
+                        self.context = ConnectionContext::Unfragmented(0);
+                        continue;
+                    }
+
+                    // 長さの上限を超える場合は、届くまで待たずに直ちに諦める
+                    if content_length > self.limits.max_content_size as u64 {
+                        return Err(ClockingFramingError::ContentTooLarge {
+                            length: content_length,
+                            limit: self.limits.max_content_size,
+                        });
+                    }
+
+                    if buf.remaining() < content_length as usize {
+                        return Ok(None);
+                    }
+                    // 長さの部分を読み捨て、中身はコピーせずにバッファから切り離す
+                    src.advance(buf.position() as usize);
+                    let data = src.split_to(content_length as usize).freeze();
+                    self.context = ConnectionContext::None;
+                    let Some(encoding) = encoding else {
+                        return Ok(Some(ClockingFrameUnit::Content(data)));
+                    };
+                    // 展開後の大きさも上限を超えないようにする
+                    return match encoding.decompress(&data, self.limits.max_content_size) {
+                        Some(data) => Ok(Some(ClockingFrameUnit::Content(data.into()))),
+                        None => Err(ClockingFramingError::DecompressionFailed(encoding)),
+                    };
+                }
+            }
+        }
+    }
+
+    /// バッファの先頭から`len`バイトをUnfragmentedとして切り出します。
+    ///
+    /// 連続して読み捨てたバイト数が上限を超えた場合はエラーを返します。
+    #[inline]
+    fn take_unfragmented(
+        &mut self,
+        src: &mut BytesMut,
+        len: usize,
+    ) -> Result<ClockingFrameUnit, ClockingFramingError> {
+        self.unfragmented_bytes += len;
+        if self.unfragmented_bytes > self.limits.max_unfragmented_bytes {
+            return Err(ClockingFramingError::TooManyUnfragmentedBytes {
+                received: self.unfragmented_bytes,
+                limit: self.limits.max_unfragmented_bytes,
+            });
+        }
+        Ok(ClockingFrameUnit::Unfragmented(src.split_to(len).freeze()))
+    }
+}
+
+/// [`ClockingFrame`]を`dst`の末尾に書き込みます。
+///
+/// `Vec<u8>`への書き込みは決して待たされないので、`write_frame`は一度pollするだけで完了します。
+#[inline]
+fn encode_frame<T: ClockingFrame>(
+    frame: &T,
+    ctx: &T::Context,
+    dst: &mut BytesMut,
+) -> io::Result<()> {
+    let mut encoded = Vec::with_capacity(T::MAX_FRAME_SIZE);
+    frame
+        .write_frame(&mut encoded, ctx)
+        .now_or_never()
+        .expect("Writing into Vec<u8> must not be pending.")?;
+    dst.extend_from_slice(&encoded);
+    Ok(())
+}
+
+impl Decoder for ClockingCodec {
+    type Item = ClockingFrameUnit;
+    type Error = ClockingFramingError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(frame) = self.parse_frame(src)? {
+            return Ok(Some(frame));
+        }
+
+        // フレームが成立しないまま上限を超えて溜めこまないように、次を読む前に確認する
+        if src.len() >= self.limits.max_buffered_bytes {
+            return Err(ClockingFramingError::BufferLimitExceeded {
+                buffered: src.len(),
+                limit: self.limits.max_buffered_bytes,
+            });
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(ClockingFramingError::ConnectionReset),
+        }
+    }
+}
+
+impl<'a> Encoder<&'a ClockingFrameUnit> for ClockingCodec {
+    type Error = ClockingFramingError;
+
+    fn encode(
+        &mut self,
+        item: &'a ClockingFrameUnit,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        match item {
+            ClockingFrameUnit::SuteraHeader(header) => encode_frame(header, &(), dst)?,
+            ClockingFrameUnit::SuteraStatus(status) => encode_frame(status, &(), dst)?,
+            ClockingFrameUnit::OneshotHeaders(header) => encode_frame(header, &self.author, dst)?,
+            ClockingFrameUnit::EventHeader(header) => encode_frame(header, &self.author, dst)?,
+            ClockingFrameUnit::Content(content) => {
+                let compressed = match self.compression.send {
+                    Some(encoding) if content.len() >= self.compression.min_size => {
+                        let compressed = encoding.compress(content);
+                        // 縮まなかった場合はそのまま送る
+                        (compressed.len() < content.len()).then_some((encoding, compressed))
+                    }
+                    _ => None,
+                };
+                let content = match &compressed {
+                    Some((encoding, compressed)) => {
+                        dst.reserve(2);
+                        dst.put_u8(COMPRESSED_CONTENT_MARKER);
+                        dst.put_u8(encoding.code());
+                        &compressed[..]
+                    }
+                    None => &content[..],
+                };
+                dst.reserve(size_of::<u64>() * 2 + content.len());
+                dst.put_u64(content.len() as u64);
+                dst.put_u64(content.len() as u64);
+                dst.put_slice(content);
+            }
+            ClockingFrameUnit::Unfragmented(content) => dst.put_slice(content),
+        }
+        Ok(())
+    }
+}
+
+impl Encoder<ClockingFrameUnit> for ClockingCodec {
+    type Error = ClockingFramingError;
+
+    #[inline]
+    fn encode(&mut self, item: ClockingFrameUnit, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        Encoder::<&ClockingFrameUnit>::encode(self, &item, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use pretty_assertions::assert_eq;
+    use rstest::*;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use crate::{
+        clocking::{
+            event_headers::{EventDirection, EventTypes},
+            oneshot_headers::{OneshotStep, OneshotTypes},
+        },
+        messaging::version::Version,
+    };
+
+    use super::*;
+
+    fn message_frames(author: MessageAuthor) -> Vec<ClockingFrameUnit> {
+        let mut frames = vec![ClockingFrameUnit::SuteraHeader(SuteraHeader {
+            version: Version {
+                major: 0,
+                minor: 1,
+                patch: 0,
+            },
+        })];
+        if author == MessageAuthor::Server {
+            frames.push(ClockingFrameUnit::SuteraStatus(SuteraStatus::Ok));
+        }
+        frames.push(match author {
+            MessageAuthor::Client => ClockingFrameUnit::OneshotHeaders(OneshotHeader {
+                step: OneshotStep::Request,
+                message_id: 0x1234,
+                message_type: OneshotTypes::Authentication_Login_Pull.into(),
+            }),
+            MessageAuthor::Server => ClockingFrameUnit::EventHeader(EventHeader {
+                direction: EventDirection::Push,
+                message_type: EventTypes::Instance_PlayerJoined_Push.into(),
+            }),
+        });
+        frames.push(ClockingFrameUnit::Content(Bytes::from_static(b"Wao!")));
+        frames
+    }
+
+    #[rstest]
+    #[case::client(MessageAuthor::Client)]
+    #[case::server(MessageAuthor::Server)]
+    #[tokio::test]
+    async fn framed_reflective(#[case] author: MessageAuthor) {
+        let (writer, reader) = tokio::io::duplex(64);
+        let mut sink = FramedWrite::new(writer, ClockingCodec::new(author));
+        let mut stream = FramedRead::new(reader, ClockingCodec::new(author));
+        let frames = message_frames(author);
+
+        let sent = frames.clone();
+        let send = tokio::spawn(async move {
+            for frame in sent {
+                sink.send(frame).await.unwrap();
+            }
+        });
+        for frame in frames {
+            assert_eq!(stream.next().await.unwrap().unwrap(), frame);
+        }
+        send.await.unwrap();
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn framed_reset_in_the_middle_of_frame() {
+        let mut encoded = BytesMut::new();
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        for frame in message_frames(MessageAuthor::Client) {
+            codec.encode(frame, &mut encoded).unwrap();
+        }
+        encoded.truncate(encoded.len() - 1);
+
+        let mut stream = FramedRead::new(&encoded[..], ClockingCodec::new(MessageAuthor::Client));
+        for _ in 0..2 {
+            assert!(stream.next().await.unwrap().is_ok());
+        }
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(ClockingFramingError::ConnectionReset))
+        ));
+    }
+
+    #[test]
+    fn content_is_split_without_copy() {
+        let mut encoded = BytesMut::new();
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        for frame in message_frames(MessageAuthor::Client) {
+            codec.encode(frame, &mut encoded).unwrap();
+        }
+        let content_at = encoded.as_ptr() as usize + encoded.len() - b"Wao!".len();
+
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        for _ in 0..2 {
+            codec.decode(&mut encoded).unwrap().unwrap();
+        }
+        let Some(ClockingFrameUnit::Content(content)) = codec.decode(&mut encoded).unwrap() else {
+            panic!("Content expected");
+        };
+        assert_eq!(content, Bytes::from_static(b"Wao!"));
+        assert_eq!(content.as_ptr() as usize, content_at);
+    }
+
+    fn encoded_sutera_header() -> (SuteraHeader, BytesMut) {
+        let header = SuteraHeader {
+            version: Version::new(0, 1, 0),
+        };
+        let mut encoded = BytesMut::new();
+        ClockingCodec::new(MessageAuthor::Client)
+            .encode(
+                ClockingFrameUnit::SuteraHeader(header.clone()),
+                &mut encoded,
+            )
+            .unwrap();
+        (header, encoded)
+    }
+
+    #[test]
+    fn resync_emits_bounded_chunks() {
+        let (header, encoded) = encoded_sutera_header();
+        let mut src = BytesMut::new();
+        src.put_bytes(0x0f, UNFRAGMENTED_CHUNK_SIZE * 3 + 10);
+        src.put_slice(&encoded);
+
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        let mut unfragmented = Vec::new();
+        let header_received = loop {
+            match codec.decode(&mut src).unwrap() {
+                Some(ClockingFrameUnit::Unfragmented(chunk)) => unfragmented.push(chunk.len()),
+                frame => break frame,
+            }
+        };
+        assert_eq!(
+            unfragmented,
+            vec![
+                UNFRAGMENTED_CHUNK_SIZE,
+                UNFRAGMENTED_CHUNK_SIZE,
+                UNFRAGMENTED_CHUNK_SIZE,
+                10
+            ]
+        );
+        assert_eq!(
+            header_received,
+            Some(ClockingFrameUnit::SuteraHeader(header))
+        );
+    }
+
+    #[test]
+    fn resync_prefix_split_across_reads() {
+        let (header, encoded) = encoded_sutera_header();
+        let mut src = BytesMut::new();
+        src.put_slice(&[0x0f; 40]);
+        src.put_slice(&encoded[..4]);
+
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        src.put_slice(&encoded[4..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(ClockingFrameUnit::Unfragmented(Bytes::from_static(
+                &[0x0f; 40]
+            )))
+        );
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(ClockingFrameUnit::SuteraHeader(header))
+        );
+    }
+
+    fn compressible_frames() -> Vec<ClockingFrameUnit> {
+        let mut frames = message_frames(MessageAuthor::Client);
+        *frames.last_mut().unwrap() = ClockingFrameUnit::Content(b"SuteraVR".repeat(128).into());
+        frames
+    }
+
+    fn compressing() -> ContentCompression {
+        ContentCompression {
+            accept: true,
+            send: Some(ContentEncoding::Deflate),
+            ..Default::default()
+        }
+    }
+
+    #[rstest]
+    #[case::accept_compressed(compressing(), compressing())]
+    #[case::accept_raw(ContentCompression::default(), compressing())]
+    #[case::raw(ContentCompression::default(), ContentCompression::default())]
+    fn compressed_content_reflective(
+        #[case] writer: ContentCompression,
+        #[case] reader: ContentCompression,
+    ) {
+        let frames = compressible_frames();
+        let mut encoded = BytesMut::new();
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        codec.set_compression(writer);
+        for frame in frames.iter() {
+            codec.encode(frame, &mut encoded).unwrap();
+        }
+
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        codec.set_compression(reader);
+        for frame in frames {
+            assert_eq!(codec.decode(&mut encoded).unwrap(), Some(frame));
+        }
+        assert!(encoded.is_empty());
+    }
+
+    #[test]
+    fn compressed_content_is_smaller() {
+        let encode = |compression: ContentCompression| {
+            let mut encoded = BytesMut::new();
+            let mut codec = ClockingCodec::new(MessageAuthor::Client);
+            codec.set_compression(compression);
+            for frame in compressible_frames() {
+                codec.encode(frame, &mut encoded).unwrap();
+            }
+            encoded.len()
+        };
+        assert!(encode(compressing()) < encode(ContentCompression::default()));
+    }
+
+    #[test]
+    fn reject_compressed_content_unless_accepted() {
+        let mut encoded = BytesMut::new();
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        codec.set_compression(compressing());
+        for frame in compressible_frames() {
+            codec.encode(frame, &mut encoded).unwrap();
+        }
+
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        for _ in 0..2 {
+            codec.decode(&mut encoded).unwrap().unwrap();
+        }
+        assert!(!matches!(
+            codec.decode(&mut encoded).unwrap(),
+            Some(ClockingFrameUnit::Content(_))
+        ));
+    }
+
+    #[test]
+    fn reject_decompression_bomb() {
+        let mut encoded = BytesMut::new();
+        let mut codec = ClockingCodec::new(MessageAuthor::Client);
+        codec.set_compression(compressing());
+        for frame in compressible_frames() {
+            codec.encode(frame, &mut encoded).unwrap();
+        }
+
+        let mut codec = ClockingCodec::with_limits(
+            MessageAuthor::Client,
+            ConnectionLimits {
+                max_content_size: 512,
+                ..Default::default()
+            },
+        );
+        codec.set_compression(compressing());
+        for _ in 0..2 {
+            codec.decode(&mut encoded).unwrap().unwrap();
+        }
+        assert!(matches!(
+            codec.decode(&mut encoded),
+            Err(ClockingFramingError::DecompressionFailed(
+                ContentEncoding::Deflate
+            ))
+        ));
+    }
+}
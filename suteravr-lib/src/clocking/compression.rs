@@ -0,0 +1,125 @@
+use enum_map::{enum_map, Enum, EnumMap};
+use once_cell::sync::Lazy;
+
+use crate::util::search_from_enum;
+
+/// Contentの圧縮形式です。
+#[derive(Enum, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ContentEncoding {
+    Deflate,
+}
+
+static CONTENT_ENCODING_MAP: Lazy<EnumMap<ContentEncoding, u8>> = Lazy::new(|| {
+    enum_map! {
+        ContentEncoding::Deflate => 0x01,
+    }
+});
+
+impl ContentEncoding {
+    /// この実装が扱える圧縮形式です。前にあるものほど優先されます。
+    pub const SUPPORTED: &'static [ContentEncoding] = &[ContentEncoding::Deflate];
+
+    #[inline]
+    pub fn from_code(code: u8) -> Option<Self> {
+        search_from_enum(*CONTENT_ENCODING_MAP, &code)
+    }
+
+    #[inline]
+    pub fn code(&self) -> u8 {
+        CONTENT_ENCODING_MAP[*self]
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ContentEncoding::Deflate => miniz_oxide::deflate::compress_to_vec(data, 6),
+        }
+    }
+
+    /// 展開します。展開後の大きさが`limit`を超える場合や、壊れている場合は`None`を返します。
+    pub fn decompress(&self, data: &[u8], limit: usize) -> Option<Vec<u8>> {
+        match self {
+            ContentEncoding::Deflate => {
+                miniz_oxide::inflate::decompress_to_vec_with_limit(data, limit).ok()
+            }
+        }
+    }
+}
+
+/// Contentの圧縮に関する設定です。
+///
+/// 相手が圧縮に対応しているとは限らないので、既定ではどちらも無効になっています。
+/// 交渉が済んだ時点で、[`ClockingConnection::set_compression`][super::ClockingConnection::set_compression]で有効にしてください。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ContentCompression {
+    /// 圧縮されたContentを受け付けるか
+    pub accept: bool,
+    /// 送信するContentを圧縮する形式
+    pub send: Option<ContentEncoding>,
+    /// これより小さいContentは圧縮せずに送る
+    pub min_size: usize,
+}
+
+impl ContentCompression {
+    pub const DEFAULT_MIN_SIZE: usize = 256;
+}
+
+impl Default for ContentCompression {
+    fn default() -> Self {
+        Self {
+            accept: false,
+            send: None,
+            min_size: Self::DEFAULT_MIN_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn encoding_code() {
+        for encoding in ContentEncoding::SUPPORTED {
+            assert_eq!(ContentEncoding::from_code(encoding.code()), Some(*encoding));
+        }
+        assert_eq!(ContentEncoding::from_code(0xff), None);
+    }
+
+    #[test]
+    fn deflate_reflective() {
+        let data = b"SuteraVR".repeat(128);
+        let compressed = ContentEncoding::Deflate.compress(&data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(
+            ContentEncoding::Deflate.decompress(&compressed, data.len()),
+            Some(data)
+        );
+    }
+
+    #[test]
+    fn deflate_limit() {
+        let data = [0u8; 4096];
+        let compressed = ContentEncoding::Deflate.compress(&data);
+        assert_eq!(ContentEncoding::Deflate.decompress(&compressed, 4095), None);
+        assert_eq!(ContentEncoding::Deflate.decompress(b"broken", 4096), None);
+    }
+
+    #[test]
+    fn choose_supported_encoding() {
+        use crate::clocking::schemas::oneshot::compression::CompressionRequest;
+
+        assert_eq!(
+            CompressionRequest::supported().choose(),
+            Some(ContentEncoding::Deflate)
+        );
+        assert_eq!(
+            CompressionRequest {
+                encodings: vec![0xff]
+            }
+            .choose(),
+            None
+        );
+    }
+}
@@ -1,12 +1,13 @@
-use bytes::Buf;
+use bytes::{Buf, Bytes};
 use derivative::Derivative;
 use derive_new::new;
 use enum_map::enum_map;
 use enum_map::{Enum, EnumMap};
 use once_cell::sync::Lazy;
 
-use crate::util::search_from_enum;
+use crate::{messaging::version::VersionCompatibility, util::search_from_enum, SCHEMA_VERSION};
 
+use super::messages::EventMessage;
 use super::sutera_header::SuteraHeader;
 use super::sutera_status::SuteraStatus;
 use super::traits::{ClockingFrame, MessageAuthor};
@@ -57,10 +58,71 @@ pub static EVENT_DIRECTION_MAP: Lazy<
     }
 });
 
+/// ヘッダーに書かれたEventの種類です。
+///
+/// 知らない種類のコードを受け取っても、メッセージを読み捨てずに済むよう`Unknown`として保持します。
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum EventMessageType {
+    Known(EventTypes),
+    Unknown([u8; EventHeader::MESSAGE_TYPE_DISTINCTOR_SIZE]),
+}
+
+impl EventMessageType {
+    #[inline]
+    pub fn from_code(code: [u8; EventHeader::MESSAGE_TYPE_DISTINCTOR_SIZE]) -> Self {
+        match search_from_enum(*EVENT_TYPES_MAP, &code) {
+            Some(known) => Self::Known(known),
+            None => Self::Unknown(code),
+        }
+    }
+
+    #[inline]
+    pub fn code(&self) -> [u8; EventHeader::MESSAGE_TYPE_DISTINCTOR_SIZE] {
+        match self {
+            Self::Known(known) => EVENT_TYPES_MAP[*known],
+            Self::Unknown(code) => *code,
+        }
+    }
+
+    #[inline]
+    pub fn known(&self) -> Option<EventTypes> {
+        match self {
+            Self::Known(known) => Some(*known),
+            Self::Unknown(_) => None,
+        }
+    }
+
+    /// 種類が分かっている場合のみ、その方向を返します。
+    #[inline]
+    pub fn direction(&self) -> Option<EventDirection> {
+        self.known().map(|known| EVENT_TYPES_DIRECTION_MAP[known])
+    }
+
+    /// 型で指定したメッセージの種類と一致するかを返します。
+    #[inline]
+    pub fn is<M: EventMessage>(&self) -> bool {
+        *self == M::TYPE
+    }
+}
+
+impl From<EventTypes> for EventMessageType {
+    #[inline]
+    fn from(value: EventTypes) -> Self {
+        Self::Known(value)
+    }
+}
+
+impl PartialEq<EventTypes> for EventMessageType {
+    #[inline]
+    fn eq(&self, other: &EventTypes) -> bool {
+        self.known() == Some(*other)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct EventHeader {
     pub direction: EventDirection,
-    pub message_type: EventTypes,
+    pub message_type: EventMessageType,
 }
 
 impl EventHeader {
@@ -87,8 +149,9 @@ impl ClockingFrame for EventHeader {
                     cursor.get_u8(),
                     cursor.get_u8(),
                 ];
-                let message_type = search_from_enum(*EVENT_TYPES_MAP, &message_type)?;
-                if EVENT_TYPES_DIRECTION_MAP[message_type] != dir {
+                let message_type = EventMessageType::from_code(message_type);
+                // 知らない種類の場合は方向を確かめようがないので、そのまま受け付ける
+                if message_type.direction().is_some_and(|d| d != dir) {
                     return None;
                 }
 
@@ -117,9 +180,7 @@ impl ClockingFrame for EventHeader {
         stream
             .write_all(&EVENT_DIRECTION_MAP[self.direction])
             .await?;
-        stream
-            .write_all(&EVENT_TYPES_MAP[self.message_type])
-            .await?;
+        stream.write_all(&self.message_type.code()).await?;
         Ok(())
     }
 }
@@ -129,13 +190,81 @@ impl ClockingFrame for EventHeader {
 pub struct EventRequest {
     pub sutera_header: SuteraHeader,
     pub event_header: EventHeader,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
+
+impl EventRequest {
+    /// イベントのスキーマバージョンが、サーバーのものと互換性があるかを調べます。
+    #[inline]
+    pub fn version_compatibility(&self) -> VersionCompatibility {
+        SCHEMA_VERSION.compatibility_with(&self.sutera_header.version)
+    }
+}
+
 #[derive(Derivative, new)]
 #[derivative(Debug)]
 pub struct EventResponse {
     pub sutera_header: SuteraHeader,
     pub sutera_status: SuteraStatus,
     pub event_header: EventHeader,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clocking::traits::test_util::{
+        encode, test_clockingframe_mustfail, test_clockingframe_reflective,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn clockingserver_event_header() {
+        test_clockingframe_reflective(
+            EventHeader {
+                direction: EventDirection::Push,
+                message_type: EventTypes::Instance_PlayerJoined_Push.into(),
+            },
+            MessageAuthor::Server,
+        )
+        .await;
+        test_clockingframe_reflective(
+            EventHeader {
+                direction: EventDirection::Pull,
+                message_type: EventTypes::Instance_PubPlayerMove_Pull.into(),
+            },
+            MessageAuthor::Client,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn clockingserver_event_header_mismatch_direction() {
+        let payload = encode(
+            &EventHeader {
+                direction: EventDirection::Pull,
+                message_type: EventTypes::Instance_PlayerJoined_Push.into(),
+            },
+            &MessageAuthor::Client,
+        )
+        .await;
+        test_clockingframe_mustfail::<EventHeader>(
+            &payload,
+            &MessageAuthor::Client,
+            Some(EventHeader::MIN_FRAME_SIZE),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn clockingserver_event_header_unknown_type() {
+        test_clockingframe_reflective(
+            EventHeader {
+                direction: EventDirection::Pull,
+                message_type: EventMessageType::Unknown([0x7f, 0xff, 0x00, 0x01]),
+            },
+            MessageAuthor::Client,
+        )
+        .await;
+    }
 }
@@ -0,0 +1,354 @@
+//! インスタンスに参加するためのトークンです。
+//!
+//! Balancing-serverが秘密鍵(Ed25519)で署名して発行し、Clocking-serverは公開鍵だけで検証します。
+//! トークンには、誰が([`JoinClaims::user`])、どのインスタンスに([`JoinClaims::instance`])、
+//! いつまで([`JoinClaims::expires_at`])参加できるかが含まれます。
+//!
+//! ```
+//! use std::time::Duration;
+//! use suteravr_lib::clocking::join_token::{JoinTokenIssuer, JoinTokenVerifier};
+//!
+//! let issuer = JoinTokenIssuer::from_pkcs8(&JoinTokenIssuer::generate_pkcs8().unwrap()).unwrap();
+//! let verifier = JoinTokenVerifier::new(issuer.public_key());
+//!
+//! let token = issuer.issue_for("alice", 0x01, Duration::from_secs(60));
+//! let claims = verifier.verify_now(&token, 0x01).unwrap();
+//! assert_eq!(claims.user, "alice");
+//! ```
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+};
+use thiserror::Error;
+
+use crate::{
+    clocking::{
+        schemas::oneshot::error_details::{ErrorDetails, RetryHint},
+        sutera_status::SuteraStatusError,
+    },
+    messaging::id::InstanceId,
+};
+
+/// トークンの形式のバージョンです。形式を変えたら上げてください。
+const TOKEN_VERSION: u8 = 1;
+/// 他の用途の署名と取り違えないよう、署名する内容の前に付けます。
+const SIGNATURE_CONTEXT: &[u8] = b"SuteraVR join token\0";
+/// バージョン(1) + インスタンスID(8) + 有効期限(8)
+const CLAIMS_HEADER_SIZE: usize = 1 + 8 + 8;
+
+/// トークンに含まれる内容です。
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct JoinClaims {
+    /// 参加するユーザーです。Balancing-serverが認証したものを入れます。
+    pub user: String,
+    pub instance: InstanceId,
+    /// UNIX時間(秒)で、この時刻以降は使えません。
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum JoinTokenError {
+    #[error("The join token is malformed")]
+    Malformed,
+    #[error("The signature of the join token is invalid")]
+    BadSignature,
+    #[error("The join token has expired at {expires_at}")]
+    Expired { expires_at: u64 },
+    #[error("The join token is for instance {token:?}, not {requested:?}")]
+    WrongInstance {
+        token: InstanceId,
+        requested: InstanceId,
+    },
+    #[error("The key for join tokens is invalid")]
+    InvalidKey,
+}
+
+impl JoinTokenError {
+    /// クライアントに返すステータスと詳細です。
+    pub fn to_status(&self) -> (SuteraStatusError, ErrorDetails) {
+        match self {
+            JoinTokenError::Malformed
+            | JoinTokenError::BadSignature
+            | JoinTokenError::InvalidKey => (
+                SuteraStatusError::Unauthorized,
+                ErrorDetails::new("login.bad_token", "The join token is invalid.")
+                    .with_retry(RetryHint::AfterReauthentication),
+            ),
+            JoinTokenError::Expired { .. } => (
+                SuteraStatusError::AuthenticationHasBeenExpired,
+                ErrorDetails::new("login.token_expired", "The join token has expired.")
+                    .with_retry(RetryHint::AfterReauthentication),
+            ),
+            JoinTokenError::WrongInstance { .. } => (
+                SuteraStatusError::Forbidden,
+                ErrorDetails::new(
+                    "login.wrong_instance",
+                    "The join token is not for this instance.",
+                ),
+            ),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl JoinClaims {
+    /// 今から`ttl`の間だけ有効な内容を作ります。`Duration::MAX`などの長すぎる`ttl`は、期限なしとみなします。
+    pub fn new(user: impl Into<String>, instance: InstanceId, ttl: Duration) -> Self {
+        Self {
+            user: user.into(),
+            instance,
+            expires_at: unix_now().saturating_add(ttl.as_secs()),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CLAIMS_HEADER_SIZE + self.user.len());
+        bytes.push(TOKEN_VERSION);
+        bytes.extend_from_slice(&self.instance.to_be_bytes());
+        bytes.extend_from_slice(&self.expires_at.to_be_bytes());
+        bytes.extend_from_slice(self.user.as_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, JoinTokenError> {
+        if bytes.len() < CLAIMS_HEADER_SIZE || bytes[0] != TOKEN_VERSION {
+            return Err(JoinTokenError::Malformed);
+        }
+        let u64_at = |at: usize| u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+        Ok(Self {
+            instance: u64_at(1),
+            expires_at: u64_at(9),
+            user: String::from_utf8(bytes[CLAIMS_HEADER_SIZE..].to_vec())
+                .map_err(|_| JoinTokenError::Malformed)?,
+        })
+    }
+}
+
+fn signed_message(claims: &[u8]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, claims].concat()
+}
+
+/// トークンを発行します。Balancing-serverで使います。
+pub struct JoinTokenIssuer {
+    key_pair: Ed25519KeyPair,
+}
+
+impl JoinTokenIssuer {
+    /// 新しい秘密鍵をPKCS#8(DER)で作ります。
+    pub fn generate_pkcs8() -> Result<Vec<u8>, JoinTokenError> {
+        Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map(|pkcs8| pkcs8.as_ref().to_vec())
+            .map_err(|_| JoinTokenError::InvalidKey)
+    }
+
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, JoinTokenError> {
+        Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map(|key_pair| Self { key_pair })
+            .map_err(|_| JoinTokenError::InvalidKey)
+    }
+
+    /// [`JoinTokenVerifier::new`]に渡す公開鍵です。
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    /// [`JoinTokenVerifier::from_base64`]に渡せる形の公開鍵です。Clocking-serverの設定に書きます。
+    pub fn public_key_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.public_key())
+    }
+
+    pub fn issue(&self, claims: &JoinClaims) -> String {
+        let claims = claims.encode();
+        let signature = self.key_pair.sign(&signed_message(&claims));
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(claims),
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        )
+    }
+
+    /// 今から`ttl`の間だけ使えるトークンを発行します。
+    pub fn issue_for(
+        &self,
+        user: impl Into<String>,
+        instance: InstanceId,
+        ttl: Duration,
+    ) -> String {
+        self.issue(&JoinClaims::new(user, instance, ttl))
+    }
+}
+
+/// トークンを検証します。Clocking-serverで使います。
+#[derive(Debug, Clone)]
+pub struct JoinTokenVerifier {
+    public_key: UnparsedPublicKey<Vec<u8>>,
+}
+
+impl JoinTokenVerifier {
+    /// `public_key`はEd25519の公開鍵(32バイト)です。
+    pub fn new(public_key: impl Into<Vec<u8>>) -> Self {
+        Self {
+            public_key: UnparsedPublicKey::new(&ED25519, public_key.into()),
+        }
+    }
+
+    /// Base64で書かれた公開鍵から作ります。設定ファイルや環境変数から読み込む場合に使います。
+    pub fn from_base64(public_key: &str) -> Result<Self, JoinTokenError> {
+        let public_key = base64::engine::general_purpose::STANDARD
+            .decode(public_key.trim())
+            .map_err(|_| JoinTokenError::InvalidKey)?;
+        if public_key.len() != 32 {
+            return Err(JoinTokenError::InvalidKey);
+        }
+        Ok(Self::new(public_key))
+    }
+
+    /// `token`が`instance`に参加するためのもので、`now`(UNIX時間の秒)の時点で有効かを確かめます。
+    pub fn verify(
+        &self,
+        token: &str,
+        instance: InstanceId,
+        now: u64,
+    ) -> Result<JoinClaims, JoinTokenError> {
+        let (claims, signature) = token.split_once('.').ok_or(JoinTokenError::Malformed)?;
+        let claims = URL_SAFE_NO_PAD
+            .decode(claims)
+            .map_err(|_| JoinTokenError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| JoinTokenError::Malformed)?;
+        self.public_key
+            .verify(&signed_message(&claims), &signature)
+            .map_err(|_| JoinTokenError::BadSignature)?;
+
+        let claims = JoinClaims::decode(&claims)?;
+        if now >= claims.expires_at {
+            return Err(JoinTokenError::Expired {
+                expires_at: claims.expires_at,
+            });
+        }
+        if claims.instance != instance {
+            return Err(JoinTokenError::WrongInstance {
+                token: claims.instance,
+                requested: instance,
+            });
+        }
+        Ok(claims)
+    }
+
+    #[inline]
+    pub fn verify_now(
+        &self,
+        token: &str,
+        instance: InstanceId,
+    ) -> Result<JoinClaims, JoinTokenError> {
+        self.verify(token, instance, unix_now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    use super::*;
+
+    fn issuer() -> JoinTokenIssuer {
+        JoinTokenIssuer::from_pkcs8(&JoinTokenIssuer::generate_pkcs8().unwrap()).unwrap()
+    }
+
+    fn claims() -> JoinClaims {
+        JoinClaims {
+            user: "アリス".to_string(),
+            instance: 0x01,
+            expires_at: 1_000,
+        }
+    }
+
+    #[test]
+    fn issue_and_verify() {
+        let issuer = issuer();
+        let verifier = JoinTokenVerifier::new(issuer.public_key());
+        let token = issuer.issue(&claims());
+        assert_eq!(verifier.verify(&token, 0x01, 999), Ok(claims()));
+    }
+
+    #[test]
+    fn never_expires() {
+        assert_eq!(
+            JoinClaims::new("アリス", 0x01, Duration::MAX).expires_at,
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn public_key_from_base64() {
+        let issuer = issuer();
+        let verifier = JoinTokenVerifier::from_base64(&issuer.public_key_base64()).unwrap();
+        verifier.verify(&issuer.issue(&claims()), 0x01, 0).unwrap();
+
+        assert_eq!(
+            JoinTokenVerifier::from_base64("c3V0ZXJh").unwrap_err(),
+            JoinTokenError::InvalidKey
+        );
+    }
+
+    #[rstest]
+    #[case::expired(0x01, 1_000, JoinTokenError::Expired { expires_at: 1_000 }, SuteraStatusError::AuthenticationHasBeenExpired)]
+    #[case::wrong_instance(0x02, 999, JoinTokenError::WrongInstance { token: 0x01, requested: 0x02 }, SuteraStatusError::Forbidden)]
+    fn reject_valid_signature(
+        #[case] instance: InstanceId,
+        #[case] now: u64,
+        #[case] expected: JoinTokenError,
+        #[case] status: SuteraStatusError,
+    ) {
+        let issuer = issuer();
+        let verifier = JoinTokenVerifier::new(issuer.public_key());
+        let error = verifier
+            .verify(&issuer.issue(&claims()), instance, now)
+            .unwrap_err();
+        assert_eq!(error, expected);
+        assert_eq!(error.to_status().0, status);
+    }
+
+    #[test]
+    fn reject_forged_token() {
+        let issuer = issuer();
+        let verifier = JoinTokenVerifier::new(issuer.public_key());
+
+        // 他の鍵で署名されたもの
+        let token = self::issuer().issue(&claims());
+        assert_eq!(
+            verifier.verify(&token, 0x01, 0),
+            Err(JoinTokenError::BadSignature)
+        );
+
+        // 署名はそのままで、有効期限を書き換えたもの
+        let token = issuer.issue(&claims());
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = JoinClaims {
+            expires_at: u64::MAX,
+            ..claims()
+        };
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode(forged.encode()), signature);
+        assert_eq!(
+            verifier.verify(&forged, 0x01, 0),
+            Err(JoinTokenError::BadSignature)
+        );
+
+        for malformed in ["", "abc", "abc.def", "!!!.???"] {
+            let error = verifier.verify(malformed, 0x01, 0).unwrap_err();
+            assert_eq!(error.to_status().0, SuteraStatusError::Unauthorized);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Oneshot・Eventの種類と、そのペイロードのスキーマを結びつけるレジストリです。
+//!
+//! `message_type`を手で照合してから`deserialize::<X, X>`を呼ぶ代わりに、
+//! ここで宣言したマーカー型を通して送受信することで、ペイロードの取り違えをコンパイル時に検出できます。
+//!
+//! 新しいメッセージを追加する場合は、`OneshotTypes`/`EventTypes`に種類を追加した上で、
+//! `oneshot_messages!`もしくは`event_messages!`に一行追加してください。
+
+use alkahest::{deserialize, Deserialize, DeserializeError, Formula, Serialize};
+use bytes::Bytes;
+
+use crate::{messaging::id::MessageId, util::serialize_to_new_vec};
+
+use super::{
+    event_headers::{EventHeader, EventTypes, EVENT_TYPES_DIRECTION_MAP},
+    oneshot_headers::{OneshotHeader, OneshotStep, OneshotTypes},
+    schemas::{
+        event::{
+            player_move::{PubPlayerMove, PushPlayerMove},
+            update_player_being::{PlayerJoined, PlayerLeft},
+        },
+        oneshot::{
+            chat_entry::{SendChatMessageRequest, SendChatMessageResponse, SendableChatEntry},
+            compression::{CompressionRequest, CompressionResponse},
+            login::{LoginRequest, LoginResponse, ResumeRequest, ResumeResponse},
+        },
+    },
+};
+
+/// Oneshotの種類と、そのリクエスト・レスポンスのスキーマを結びつけるトレイトです。
+pub trait OneshotMessage {
+    const TYPE: OneshotTypes;
+    type Request: Formula + Serialize<Self::Request> + for<'de> Deserialize<'de, Self::Request>;
+    type Response: Formula + Serialize<Self::Response> + for<'de> Deserialize<'de, Self::Response>;
+
+    #[inline]
+    fn request_header(message_id: MessageId) -> OneshotHeader {
+        OneshotHeader {
+            step: OneshotStep::Request,
+            message_type: Self::TYPE.into(),
+            message_id,
+        }
+    }
+
+    #[inline]
+    fn response_header(message_id: MessageId) -> OneshotHeader {
+        OneshotHeader {
+            step: OneshotStep::Response,
+            message_type: Self::TYPE.into(),
+            message_id,
+        }
+    }
+
+    #[inline]
+    fn encode_request(request: Self::Request) -> Bytes {
+        serialize_to_new_vec(request).into()
+    }
+
+    #[inline]
+    fn decode_request(payload: &[u8]) -> Result<Self::Request, DeserializeError> {
+        deserialize::<Self::Request, Self::Request>(payload)
+    }
+
+    #[inline]
+    fn encode_response(response: Self::Response) -> Bytes {
+        serialize_to_new_vec(response).into()
+    }
+
+    #[inline]
+    fn decode_response(payload: &[u8]) -> Result<Self::Response, DeserializeError> {
+        deserialize::<Self::Response, Self::Response>(payload)
+    }
+}
+
+/// Eventの種類と、そのペイロードのスキーマを結びつけるトレイトです。
+pub trait EventMessage {
+    const TYPE: EventTypes;
+    type Payload: Formula + Serialize<Self::Payload> + for<'de> Deserialize<'de, Self::Payload>;
+
+    /// 種類に応じた方向を持つヘッダーを返します。
+    #[inline]
+    fn header() -> EventHeader {
+        EventHeader {
+            direction: EVENT_TYPES_DIRECTION_MAP[Self::TYPE],
+            message_type: Self::TYPE.into(),
+        }
+    }
+
+    #[inline]
+    fn encode(payload: Self::Payload) -> Bytes {
+        serialize_to_new_vec(payload).into()
+    }
+
+    #[inline]
+    fn decode(payload: &[u8]) -> Result<Self::Payload, DeserializeError> {
+        deserialize::<Self::Payload, Self::Payload>(payload)
+    }
+}
+
+macro_rules! oneshot_messages {
+    ($($(#[$meta:meta])* $name:ident: $ty:ident => ($req:ty, $res:ty);)*) => {
+        $(
+            $(#[$meta])*
+            pub enum $name {}
+
+            impl OneshotMessage for $name {
+                const TYPE: OneshotTypes = OneshotTypes::$ty;
+                type Request = $req;
+                type Response = $res;
+            }
+        )*
+    };
+}
+
+macro_rules! event_messages {
+    ($($(#[$meta:meta])* $name:ident: $ty:ident => $payload:ty;)*) => {
+        $(
+            $(#[$meta])*
+            pub enum $name {}
+
+            impl EventMessage for $name {
+                const TYPE: EventTypes = EventTypes::$ty;
+                type Payload = $payload;
+            }
+        )*
+    };
+}
+
+oneshot_messages! {
+    /// サーバーからの死活確認です。
+    HealthCheckPushOneshot: Connection_HealthCheck_Push => ((), ());
+    /// クライアントからの死活確認です。
+    HealthCheckPullOneshot: Connection_HealthCheck_Pull => ((), ());
+    /// Contentの圧縮形式の交渉です。
+    NegotiateCompressionOneshot: Connection_Compression_Pull => (CompressionRequest, CompressionResponse);
+    LoginOneshot: Authentication_Login_Pull => (LoginRequest, LoginResponse);
+    /// 切れた接続のプレイヤーを、新しい接続で引き継ぎます。
+    ResumeOneshot: Authentication_Resume_Pull => (ResumeRequest, ResumeResponse);
+    SendChatMessageOneshot: TextChat_SendMessage_Pull => (SendChatMessageRequest, SendChatMessageResponse);
+}
+
+event_messages! {
+    PlayerJoinedEvent: Instance_PlayerJoined_Push => PlayerJoined;
+    PlayerLeftEvent: Instance_PlayerLeft_Push => PlayerLeft;
+    PubPlayerMoveEvent: Instance_PubPlayerMove_Pull => PubPlayerMove;
+    PushPlayerMoveEvent: Instance_PushPlayerMove_Push => PushPlayerMove;
+    ReceiveChatMessageEvent: TextChat_ReceiveChatMessage_Push => SendableChatEntry;
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::clocking::{
+        event_headers::EventDirection, oneshot_headers::OneshotMessageType, traits::MessageAuthor,
+    };
+
+    use super::*;
+
+    #[test]
+    fn typed_headers() {
+        assert_eq!(
+            LoginOneshot::request_header(0x1234),
+            OneshotHeader {
+                step: OneshotStep::Request,
+                message_type: OneshotTypes::Authentication_Login_Pull.into(),
+                message_id: 0x1234,
+            }
+        );
+        assert_eq!(
+            HealthCheckPushOneshot::response_header(0x1234).step,
+            OneshotStep::Response
+        );
+        assert_eq!(PubPlayerMoveEvent::header().direction, EventDirection::Pull);
+        assert_eq!(PlayerJoinedEvent::header().direction, EventDirection::Push);
+    }
+
+    #[test]
+    fn match_message_type() {
+        let login: OneshotMessageType = OneshotTypes::Authentication_Login_Pull.into();
+        assert!(login.is::<LoginOneshot>());
+        assert!(!login.is::<SendChatMessageOneshot>());
+        assert!(!OneshotMessageType::Unknown([0xff; 4]).is::<LoginOneshot>());
+        assert!(PlayerLeftEvent::header()
+            .message_type
+            .is::<PlayerLeftEvent>());
+    }
+
+    #[tokio::test]
+    async fn typed_headers_are_parsable() {
+        use crate::clocking::traits::test_util::test_clockingframe_reflective;
+
+        test_clockingframe_reflective(LoginOneshot::request_header(1), MessageAuthor::Client).await;
+        test_clockingframe_reflective(LoginOneshot::response_header(1), MessageAuthor::Server)
+            .await;
+        test_clockingframe_reflective(PushPlayerMoveEvent::header(), MessageAuthor::Server).await;
+        test_clockingframe_reflective(PubPlayerMoveEvent::header(), MessageAuthor::Client).await;
+    }
+}
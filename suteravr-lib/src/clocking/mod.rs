@@ -1,22 +1,30 @@
-use std::{
-    io::{self, Cursor},
-    mem::size_of,
-};
+use std::io;
 
-use bytes::{Buf, BytesMut};
+use bytes::{Bytes, BytesMut};
 use futures::{future::BoxFuture, FutureExt};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-use crate::clocking::traits::ClockingFrame;
+use tokio_util::codec::{Decoder, Encoder};
 
 use self::{
-    event_headers::EventHeader, oneshot_headers::OneshotHeader, sutera_status::SuteraStatus,
+    buffer::ContentHeader,
+    capture::{CaptureDirection, FrameCapture},
+    codec::ClockingCodec,
+    compression::{ContentCompression, ContentEncoding},
+    sutera_header::SuteraHeader,
+    sutera_status::SuteraStatus,
     traits::MessageAuthor,
 };
 
 pub mod buffer;
+pub mod capture;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod codec;
+pub mod compression;
 pub mod event_headers;
+pub mod join_token;
+pub mod messages;
 pub mod oneshot_headers;
 pub mod schema_snapshot;
 pub mod schemas;
@@ -30,14 +38,48 @@ pub enum ClockingFramingError {
     IoError(#[from] std::io::Error),
     #[error("Connection reset by peer")]
     ConnectionReset,
+    #[error("Content length {length} exceeds the limit of {limit} byte(s)")]
+    ContentTooLarge { length: u64, limit: usize },
+    #[error("Buffered {buffered} byte(s) without completing a frame (limit: {limit})")]
+    BufferLimitExceeded { buffered: usize, limit: usize },
+    #[error("Received {received} unfragmented byte(s) in a row (limit: {limit})")]
+    TooManyUnfragmentedBytes { received: usize, limit: usize },
+    #[error("Failed to decompress the content encoded with {0:?}")]
+    DecompressionFailed(ContentEncoding),
+}
+
+/// [`ClockingConnection`]が1接続あたりに許容するデータ量の上限です。
+///
+/// 公開されたサーバーで、相手が巨大なContentの長さを送りつけたり、
+/// フレームとして解釈できないデータを送り続けたりして、メモリを食いつぶされないようにするために使います。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ConnectionLimits {
+    /// 1つのContentフレームとして受け付ける最大のバイト数
+    pub max_content_size: usize,
+    /// フレームが成立しないまま、受信バッファに溜めておける最大のバイト数
+    ///
+    /// `max_content_size`より小さいと、上限ぎりぎりのContentを受け取れなくなることに注意してください。
+    pub max_buffered_bytes: usize,
+    /// SuteraHeaderが見つからないまま、Unfragmentedとして読み捨てられる最大のバイト数
+    ///
+    /// この値を超えた場合、相手は壊れているとみなして接続を諦めます。
+    pub max_unfragmented_bytes: usize,
 }
 
-enum ConnectionContext {
-    None,
-    Unfragmented(usize),
-    WaitStatus,
-    WaitMessageType,
-    WaitContent,
+impl ConnectionLimits {
+    pub const DEFAULT_MAX_CONTENT_SIZE: usize = 1024 * 1024;
+    pub const DEFAULT_MAX_BUFFERED_BYTES: usize = 2 * 1024 * 1024;
+    pub const DEFAULT_MAX_UNFRAGMENTED_BYTES: usize = 64 * 1024;
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_content_size: Self::DEFAULT_MAX_CONTENT_SIZE,
+            max_buffered_bytes: Self::DEFAULT_MAX_BUFFERED_BYTES,
+            max_unfragmented_bytes: Self::DEFAULT_MAX_UNFRAGMENTED_BYTES,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -46,15 +88,16 @@ pub enum ClockingFrameUnit {
     SuteraStatus(sutera_status::SuteraStatus),
     OneshotHeaders(oneshot_headers::OneshotHeader),
     EventHeader(event_headers::EventHeader),
-    Content(Vec<u8>),
-    Unfragmented(Vec<u8>),
+    Content(Bytes),
+    Unfragmented(Bytes),
 }
 
 pub struct ClockingConnection<W: AsyncReadExt + AsyncWriteExt + Unpin + Send> {
     stream: W,
     buffer: BytesMut,
-    author: MessageAuthor,
-    context: ConnectionContext,
+    write_buffer: BytesMut,
+    codec: ClockingCodec,
+    capture: Option<FrameCapture>,
 }
 impl<W: AsyncReadExt + AsyncWriteExt + Unpin + Send> ClockingConnection<W> {
     /// 既存のストリームから新しいClockingConnectionを作成します。
@@ -62,11 +105,60 @@ impl<W: AsyncReadExt + AsyncWriteExt + Unpin + Send> ClockingConnection<W> {
     /// **authorには、名前の通り「メッセージの送信者」が格納されることに注意してください。**
     /// たとえば、サーバー側で動いている場合は、[`MessageAuthor::Client`]を`author`に指定する必要があります。`
     pub fn new(stream: W, author: MessageAuthor) -> Self {
+        Self::with_limits(stream, author, ConnectionLimits::default())
+    }
+
+    /// 受信するデータ量の上限を指定して、新しいClockingConnectionを作成します。
+    ///
+    /// `author`については[`ClockingConnection::new`]を参照してください。
+    pub fn with_limits(stream: W, author: MessageAuthor, limits: ConnectionLimits) -> Self {
         Self {
             stream,
-            author,
             buffer: BytesMut::with_capacity(4096),
-            context: ConnectionContext::None,
+            write_buffer: BytesMut::with_capacity(4096),
+            codec: ClockingCodec::with_limits(author, limits),
+            capture: None,
+        }
+    }
+
+    #[inline]
+    pub fn author(&self) -> MessageAuthor {
+        self.codec.author()
+    }
+
+    #[inline]
+    pub fn limits(&self) -> &ConnectionLimits {
+        self.codec.limits()
+    }
+
+    #[inline]
+    pub fn compression(&self) -> &ContentCompression {
+        self.codec.compression()
+    }
+
+    /// Contentの圧縮の設定を変更します。
+    ///
+    /// 相手が圧縮に対応していることを確かめてから、`send`を有効にしてください。
+    #[inline]
+    pub fn set_compression(&mut self, compression: ContentCompression) {
+        self.codec.set_compression(compression);
+    }
+
+    /// 読み書きしたフレームを記録するタップを取り付けます。`None`を渡すと取り外します。
+    ///
+    /// `capture`は、このコネクションと同じ`author`で作成してください。
+    #[inline]
+    pub fn set_capture(&mut self, capture: Option<FrameCapture>) {
+        if let Some(capture) = &capture {
+            debug_assert_eq!(capture.author(), self.author());
+        }
+        self.capture = capture;
+    }
+
+    #[inline]
+    fn capture(&self, direction: CaptureDirection, frame: &ClockingFrameUnit) {
+        if let Some(capture) = &self.capture {
+            capture.record(direction, frame);
         }
     }
 
@@ -78,28 +170,45 @@ impl<W: AsyncReadExt + AsyncWriteExt + Unpin + Send> ClockingConnection<W> {
         &mut self,
         frame: &ClockingFrameUnit,
     ) -> Result<(), ClockingFramingError> {
-        match frame {
-            ClockingFrameUnit::SuteraHeader(header) => {
-                header.write_frame(&mut self.stream, &()).await?;
-            }
-            ClockingFrameUnit::SuteraStatus(status) => {
-                status.write_frame(&mut self.stream, &()).await?;
-            }
-            ClockingFrameUnit::OneshotHeaders(header) => {
-                header.write_frame(&mut self.stream, &self.author).await?;
-            }
-            ClockingFrameUnit::EventHeader(header) => {
-                header.write_frame(&mut self.stream, &self.author).await?;
-            }
-            ClockingFrameUnit::Content(content) => {
-                self.stream.write_u64(content.len() as u64).await?;
-                self.stream.write_u64(content.len() as u64).await?;
-                self.stream.write_all(content).await?;
-            }
-            ClockingFrameUnit::Unfragmented(content) => {
-                self.stream.write_all(content).await?;
-            }
+        self.write_buffer.clear();
+        self.codec.encode(frame, &mut self.write_buffer)?;
+        self.capture(CaptureDirection::Written, frame);
+        self.flush_write_buffer().await
+    }
+
+    /// 1つのメッセージを構成するフレームをまとめて書き込みます。
+    ///
+    /// フレームはすべて1つのバッファにエンコードされ、一度の書き込みとflushで送信されます。
+    /// `sutera_status`は、サーバーからクライアントへ送る場合は必ず指定してください。
+    /// クライアントからサーバーへ送る場合は、Oneshotのレスポンスでのみ指定でき、省略した場合は`Ok`とみなされます。
+    pub async fn write_message(
+        &mut self,
+        sutera_header: SuteraHeader,
+        sutera_status: Option<SuteraStatus>,
+        content_header: ContentHeader,
+        payload: Bytes,
+    ) -> Result<(), ClockingFramingError> {
+        let frames = [
+            Some(ClockingFrameUnit::SuteraHeader(sutera_header)),
+            sutera_status.map(ClockingFrameUnit::SuteraStatus),
+            Some(match content_header {
+                ContentHeader::Oneshot(header) => ClockingFrameUnit::OneshotHeaders(header),
+                ContentHeader::Event(header) => ClockingFrameUnit::EventHeader(header),
+            }),
+            Some(ClockingFrameUnit::Content(payload)),
+        ];
+
+        self.write_buffer.clear();
+        for frame in frames.iter().flatten() {
+            self.codec.encode(frame, &mut self.write_buffer)?;
+            self.capture(CaptureDirection::Written, frame);
         }
+        self.flush_write_buffer().await
+    }
+
+    #[inline]
+    async fn flush_write_buffer(&mut self) -> Result<(), ClockingFramingError> {
+        self.stream.write_all(&self.write_buffer).await?;
         self.stream.flush().await?;
         Ok(())
     }
@@ -111,192 +220,45 @@ impl<W: AsyncReadExt + AsyncWriteExt + Unpin + Send> ClockingConnection<W> {
         &mut self,
     ) -> BoxFuture<'_, Result<Option<ClockingFrameUnit>, ClockingFramingError>> {
         async {
-            loop {
-                if let Some(frame) = self.parse_frame()? {
-                    return Ok(Some(frame));
+            let frame = loop {
+                if let Some(frame) = self.codec.decode(&mut self.buffer)? {
+                    break Some(frame);
                 }
 
                 // read_buf is cancellation safe.
                 if self.stream.read_buf(&mut self.buffer).await? == 0 {
-                    if self.buffer.is_empty() {
-                        return Ok(None);
-                    } else {
-                        return Err(ClockingFramingError::ConnectionReset);
-                    }
+                    break self.codec.decode_eof(&mut self.buffer)?;
                 }
+            };
+            if let Some(frame) = &frame {
+                self.capture(CaptureDirection::Read, frame);
             }
+            Ok(frame)
         }
         .boxed()
     }
-
-    #[inline]
-    fn parse_frame(&mut self) -> Result<Option<ClockingFrameUnit>, ClockingFramingError> {
-        let mut buf = Cursor::new(&self.buffer[..]);
-        match self.context {
-            ConnectionContext::None => {
-                let remaining = buf.remaining();
-
-                // SuteraHeader (通信のはじまりの目印) を探す
-                // この時点で、SuteraHeaderの最小サイズよりも小さい場合は、次のバッファも読む
-                if remaining < sutera_header::SuteraHeader::MIN_FRAME_SIZE {
-                    return Ok(None);
-                }
-
-                // 先頭からSuteraHeaderが成立していれば文句なしでOK
-                buf.set_position(0);
-                if let Some(header) =
-                    sutera_header::SuteraHeader::parse_frame_unchecked(&mut buf, &())
-                {
-                    self.buffer.advance(buf.position() as usize);
-                    self.context = match self.author {
-                        MessageAuthor::Server => ConnectionContext::WaitStatus,
-                        MessageAuthor::Client => ConnectionContext::WaitMessageType,
-                    };
-                    return Ok(Some(ClockingFrameUnit::SuteraHeader(header)));
-                }
-
-                if remaining < sutera_header::SuteraHeader::MAX_FRAME_SIZE {
-                    return Ok(None);
-                }
-
-                // 処理がここまで流れている時点で、
-                // 最後にフレームが成立してから直ちにヘッダーが来ていないから何かがおかしい
-                //
-                // ただ、一応次にどこかでSuteraHeaderが来るかもしれないので、
-                // バッファのどこかにSuteraHeaderを検知できたらそれまでのところをUnfragmentedとする
-                self.context = ConnectionContext::Unfragmented(1);
-                self.parse_frame()
-            }
-            ConnectionContext::Unfragmented(checked_length) => {
-                let remaining = buf.remaining();
-
-                // この時点で、SuteraHeaderの最小サイズよりも小さい場合は、次のバッファも読む
-                if remaining < sutera_header::SuteraHeader::MIN_FRAME_SIZE {
-                    return Ok(None);
-                }
-
-                // 新しく増えた領域にSuteraHeaderが存在しないか確認する
-                let last_possible_index = remaining - sutera_header::SuteraHeader::MIN_FRAME_SIZE;
-                for i in checked_length..=last_possible_index {
-                    buf.set_position(i as u64);
-                    if sutera_header::SuteraHeader::parse_frame_unchecked(&mut buf, &()).is_some() {
-                        self.context = ConnectionContext::None;
-                        if i == 0 {
-                            return self.parse_frame();
-                        } else {
-                            return Ok(Some(ClockingFrameUnit::Unfragmented(
-                                self.buffer.copy_to_bytes(i).to_vec(),
-                            )));
-                        }
-                    }
-                }
-
-                // 認識されていない状態でバッファが増えつづけると危険なので、
-                // 定期的にUnfragmentedとして処理する
-                if remaining > 1024 {
-                    self.context = ConnectionContext::Unfragmented(0);
-                    Ok(Some(ClockingFrameUnit::Unfragmented(
-                        self.buffer.copy_to_bytes(1024).to_vec(),
-                    )))
-                } else {
-                    self.context = ConnectionContext::Unfragmented(checked_length + 1);
-                    Ok(None)
-                }
-            }
-            ConnectionContext::WaitStatus => {
-                buf.set_position(0);
-                if let Some(status) = SuteraStatus::parse_frame(&mut buf, &()) {
-                    self.context = ConnectionContext::WaitMessageType;
-                    self.buffer.advance(buf.position() as usize);
-                    return Ok(Some(ClockingFrameUnit::SuteraStatus(status)));
-                }
-
-                buf.set_position(0);
-                let remaining = buf.remaining();
-                let max_parsable_size = SuteraStatus::MAX_FRAME_SIZE;
-                if remaining >= max_parsable_size {
-                    self.context = ConnectionContext::Unfragmented(0);
-                    return self.parse_frame();
-                }
-
-                Ok(None)
-            }
-            ConnectionContext::WaitMessageType => {
-                buf.set_position(0);
-                if let Some(header) = OneshotHeader::parse_frame(&mut buf, &self.author) {
-                    self.context = ConnectionContext::WaitContent;
-                    self.buffer.advance(buf.position() as usize);
-                    return Ok(Some(ClockingFrameUnit::OneshotHeaders(header)));
-                }
-                buf.set_position(0);
-                if let Some(header) = EventHeader::parse_frame(&mut buf, &self.author) {
-                    self.context = ConnectionContext::WaitContent;
-                    self.buffer.advance(buf.position() as usize);
-                    return Ok(Some(ClockingFrameUnit::EventHeader(header)));
-                }
-
-                buf.set_position(0);
-                let remaining = buf.remaining();
-                let max_parsable_size = OneshotHeader::MAX_FRAME_SIZE;
-                if remaining >= max_parsable_size {
-                    self.context = ConnectionContext::Unfragmented(0);
-                    return self.parse_frame();
-                }
-                Ok(None)
-            }
-            ConnectionContext::WaitContent => {
-                if buf.remaining() <= size_of::<u64>() {
-                    return Ok(None);
-                }
-
-                // 送信するデータの長さを読む
-                // 長さは二回同じものが出力される。
-                // 同じものの場合のみ入力を受け付け、違うものの場合Contentを読んでいないと考えUnfragmentedに
-                let content_length = buf.get_u64();
-                let remaining = buf.remaining();
-                if remaining < size_of::<u64>() {
-                    return if buf.copy_to_bytes(remaining)
-                        != content_length.to_be_bytes()[0..remaining]
-                    {
-                        // 与えられた入力が違う場合はその時点で却下
-                        self.context = ConnectionContext::Unfragmented(0);
-                        self.parse_frame()
-                    } else {
-                        // 二回目の長さが最後まで届いていないが、届いていたところまではあっている場合
-                        // 続きを待つ
-                        Ok(None)
-                    };
-                }
-
-                let content_length_check = buf.get_u64();
-                if content_length != content_length_check {
-                    return Ok(None);
-                }
-
-                if buf.remaining() < content_length as usize {
-                    Ok(None)
-                } else {
-                    let data = buf.copy_to_bytes(content_length as usize);
-                    self.buffer.advance(buf.position() as usize);
-                    self.context = ConnectionContext::None;
-                    Ok(Some(ClockingFrameUnit::Content(data.to_vec())))
-                }
-            }
-        }
-    }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::clocking::buffer::ContentHeader;
+    use crate::clocking::buffer::{FrameBuffer, ReceivePayload};
+    use crate::clocking::oneshot_headers::OneshotHeader;
+    use crate::clocking::oneshot_headers::OneshotMessageType;
     use crate::clocking::oneshot_headers::OneshotStep;
     use crate::clocking::oneshot_headers::OneshotTypes;
-    use crate::clocking::OneshotHeader;
-    use crate::clocking::SuteraStatus;
+    use crate::clocking::sutera_status::{
+        SuteraStatus, SuteraStatusCategory, SuteraStatusError, SuteraStatusWarning,
+    };
+    use crate::util::logger::EnvLogger;
+    use bytes::Bytes;
     use rstest::*;
     use std::io::{Cursor, Write};
 
     use crate::clocking::traits::test_util::encode;
-    use crate::clocking::{ClockingConnection, ClockingFrameUnit};
+    use crate::clocking::{
+        ClockingConnection, ClockingFrameUnit, ClockingFramingError, ConnectionLimits,
+    };
     use crate::{clocking::sutera_header::SuteraHeader, messaging::version::Version};
     use pretty_assertions::assert_eq;
 
@@ -352,7 +314,9 @@ mod test {
         );
         assert_eq!(
             connection.read_frame().await.unwrap(),
-            Some(ClockingFrameUnit::Unfragmented(inject.into()))
+            Some(ClockingFrameUnit::Unfragmented(Bytes::copy_from_slice(
+                inject
+            )))
         );
         assert_eq!(
             connection.read_frame().await.unwrap(),
@@ -383,7 +347,7 @@ mod test {
                 MessageAuthor::Server => OneshotStep::Response,
             },
             message_id: 0x1234,
-            message_type: OneshotTypes::Authentication_Login_Pull,
+            message_type: OneshotTypes::Authentication_Login_Pull.into(),
         };
 
         let payload = b"Wao!";
@@ -421,7 +385,315 @@ mod test {
         );
         assert_eq!(
             connection.read_frame().await.unwrap(),
-            Some(ClockingFrameUnit::Content(payload.into()))
+            Some(ClockingFrameUnit::Content(Bytes::from_static(payload)))
+        );
+    }
+
+    async fn write_request_prefix(vec: &mut Cursor<Vec<u8>>) -> SuteraHeader {
+        let header = SuteraHeader {
+            version: Version {
+                major: 0,
+                minor: 1,
+                patch: 0,
+            },
+        };
+        let oneshot_header = OneshotHeader {
+            step: OneshotStep::Request,
+            message_id: 0x1234,
+            message_type: OneshotTypes::Authentication_Login_Pull.into(),
+        };
+        vec.write_all(&encode(&header, &()).await).unwrap();
+        vec.write_all(&encode(&oneshot_header, &MessageAuthor::Client).await)
+            .unwrap();
+        header
+    }
+
+    #[tokio::test]
+    async fn reject_too_large_content() {
+        let mut vec = Cursor::new(Vec::<u8>::new());
+        write_request_prefix(&mut vec).await;
+        vec.write_all(&0x1000u64.to_be_bytes()).unwrap();
+        vec.write_all(&0x1000u64.to_be_bytes()).unwrap();
+
+        vec.set_position(0);
+        let mut connection = ClockingConnection::with_limits(
+            &mut vec,
+            MessageAuthor::Client,
+            ConnectionLimits {
+                max_content_size: 0x0fff,
+                ..Default::default()
+            },
+        );
+        connection.read_frame().await.unwrap();
+        connection.read_frame().await.unwrap();
+        assert!(matches!(
+            connection.read_frame().await,
+            Err(ClockingFramingError::ContentTooLarge {
+                length: 0x1000,
+                limit: 0x0fff
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_exceeded_buffer() {
+        let mut vec = Cursor::new(Vec::<u8>::new());
+        write_request_prefix(&mut vec).await;
+        vec.write_all(&1000u64.to_be_bytes()).unwrap();
+        vec.write_all(&1000u64.to_be_bytes()).unwrap();
+        vec.write_all(&[0x00; 200]).unwrap();
+
+        vec.set_position(0);
+        let mut connection = ClockingConnection::with_limits(
+            &mut vec,
+            MessageAuthor::Client,
+            ConnectionLimits {
+                max_buffered_bytes: 64,
+                ..Default::default()
+            },
+        );
+        connection.read_frame().await.unwrap();
+        connection.read_frame().await.unwrap();
+        assert!(matches!(
+            connection.read_frame().await,
+            Err(ClockingFramingError::BufferLimitExceeded { limit: 64, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_too_many_unfragmented_bytes() {
+        let mut vec = Cursor::new(Vec::<u8>::new());
+        let header = write_request_prefix(&mut vec).await;
+        vec.write_all(&4u64.to_be_bytes()).unwrap();
+        vec.write_all(&4u64.to_be_bytes()).unwrap();
+        vec.write_all(b"Wao!").unwrap();
+        vec.write_all(&[0x0f; 100]).unwrap();
+        vec.write_all(&encode(&header, &()).await).unwrap();
+
+        vec.set_position(0);
+        let mut connection = ClockingConnection::with_limits(
+            &mut vec,
+            MessageAuthor::Client,
+            ConnectionLimits {
+                max_unfragmented_bytes: 16,
+                ..Default::default()
+            },
+        );
+        connection.read_frame().await.unwrap();
+        connection.read_frame().await.unwrap();
+        connection.read_frame().await.unwrap();
+        assert!(matches!(
+            connection.read_frame().await,
+            Err(ClockingFramingError::TooManyUnfragmentedBytes {
+                received: 100,
+                limit: 16
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn mismatched_content_length_is_unfragmented() {
+        let mut vec = Cursor::new(Vec::<u8>::new());
+        let header = write_request_prefix(&mut vec).await;
+        let mut garbage = Vec::new();
+        garbage.extend_from_slice(&4u64.to_be_bytes());
+        garbage.extend_from_slice(&5u64.to_be_bytes());
+        garbage.extend_from_slice(b"Wao!");
+        vec.write_all(&garbage).unwrap();
+        vec.write_all(&encode(&header, &()).await).unwrap();
+
+        vec.set_position(0);
+        let mut connection = ClockingConnection::new(&mut vec, MessageAuthor::Client);
+        connection.read_frame().await.unwrap();
+        connection.read_frame().await.unwrap();
+        assert_eq!(
+            connection.read_frame().await.unwrap(),
+            Some(ClockingFrameUnit::Unfragmented(garbage.into()))
+        );
+        assert_eq!(
+            connection.read_frame().await.unwrap(),
+            Some(ClockingFrameUnit::SuteraHeader(header))
+        );
+    }
+
+    #[rstest]
+    #[case::client(MessageAuthor::Client)]
+    #[case::server(MessageAuthor::Server)]
+    #[tokio::test]
+    async fn write_message_reflective(#[case] author: MessageAuthor) {
+        let header = SuteraHeader {
+            version: Version {
+                major: 0,
+                minor: 1,
+                patch: 0,
+            },
+        };
+        let status = match author {
+            MessageAuthor::Client => None,
+            MessageAuthor::Server => Some(SuteraStatus::Ok),
+        };
+        let oneshot_header = OneshotHeader {
+            step: match author {
+                MessageAuthor::Client => OneshotStep::Request,
+                MessageAuthor::Server => OneshotStep::Response,
+            },
+            message_id: 0x1234,
+            message_type: OneshotTypes::Authentication_Login_Pull.into(),
+        };
+
+        let mut vec = Cursor::new(Vec::<u8>::new());
+        ClockingConnection::new(&mut vec, author)
+            .write_message(
+                header.clone(),
+                status.clone(),
+                ContentHeader::Oneshot(oneshot_header.clone()),
+                Bytes::from_static(b"Wao!"),
+            )
+            .await
+            .unwrap();
+
+        vec.set_position(0);
+        let mut connection = ClockingConnection::new(&mut vec, author);
+        assert_eq!(
+            connection.read_frame().await.unwrap(),
+            Some(ClockingFrameUnit::SuteraHeader(header))
+        );
+        if let Some(status) = status {
+            assert_eq!(
+                connection.read_frame().await.unwrap(),
+                Some(ClockingFrameUnit::SuteraStatus(status))
+            );
+        }
+        assert_eq!(
+            connection.read_frame().await.unwrap(),
+            Some(ClockingFrameUnit::OneshotHeaders(oneshot_header))
+        );
+        assert_eq!(
+            connection.read_frame().await.unwrap(),
+            Some(ClockingFrameUnit::Content(Bytes::from_static(b"Wao!")))
+        );
+        assert_eq!(connection.read_frame().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn read_unknown_oneshot_type() {
+        let header = SuteraHeader {
+            version: Version {
+                major: 0,
+                minor: 1,
+                patch: 0,
+            },
+        };
+        let oneshot_header = OneshotHeader {
+            step: OneshotStep::Request,
+            message_id: 0x1234,
+            message_type: OneshotMessageType::Unknown([0x7f, 0xff, 0x00, 0x01]),
+        };
+
+        let mut vec = Cursor::new(Vec::<u8>::new());
+        ClockingConnection::new(&mut vec, MessageAuthor::Client)
+            .write_message(
+                header.clone(),
+                None,
+                ContentHeader::Oneshot(oneshot_header.clone()),
+                Bytes::from_static(b"Wao!"),
+            )
+            .await
+            .unwrap();
+
+        vec.set_position(0);
+        let mut connection = ClockingConnection::new(&mut vec, MessageAuthor::Client);
+        assert_eq!(
+            connection.read_frame().await.unwrap(),
+            Some(ClockingFrameUnit::SuteraHeader(header))
+        );
+        assert_eq!(
+            connection.read_frame().await.unwrap(),
+            Some(ClockingFrameUnit::OneshotHeaders(oneshot_header))
+        );
+        assert_eq!(
+            connection.read_frame().await.unwrap(),
+            Some(ClockingFrameUnit::Content(Bytes::from_static(b"Wao!")))
+        );
+    }
+
+    async fn receive_client_message(
+        status: Option<SuteraStatus>,
+        oneshot_header: OneshotHeader,
+    ) -> Option<ReceivePayload> {
+        let mut vec = Cursor::new(Vec::<u8>::new());
+        ClockingConnection::new(&mut vec, MessageAuthor::Server)
+            .write_message(
+                SuteraHeader {
+                    version: Version::new(0, 1, 0),
+                },
+                status,
+                ContentHeader::Oneshot(oneshot_header),
+                Bytes::from_static(b"Wao!"),
+            )
+            .await
+            .unwrap();
+
+        vec.set_position(0);
+        let mut connection = ClockingConnection::new(&mut vec, MessageAuthor::Client);
+        let mut frame_buffer = FrameBuffer::new(EnvLogger {
+            target: "test".into(),
+        });
+        let mut received = None;
+        while let Some(frame) = connection.read_frame().await.unwrap() {
+            assert!(!matches!(frame, ClockingFrameUnit::Unfragmented(_)));
+            received = received.or(frame_buffer.append(frame, MessageAuthor::Client));
+        }
+        received
+    }
+
+    #[rstest]
+    #[case::ok(SuteraStatus::Ok)]
+    #[case::warning(SuteraStatus::Warning(SuteraStatusWarning::SchemaVersionNotExactlyMatched))]
+    #[case::unimplemented(SuteraStatus::Error(SuteraStatusError::Unimplemented))]
+    #[case::bad_request(SuteraStatus::Error(SuteraStatusError::BadRequest))]
+    #[case::forbidden(SuteraStatus::Error(SuteraStatusError::Forbidden))]
+    #[case::unknown(SuteraStatus::Unknown(SuteraStatusCategory::Error, [0x40, 0x00, 0x00]))]
+    #[tokio::test]
+    async fn client_response_with_status(#[case] status: SuteraStatus) {
+        let oneshot_header = OneshotHeader {
+            step: OneshotStep::Response,
+            message_id: 0x1234,
+            message_type: OneshotTypes::Connection_HealthCheck_Push.into(),
+        };
+        let received = receive_client_message(Some(status.clone()), oneshot_header.clone())
+            .await
+            .unwrap();
+        assert_eq!(received.sutera_status, Some(status));
+        assert!(matches!(
+            received.content_header,
+            ContentHeader::Oneshot(header) if header == oneshot_header
+        ));
+        assert_eq!(received.payload, Bytes::from_static(b"Wao!"));
+    }
+
+    #[tokio::test]
+    async fn client_status_is_optional() {
+        let oneshot_header = OneshotHeader {
+            step: OneshotStep::Response,
+            message_id: 0x1234,
+            message_type: OneshotTypes::Connection_HealthCheck_Push.into(),
+        };
+        let received = receive_client_message(None, oneshot_header).await.unwrap();
+        assert_eq!(received.sutera_status, None);
+    }
+
+    #[tokio::test]
+    async fn reject_client_request_with_status() {
+        let oneshot_header = OneshotHeader {
+            step: OneshotStep::Request,
+            message_id: 0x1234,
+            message_type: OneshotTypes::Authentication_Login_Pull.into(),
+        };
+        assert!(
+            receive_client_message(Some(SuteraStatus::Ok), oneshot_header)
+                .await
+                .is_none()
         );
     }
 }
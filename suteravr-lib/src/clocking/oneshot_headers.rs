@@ -8,6 +8,7 @@ use once_cell::sync::Lazy;
 use crate::messaging::id::MessageId;
 use crate::util::search_from_enum;
 
+use super::messages::OneshotMessage;
 use super::traits::{ClockingFrame, MessageAuthor};
 
 #[derive(Enum, PartialEq, Debug, Clone, Copy)]
@@ -15,7 +16,9 @@ use super::traits::{ClockingFrame, MessageAuthor};
 pub enum OneshotTypes {
     Connection_HealthCheck_Push,
     Connection_HealthCheck_Pull,
+    Connection_Compression_Pull,
     Authentication_Login_Pull,
+    Authentication_Resume_Pull,
     TextChat_SendMessage_Pull,
     VoiceChat_SubVoiceTopic_Pull,
     VoiceChat_UnsubVoiceTopic_Pull,
@@ -40,7 +43,9 @@ static ONESHOT_TYPES_MAP: Lazy<
     enum_map! {
         OneshotTypes::Connection_HealthCheck_Push     => [0x00, 0x00, 0x00, 0x00],
         OneshotTypes::Connection_HealthCheck_Pull     => [0x00, 0x00, 0x00, 0x01],
+        OneshotTypes::Connection_Compression_Pull     => [0x00, 0x00, 0x01, 0x00],
         OneshotTypes::Authentication_Login_Pull       => [0x00, 0x01, 0x00, 0x00],
+        OneshotTypes::Authentication_Resume_Pull      => [0x00, 0x01, 0x00, 0x01],
         OneshotTypes::TextChat_SendMessage_Pull       => [0x00, 0x03, 0x00, 0x00],
         OneshotTypes::VoiceChat_SubVoiceTopic_Pull    => [0x00, 0x03, 0x01, 0x00],
         OneshotTypes::VoiceChat_UnsubVoiceTopic_Pull  => [0x00, 0x03, 0x01, 0x01],
@@ -52,7 +57,9 @@ pub static ONESHOT_DIRECTION_MAP: Lazy<EnumMap<OneshotTypes, OneshotDirection>>
     enum_map! {
         OneshotTypes::Connection_HealthCheck_Push     => OneshotDirection::Push,
         OneshotTypes::Connection_HealthCheck_Pull     => OneshotDirection::Pull,
+        OneshotTypes::Connection_Compression_Pull     => OneshotDirection::Pull,
         OneshotTypes::Authentication_Login_Pull       => OneshotDirection::Pull,
+        OneshotTypes::Authentication_Resume_Pull      => OneshotDirection::Pull,
         OneshotTypes::TextChat_SendMessage_Pull       => OneshotDirection::Pull,
         OneshotTypes::VoiceChat_SubVoiceTopic_Pull    => OneshotDirection::Pull,
         OneshotTypes::VoiceChat_UnsubVoiceTopic_Pull  => OneshotDirection::Pull,
@@ -69,10 +76,72 @@ pub static ONESHOT_STEP_MAP: Lazy<
     }
 });
 
+/// ヘッダーに書かれたOneshotの種類です。
+///
+/// 知らない種類のコードを受け取っても、メッセージを読み捨てずに済むよう`Unknown`として保持します。
+/// これにより、古い実装でも[`SuteraStatusError::Unimplemented`][super::sutera_status::SuteraStatusError::Unimplemented]を返すことができます。
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum OneshotMessageType {
+    Known(OneshotTypes),
+    Unknown([u8; OneshotHeader::MESSAGE_TYPE_DISTINCTOR_SIZE]),
+}
+
+impl OneshotMessageType {
+    #[inline]
+    pub fn from_code(code: [u8; OneshotHeader::MESSAGE_TYPE_DISTINCTOR_SIZE]) -> Self {
+        match search_from_enum(*ONESHOT_TYPES_MAP, &code) {
+            Some(known) => Self::Known(known),
+            None => Self::Unknown(code),
+        }
+    }
+
+    #[inline]
+    pub fn code(&self) -> [u8; OneshotHeader::MESSAGE_TYPE_DISTINCTOR_SIZE] {
+        match self {
+            Self::Known(known) => ONESHOT_TYPES_MAP[*known],
+            Self::Unknown(code) => *code,
+        }
+    }
+
+    #[inline]
+    pub fn known(&self) -> Option<OneshotTypes> {
+        match self {
+            Self::Known(known) => Some(*known),
+            Self::Unknown(_) => None,
+        }
+    }
+
+    /// 種類が分かっている場合のみ、その方向を返します。
+    #[inline]
+    pub fn direction(&self) -> Option<OneshotDirection> {
+        self.known().map(|known| ONESHOT_DIRECTION_MAP[known])
+    }
+
+    /// 型で指定したメッセージの種類と一致するかを返します。
+    #[inline]
+    pub fn is<M: OneshotMessage>(&self) -> bool {
+        *self == M::TYPE
+    }
+}
+
+impl From<OneshotTypes> for OneshotMessageType {
+    #[inline]
+    fn from(value: OneshotTypes) -> Self {
+        Self::Known(value)
+    }
+}
+
+impl PartialEq<OneshotTypes> for OneshotMessageType {
+    #[inline]
+    fn eq(&self, other: &OneshotTypes) -> bool {
+        self.known() == Some(*other)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct OneshotHeader {
     pub step: OneshotStep,
-    pub message_type: OneshotTypes,
+    pub message_type: OneshotMessageType,
     pub message_id: MessageId,
 }
 
@@ -102,16 +171,23 @@ impl ClockingFrame for OneshotHeader {
                     cursor.get_u8(),
                     cursor.get_u8(),
                 ];
-                let message_type = search_from_enum(*ONESHOT_TYPES_MAP, &message_type)?;
-                if ONESHOT_DIRECTION_MAP[message_type]
-                    != match (ctx, step) {
-                        (MessageAuthor::Client, OneshotStep::Request) => OneshotDirection::Pull,
-                        (MessageAuthor::Client, OneshotStep::Response) => OneshotDirection::Push,
-                        (MessageAuthor::Server, OneshotStep::Request) => OneshotDirection::Push,
-                        (MessageAuthor::Server, OneshotStep::Response) => OneshotDirection::Pull,
+                let message_type = OneshotMessageType::from_code(message_type);
+                // 知らない種類の場合は方向を確かめようがないので、そのまま受け付ける
+                if let Some(direction) = message_type.direction() {
+                    if direction
+                        != match (ctx, step) {
+                            (MessageAuthor::Client, OneshotStep::Request) => OneshotDirection::Pull,
+                            (MessageAuthor::Client, OneshotStep::Response) => {
+                                OneshotDirection::Push
+                            }
+                            (MessageAuthor::Server, OneshotStep::Request) => OneshotDirection::Push,
+                            (MessageAuthor::Server, OneshotStep::Response) => {
+                                OneshotDirection::Pull
+                            }
+                        }
+                    {
+                        return None;
                     }
-                {
-                    return None;
                 }
                 Some(Self {
                     step,
@@ -130,9 +206,7 @@ impl ClockingFrame for OneshotHeader {
     ) -> std::io::Result<()> {
         stream.write_all(&ONESHOT_STEP_MAP[self.step]).await?;
         stream.write_u64(self.message_id).await?;
-        stream
-            .write_all(&ONESHOT_TYPES_MAP[self.message_type])
-            .await?;
+        stream.write_all(&self.message_type.code()).await?;
         Ok(())
     }
 }
@@ -150,7 +224,7 @@ mod tests {
         test_clockingframe_reflective(
             OneshotHeader {
                 step: OneshotStep::Request,
-                message_type: OneshotTypes::Connection_HealthCheck_Push,
+                message_type: OneshotTypes::Connection_HealthCheck_Push.into(),
                 message_id: 0x1234567890abcdef,
             },
             MessageAuthor::Server,
@@ -159,7 +233,7 @@ mod tests {
         test_clockingframe_reflective(
             OneshotHeader {
                 step: OneshotStep::Request,
-                message_type: OneshotTypes::Connection_HealthCheck_Pull,
+                message_type: OneshotTypes::Connection_HealthCheck_Pull.into(),
                 message_id: 0x1234567890abcdef,
             },
             MessageAuthor::Client,
@@ -168,7 +242,7 @@ mod tests {
         test_clockingframe_reflective(
             OneshotHeader {
                 step: OneshotStep::Response,
-                message_type: OneshotTypes::Connection_HealthCheck_Push,
+                message_type: OneshotTypes::Connection_HealthCheck_Push.into(),
                 message_id: 0x1234567890abcdef,
             },
             MessageAuthor::Client,
@@ -177,7 +251,7 @@ mod tests {
         test_clockingframe_reflective(
             OneshotHeader {
                 step: OneshotStep::Response,
-                message_type: OneshotTypes::Connection_HealthCheck_Pull,
+                message_type: OneshotTypes::Connection_HealthCheck_Pull.into(),
                 message_id: 0x1234567890abcdef,
             },
             MessageAuthor::Server,
@@ -190,7 +264,7 @@ mod tests {
         let payload = encode(
             &OneshotHeader {
                 step: OneshotStep::Request,
-                message_type: OneshotTypes::Connection_HealthCheck_Pull,
+                message_type: OneshotTypes::Connection_HealthCheck_Pull.into(),
                 message_id: 0x1234567890abcdef,
             },
             &MessageAuthor::Server,
@@ -209,7 +283,7 @@ mod tests {
         let payload = encode(
             &OneshotHeader {
                 step: OneshotStep::Request,
-                message_type: OneshotTypes::Connection_HealthCheck_Push,
+                message_type: OneshotTypes::Connection_HealthCheck_Push.into(),
                 message_id: 0x1234567890abcdef,
             },
             &MessageAuthor::Client,
@@ -222,4 +296,15 @@ mod tests {
         )
         .await;
     }
+
+    #[tokio::test]
+    async fn clockingserver_oneshot_header_unknown_type() {
+        let header = OneshotHeader {
+            step: OneshotStep::Request,
+            message_type: OneshotMessageType::Unknown([0x7f, 0xff, 0x00, 0x01]),
+            message_id: 0x1234567890abcdef,
+        };
+        test_clockingframe_reflective(header.clone(), MessageAuthor::Client).await;
+        test_clockingframe_reflective(header, MessageAuthor::Server).await;
+    }
 }
@@ -0,0 +1,43 @@
+use alkahest::alkahest;
+
+use crate::clocking::compression::ContentEncoding;
+
+/// クライアントが対応している圧縮形式を伝えます。
+///
+/// 値は[`ContentEncoding::code`]です。
+#[derive(Debug)]
+#[alkahest(Formula, Serialize, Deserialize)]
+pub struct CompressionRequest {
+    pub encodings: Vec<u8>,
+}
+
+/// サーバーが選んだ圧縮形式です。
+///
+/// `Enabled`を返した時点で、サーバーは圧縮されたContentを受け付けます。
+/// クライアントは、このレスポンスを受け取ってから圧縮して送りはじめてください。
+#[derive(Debug)]
+#[alkahest(Formula, Serialize, Deserialize)]
+pub enum CompressionResponse {
+    Enabled(u8),
+    Disabled,
+}
+
+impl CompressionRequest {
+    /// この実装が対応しているすべての圧縮形式を伝えるリクエストを作ります。
+    pub fn supported() -> Self {
+        Self {
+            encodings: ContentEncoding::SUPPORTED
+                .iter()
+                .map(ContentEncoding::code)
+                .collect(),
+        }
+    }
+
+    /// 双方が対応している圧縮形式のうち、最も優先されるものを選びます。
+    pub fn choose(&self) -> Option<ContentEncoding> {
+        ContentEncoding::SUPPORTED
+            .iter()
+            .find(|e| self.encodings.contains(&e.code()))
+            .copied()
+    }
+}
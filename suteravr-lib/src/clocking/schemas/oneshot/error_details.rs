@@ -0,0 +1,143 @@
+use alkahest::{alkahest, deserialize};
+use bytes::Bytes;
+
+use crate::{clocking::sutera_status::SuteraStatusError, util::serialize_to_new_vec};
+
+/// [`SuteraStatus::Error`][crate::clocking::sutera_status::SuteraStatus::Error]を返す際に、ペイロードとして付ける詳細です。
+///
+/// 古い実装はエラーの場合にペイロードを空にするので、受け取る側は空の場合も扱える必要があります。
+#[derive(Debug, PartialEq, Clone)]
+#[alkahest(Formula, Serialize, Deserialize)]
+pub struct ErrorDetails {
+    /// 機械向けのコードです。`SuteraStatusError`よりも細かい理由を、`login.bad_token`のような形で表します。
+    pub code: String,
+    /// 人間向けのメッセージです。そのままプレイヤーに見せても問題ない内容にしてください。
+    pub message: String,
+    pub retry: RetryHint,
+}
+
+/// 同じリクエストを送り直してよいかを表します。
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[alkahest(Formula, Serialize, Deserialize)]
+pub enum RetryHint {
+    /// 送り直しても同じ結果になります。
+    Never,
+    /// 指定したミリ秒が経った後であれば、送り直してもかまいません。
+    After(u64),
+    /// 認証をやり直した後であれば、送り直してもかまいません。
+    AfterReauthentication,
+}
+
+impl ErrorDetails {
+    #[inline]
+    pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            message: message.into(),
+            retry: RetryHint::Never,
+        }
+    }
+
+    #[inline]
+    pub fn with_retry(mut self, retry: RetryHint) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    #[inline]
+    pub fn encode(self) -> Bytes {
+        serialize_to_new_vec(self).into()
+    }
+
+    /// エラーのレスポンスのペイロードから詳細を読み取ります。
+    ///
+    /// ペイロードが空の場合や、詳細として解釈できない場合は`None`を返します。
+    #[inline]
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.is_empty() {
+            return None;
+        }
+        deserialize::<Self, Self>(payload).ok()
+    }
+}
+
+impl From<SuteraStatusError> for ErrorDetails {
+    /// 詳しい理由が分からない場合に使う、エラーの種類ごとの既定の詳細です。
+    fn from(error: SuteraStatusError) -> Self {
+        match error {
+            SuteraStatusError::SchemaVersionNotSupported => Self::new(
+                "schema_version_not_supported",
+                "The schema version is not supported. Please update your client.",
+            ),
+            SuteraStatusError::BadRequest => {
+                Self::new("bad_request", "The request could not be understood.")
+            }
+            SuteraStatusError::Unimplemented => {
+                Self::new("unimplemented", "The request is not implemented.")
+            }
+            SuteraStatusError::Unauthorized => {
+                Self::new("unauthorized", "You need to log in before this request.")
+                    .with_retry(RetryHint::AfterReauthentication)
+            }
+            SuteraStatusError::AuthenticationHasBeenExpired => {
+                Self::new("authentication_expired", "Your login has expired.")
+                    .with_retry(RetryHint::AfterReauthentication)
+            }
+            SuteraStatusError::Forbidden => {
+                Self::new("forbidden", "You are not allowed to do this.")
+            }
+            SuteraStatusError::YouAreNotInInstance => Self::new(
+                "not_in_instance",
+                "You need to join an instance before this request.",
+            )
+            .with_retry(RetryHint::AfterReauthentication),
+            SuteraStatusError::InstanceFull => Self::new("instance_full", "The instance is full.")
+                .with_retry(RetryHint::After(30_000)),
+            SuteraStatusError::NotFound => {
+                Self::new("not_found", "The requested resource was not found.")
+            }
+            SuteraStatusError::PayloadTooLarge => {
+                Self::new("payload_too_large", "The request is too large.")
+            }
+            SuteraStatusError::RateLimited => {
+                Self::new("rate_limited", "Too many requests. Please slow down.")
+                    .with_retry(RetryHint::After(1_000))
+            }
+            SuteraStatusError::InternalError => {
+                Self::new("internal_error", "Something went wrong on the server.")
+                    .with_retry(RetryHint::After(5_000))
+            }
+            SuteraStatusError::ServerShuttingDown => {
+                Self::new("server_shutting_down", "The server is shutting down.")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn default_details() {
+        assert_eq!(
+            ErrorDetails::from(SuteraStatusError::Unauthorized).retry,
+            RetryHint::AfterReauthentication
+        );
+        assert_eq!(
+            ErrorDetails::from(SuteraStatusError::BadRequest).retry,
+            RetryHint::Never
+        );
+        assert_eq!(
+            ErrorDetails::from(SuteraStatusError::Unimplemented).code,
+            "unimplemented"
+        );
+    }
+
+    #[test]
+    fn empty_payload_has_no_details() {
+        assert_eq!(ErrorDetails::decode(&[]), None);
+    }
+}
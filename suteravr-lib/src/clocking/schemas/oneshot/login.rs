@@ -4,15 +4,34 @@ use alkahest::alkahest;
 #[derive(Debug)]
 #[alkahest(Formula, Serialize, Deserialize)]
 pub struct LoginRequest {
-    // FIXME:
-    // このトークンは、Balancing-serverに問い合わせるか、データベースから正常なトークンを貰っているかを確認する必要があります。
-    // この実装は、インスタンスIDさえ分かれば、誰でもインスタンスに入れてしまうので、セキュリティ上の問題があります。
-    pub join_token: InstanceId,
+    /// 参加するインスタンスです。
+    pub instance: InstanceId,
+    /// Balancing-serverが発行した、`instance`に参加するためのトークンです。
+    /// [`JoinTokenVerifier`][crate::clocking::join_token::JoinTokenVerifier]で検証されます。
+    pub join_token: String,
 }
 
 #[derive(Debug)]
 #[alkahest(Formula, Serialize, Deserialize)]
 pub enum LoginResponse {
-    Ok(PlayerId, Vec<PlayerId>),
+    /// 自分のID、既に参加しているプレイヤー、[`ResumeRequest`]に使うトークンです。
+    Ok(PlayerId, Vec<PlayerId>, String),
     BadToken,
 }
+
+/// 接続が切れてから猶予期間のうちであれば、同じ`PlayerId`のまま参加し直せます。
+///
+/// 切れている間に届くはずだったイベントは、返答の後に続けて送られます。
+#[derive(Debug)]
+#[alkahest(Formula, Serialize, Deserialize)]
+pub struct ResumeRequest {
+    /// 最後に受け取った[`LoginResponse::Ok`]もしくは[`ResumeResponse::Ok`]のトークンです。
+    pub resume_token: String,
+}
+
+#[derive(Debug)]
+#[alkahest(Formula, Serialize, Deserialize)]
+pub enum ResumeResponse {
+    /// 引き継いだ`PlayerId`と、次に使うトークンです。使ったトークンは無効になります。
+    Ok(PlayerId, String),
+}
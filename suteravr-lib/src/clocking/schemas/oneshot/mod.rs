@@ -1,2 +1,4 @@
 pub mod chat_entry;
+pub mod compression;
+pub mod error_details;
 pub mod login;
@@ -10,7 +10,7 @@ pub struct SuteraHeader {
 }
 
 impl SuteraHeader {
-    const PREFIX: &'static [u8] = b"SuteraVR";
+    pub(crate) const PREFIX: &'static [u8] = b"SuteraVR";
 }
 impl ClockingFrame for SuteraHeader {
     type Context = ();
@@ -17,16 +17,28 @@ pub enum SuteraStatusError {
     AuthenticationHasBeenExpired,
     Forbidden,
     YouAreNotInInstance,
+    InstanceFull,
+    NotFound,
+    PayloadTooLarge,
+    RateLimited,
+    InternalError,
+    ServerShuttingDown,
 }
 static SUTERA_STATUS_ERROR_MAP: Lazy<EnumMap<SuteraStatusError, [u8; 3]>> = Lazy::new(|| {
     enum_map! {
         SuteraStatusError::SchemaVersionNotSupported    => [0x10, 0x00, 0x00],
         SuteraStatusError::BadRequest                   => [0x10, 0x02, 0x00],
         SuteraStatusError::Unimplemented                => [0x10, 0x02, 0x01],
+        SuteraStatusError::NotFound                     => [0x10, 0x02, 0x02],
+        SuteraStatusError::PayloadTooLarge              => [0x10, 0x02, 0x03],
+        SuteraStatusError::RateLimited                  => [0x10, 0x03, 0x00],
         SuteraStatusError::Unauthorized                 => [0x20, 0x00, 0x00],
         SuteraStatusError::AuthenticationHasBeenExpired => [0x20, 0x00, 0x01],
         SuteraStatusError::Forbidden                    => [0x20, 0x01, 0x00],
         SuteraStatusError::YouAreNotInInstance          => [0x20, 0x02, 0x00],
+        SuteraStatusError::InstanceFull                 => [0x20, 0x02, 0x01],
+        SuteraStatusError::InternalError                => [0x30, 0x00, 0x00],
+        SuteraStatusError::ServerShuttingDown           => [0x30, 0x01, 0x00],
     }
 });
 
@@ -40,11 +52,54 @@ static SUTERA_STATUS_WARNING_MAP: Lazy<EnumMap<SuteraStatusWarning, [u8; 3]>> =
     }
 });
 
+/// ステータスの分類です。コードを知らなくても、分類だけは必ず分かります。
+#[derive(Enum, PartialEq, Debug, Clone, Copy)]
+pub enum SuteraStatusCategory {
+    Ok,
+    Warning,
+    Error,
+}
+static SUTERA_STATUS_CATEGORY_MAP: Lazy<EnumMap<SuteraStatusCategory, u8>> = Lazy::new(|| {
+    enum_map! {
+        SuteraStatusCategory::Ok      => 0x00,
+        SuteraStatusCategory::Warning => 0x01,
+        SuteraStatusCategory::Error   => 0x02,
+    }
+});
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum SuteraStatus {
     Ok,
     Warning(SuteraStatusWarning),
     Error(SuteraStatusError),
+    /// この実装が知らないコードです。
+    ///
+    /// 新しいサーバーが追加したステータスで古いクライアントが壊れないように、分類とコードをそのまま保持します。
+    /// 分類は`Warning`か`Error`のいずれかです。
+    Unknown(SuteraStatusCategory, [u8; 3]),
+}
+
+impl SuteraStatus {
+    /// コードの先頭のバイト(グループ)の最小値です。これより小さいグループは予約されています。
+    ///
+    /// OneshotHeader・EventHeaderの先頭がSuteraStatusとして読めないのは、この予約のおかげです。
+    pub const MIN_CODE_GROUP: u8 = 0x10;
+
+    #[inline]
+    pub fn category(&self) -> SuteraStatusCategory {
+        match self {
+            Self::Ok => SuteraStatusCategory::Ok,
+            Self::Warning(_) => SuteraStatusCategory::Warning,
+            Self::Error(_) => SuteraStatusCategory::Error,
+            Self::Unknown(category, _) => *category,
+        }
+    }
+
+    /// 知らないコードも含めて、エラーであるかを返します。
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        self.category() == SuteraStatusCategory::Error
+    }
 }
 
 impl ClockingFrame for SuteraStatus {
@@ -57,24 +112,29 @@ impl ClockingFrame for SuteraStatus {
         cursor: &mut std::io::Cursor<&[u8]>,
         _ctx: &Self::Context,
     ) -> Option<Self> {
-        match cursor.copy_to_bytes(1)[0] {
-            0x00 => Some(Self::Ok),
-            0x01 => {
-                if cursor.remaining() < 3 {
-                    return None;
-                }
-                let kind = [cursor.get_u8(), cursor.get_u8(), cursor.get_u8()];
+        let category = search_from_enum(*SUTERA_STATUS_CATEGORY_MAP, &cursor.get_u8())?;
+        if category == SuteraStatusCategory::Ok {
+            return Some(Self::Ok);
+        }
+        if cursor.remaining() < 3 {
+            return None;
+        }
+        let kind = [cursor.get_u8(), cursor.get_u8(), cursor.get_u8()];
+        let known = match category {
+            SuteraStatusCategory::Warning => {
                 search_from_enum(*SUTERA_STATUS_WARNING_MAP, &kind).map(Self::Warning)
             }
-            0x02 => {
-                if cursor.remaining() < 3 {
-                    return None;
-                }
-                let kind = [cursor.get_u8(), cursor.get_u8(), cursor.get_u8()];
-                search_from_enum(*SUTERA_STATUS_ERROR_MAP, &kind).map(Self::Error)
-            }
-            _ => None,
+            _ => search_from_enum(*SUTERA_STATUS_ERROR_MAP, &kind).map(Self::Error),
+        };
+        if known.is_some() {
+            return known;
+        }
+        // 知らないコードでも、分類が分かっていればフレームとしては成立している
+        // ただし、ゴミを読み違えないように、グループが予約された範囲にあるものは受け付けない
+        if kind[0] < Self::MIN_CODE_GROUP {
+            return None;
         }
+        Some(Self::Unknown(category, kind))
     }
 
     async fn write_frame<W: tokio::io::AsyncWriteExt + Unpin>(
@@ -82,16 +142,14 @@ impl ClockingFrame for SuteraStatus {
         stream: &mut W,
         _ctx: &Self::Context,
     ) -> std::io::Result<()> {
+        stream
+            .write_all(&[SUTERA_STATUS_CATEGORY_MAP[self.category()]])
+            .await?;
         match self {
-            Self::Ok => stream.write_all(&[0x00]).await?,
-            Self::Warning(w) => {
-                stream.write_all(&[0x01]).await?;
-                stream.write_all(&SUTERA_STATUS_WARNING_MAP[*w]).await?;
-            }
-            Self::Error(e) => {
-                stream.write_all(&[0x02]).await?;
-                stream.write_all(&SUTERA_STATUS_ERROR_MAP[*e]).await?;
-            }
+            Self::Ok => {}
+            Self::Warning(w) => stream.write_all(&SUTERA_STATUS_WARNING_MAP[*w]).await?,
+            Self::Error(e) => stream.write_all(&SUTERA_STATUS_ERROR_MAP[*e]).await?,
+            Self::Unknown(_, kind) => stream.write_all(kind).await?,
         }
         Ok(())
     }
@@ -100,8 +158,9 @@ impl ClockingFrame for SuteraStatus {
 #[cfg(test)]
 mod tests {
     use crate::clocking::traits::test_util::{
-        test_clockingframe_mustfail, test_clockingframe_reflective,
+        decode, test_clockingframe_mustfail, test_clockingframe_reflective,
     };
+    use pretty_assertions::assert_eq;
 
     use super::*;
 
@@ -129,7 +188,46 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn clockingframe_sutera_status_unexists() {
-        test_clockingframe_mustfail::<SuteraStatus>(&[0x02, 0xFF, 0xFF, 0xFF], &(), Some(4)).await;
+    async fn clockingframe_sutera_status_extended() {
+        for error in [
+            SuteraStatusError::InstanceFull,
+            SuteraStatusError::NotFound,
+            SuteraStatusError::PayloadTooLarge,
+            SuteraStatusError::RateLimited,
+            SuteraStatusError::InternalError,
+            SuteraStatusError::ServerShuttingDown,
+        ] {
+            test_clockingframe_reflective(SuteraStatus::Error(error), ()).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn clockingframe_sutera_status_reserved_group() {
+        test_clockingframe_mustfail::<SuteraStatus>(&[0x01, 0x00, 0x00, 0x00], &(), Some(4)).await;
+        test_clockingframe_mustfail::<SuteraStatus>(&[0x02, 0x0F, 0xFF, 0xFF], &(), Some(4)).await;
+    }
+
+    #[tokio::test]
+    async fn clockingframe_sutera_status_unknown() {
+        let (status, _) = decode::<SuteraStatus>(&[0x02, 0xFF, 0xFF, 0xFF], &()).await;
+        assert_eq!(
+            status,
+            Some(SuteraStatus::Unknown(
+                SuteraStatusCategory::Error,
+                [0xFF, 0xFF, 0xFF]
+            ))
+        );
+        assert!(status.unwrap().is_error());
+
+        test_clockingframe_reflective(
+            SuteraStatus::Unknown(SuteraStatusCategory::Warning, [0x7F, 0x00, 0x01]),
+            (),
+        )
+        .await;
+        test_clockingframe_reflective(
+            SuteraStatus::Unknown(SuteraStatusCategory::Error, [0xFF, 0xFF, 0xFF]),
+            (),
+        )
+        .await;
     }
 }
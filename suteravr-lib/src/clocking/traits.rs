@@ -4,8 +4,9 @@ use std::{
 };
 
 use bytes::Buf;
+use enum_map::Enum;
 use tokio::io::AsyncWriteExt;
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
 pub enum MessageAuthor {
     Client,
     Server,
@@ -6,8 +6,12 @@ pub mod util;
 
 pub struct Foo {}
 
+/// ペイロードの形を変えたら上げてください。
+///
+/// 受け付ける範囲は[`Version::compatible_range`]で決まるので、`0.x`の間はマイナーを上げると
+/// 以前のバージョンとは互換性がなくなります。
 pub const SCHEMA_VERSION: Version = Version {
     major: 0,
-    minor: 1,
+    minor: 3,
     patch: 0,
 };
@@ -61,6 +61,9 @@ impl StandingTransformEncoder {
         self.target = target;
         self.checked = false;
     }
+    /// 前に送った位置から十分に動いていれば、送るべき位置を返します。
+    ///
+    /// 送れた場合は[`Self::commit`]を呼んでください。呼ばなければ、次も同じ位置を返します。
     pub fn payload(&mut self) -> Option<StandingTransform> {
         self.checked = true;
         let elapse = Instant::now().sub(self.last_sent_at).as_millis();
@@ -79,11 +82,42 @@ impl StandingTransformEncoder {
             > threshold.powi(2))
             || (self.target.yaw.sub(self.last_sent.yaw).abs() > threshold)
         {
-            self.last_sent_at = Instant::now();
-            self.last_sent = self.target.clone();
             Some(self.target.clone())
         } else {
             None
         }
     }
+
+    /// `sent`を送ったものとして、次に送るかどうかの基準にします。
+    pub fn commit(&mut self, sent: StandingTransform) {
+        self.last_sent_at = Instant::now();
+        self.last_sent = sent;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread::sleep, time::Duration};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn resend_until_committed() {
+        let moved = StandingTransform {
+            x: 1f64,
+            ..Default::default()
+        };
+        let mut encoder = StandingTransformEncoder::new();
+        sleep(Duration::from_millis(60));
+        encoder.push(moved.clone());
+        assert_eq!(encoder.payload().map(|sent| sent.x), Some(1f64));
+        // 送れなかった位置は、止まっていても送り直す
+        assert_eq!(encoder.payload().map(|sent| sent.x), Some(1f64));
+
+        encoder.commit(moved.clone());
+        sleep(Duration::from_millis(60));
+        assert_eq!(encoder.payload().map(|sent| sent.x), None);
+    }
 }
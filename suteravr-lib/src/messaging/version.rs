@@ -1,17 +1,73 @@
-use std::mem::size_of;
+use std::{fmt, mem::size_of, ops::Range};
 
 use bytes::Buf;
 use tokio::io::AsyncWriteExt;
 
 use crate::clocking::traits::ClockingFrame;
 
-#[derive(Debug, PartialEq, Clone)]
+/// フィールドの宣言順 (major, minor, patch) がそのまま大小比較の優先順位になります。
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub struct Version {
     pub major: u16,
     pub minor: u16,
     pub patch: u16,
 }
 
+/// 2つの[`Version`]が、どの程度互換性を持つかを表します。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VersionCompatibility {
+    /// 完全に一致している
+    Exact,
+    /// 一致はしていないが、互換性の範囲内にある
+    Compatible,
+    /// 互換性がない
+    Incompatible,
+}
+
+impl Version {
+    #[inline]
+    pub const fn new(major: u16, minor: u16, patch: u16) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// このバージョンと互換性のあるバージョンの範囲を返します。
+    ///
+    /// semverと同様に、左から数えて最初の0でない数字が一致する範囲を互換とみなします。
+    /// - `1.2.3` => `1.0.0..2.0.0`
+    /// - `0.1.2` => `0.1.0..0.2.0`
+    /// - `0.0.3` => `0.0.3..0.0.4`
+    pub fn compatible_range(&self) -> Range<Version> {
+        if self.major != 0 {
+            Self::new(self.major, 0, 0)..Self::new(self.major.saturating_add(1), 0, 0)
+        } else if self.minor != 0 {
+            Self::new(0, self.minor, 0)..Self::new(0, self.minor.saturating_add(1), 0)
+        } else {
+            Self::new(0, 0, self.patch)..Self::new(0, 0, self.patch.saturating_add(1))
+        }
+    }
+
+    /// `other`が、このバージョンとどの程度互換性を持つかを調べます。
+    pub fn compatibility_with(&self, other: &Version) -> VersionCompatibility {
+        if self == other {
+            VersionCompatibility::Exact
+        } else if self.compatible_range().contains(other) {
+            VersionCompatibility::Compatible
+        } else {
+            VersionCompatibility::Incompatible
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
 impl ClockingFrame for Version {
     type Context = ();
     const MIN_FRAME_SIZE: usize = size_of::<[u16; 3]>();
@@ -44,8 +100,11 @@ impl ClockingFrame for Version {
 
 #[cfg(test)]
 mod tests {
+    use rstest::*;
+
     use crate::{
-        clocking::traits::test_util::test_clockingframe_reflective, messaging::version::Version,
+        clocking::traits::test_util::test_clockingframe_reflective,
+        messaging::version::{Version, VersionCompatibility},
     };
 
     #[tokio::test]
@@ -60,4 +119,56 @@ mod tests {
         )
         .await;
     }
+
+    #[test]
+    fn version_ordering() {
+        assert!(Version::new(0, 1, 0) < Version::new(0, 1, 1));
+        assert!(Version::new(0, 1, 9) < Version::new(0, 2, 0));
+        assert!(Version::new(0, 9, 9) < Version::new(1, 0, 0));
+        assert_eq!(Version::new(1, 2, 3).to_string(), "1.2.3");
+    }
+
+    #[rstest]
+    #[case(
+        Version::new(1, 2, 3),
+        Version::new(1, 2, 3),
+        VersionCompatibility::Exact
+    )]
+    #[case(
+        Version::new(1, 2, 3),
+        Version::new(1, 0, 0),
+        VersionCompatibility::Compatible
+    )]
+    #[case(
+        Version::new(1, 2, 3),
+        Version::new(1, 9, 0),
+        VersionCompatibility::Compatible
+    )]
+    #[case(
+        Version::new(1, 2, 3),
+        Version::new(2, 0, 0),
+        VersionCompatibility::Incompatible
+    )]
+    #[case(
+        Version::new(0, 1, 0),
+        Version::new(0, 1, 5),
+        VersionCompatibility::Compatible
+    )]
+    #[case(
+        Version::new(0, 1, 0),
+        Version::new(0, 2, 0),
+        VersionCompatibility::Incompatible
+    )]
+    #[case(
+        Version::new(0, 0, 1),
+        Version::new(0, 0, 2),
+        VersionCompatibility::Incompatible
+    )]
+    fn version_compatibility(
+        #[case] base: Version,
+        #[case] other: Version,
+        #[case] expected: VersionCompatibility,
+    ) {
+        assert_eq!(base.compatibility_with(&other), expected);
+    }
 }
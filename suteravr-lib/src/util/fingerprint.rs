@@ -0,0 +1,82 @@
+use std::{fmt, str::FromStr};
+
+use ring::digest::{digest, SHA256};
+use thiserror::Error;
+
+/// 証明書(DER)のSHA-256フィンガープリントです。
+///
+/// `openssl x509 -noout -fingerprint -sha256`と同じく、`AB:CD:...`の形式で表示します。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct CertFingerprint(pub [u8; 32]);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Invalid SHA-256 fingerprint: {0:?}")]
+pub struct ParseFingerprintError(String);
+
+impl CertFingerprint {
+    pub fn of(der: &[u8]) -> Self {
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(digest(&SHA256, der).as_ref());
+        Self(fingerprint)
+    }
+}
+
+impl fmt::Display for CertFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// `:`の有無や大文字小文字は問いません。
+impl FromStr for CertFingerprint {
+    type Err = ParseFingerprintError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseFingerprintError(s.to_string());
+        let hex = s.trim().replace(':', "");
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(error());
+        }
+        let mut fingerprint = [0u8; 32];
+        for (byte, chunk) in fingerprint.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let chunk = std::str::from_utf8(chunk).map_err(|_| error())?;
+            *byte = u8::from_str_radix(chunk, 16).map_err(|_| error())?;
+        }
+        Ok(Self(fingerprint))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn fingerprint_format() {
+        let fingerprint = CertFingerprint::of(b"SuteraVR");
+        let formatted = fingerprint.to_string();
+        assert_eq!(formatted.len(), 32 * 3 - 1);
+        assert_eq!(formatted.parse(), Ok(fingerprint));
+        assert_eq!(
+            formatted.replace(':', "").to_lowercase().parse(),
+            Ok(fingerprint)
+        );
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::too_short("AB:CD")]
+    #[case::not_hex(&"ZZ".repeat(32))]
+    #[case::multibyte(&"é".repeat(32))]
+    fn reject_invalid_fingerprint(#[case] input: &str) {
+        assert!(input.parse::<CertFingerprint>().is_err());
+    }
+}
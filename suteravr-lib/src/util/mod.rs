@@ -1,3 +1,4 @@
+pub mod fingerprint;
 pub mod logger;
 
 use alkahest::{serialize_to_vec, Formula, Serialize};